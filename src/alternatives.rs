@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+
+use crate::biome::Biome;
+use crate::cost_model::CostModel;
+use crate::dijkstra::{Edge, EdgeKind, GlobePoint, GlobePoints, GridPoint, NodeIndex, route};
+use crate::state::Config;
+
+/*
+
+Alternatives to the cheapest route between two cities, for the player to choose from.
+
+Yen's k shortest paths would need a search per node of every route found, and its paths tend to
+differ from each other by a single detour. Instead, after every route the edges into its grid
+points get more expensive, so the next search looks for a route elsewhere, as far as that pays.
+The cheapest route is always the first one.
+
+*/
+
+// Factor on the cost of an edge into a grid point, for every earlier route through it.
+const OVERLAP_PENALTY: f32 = 1.5;
+
+pub struct Route {
+    pub path: Vec<GridPoint>,
+    pub cost: f32, // in the cost model of the config
+    pub breakdown: CostBreakdown,
+}
+
+/// What the edges of a route cost in terms of `TerrainCost`, whatever the configured cost model.
+/// The parts add up to the cost of the route under `TerrainCost`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CostBreakdown {
+    pub distance: f32,   // length of the edges, see `TerrainCost::unscaled`
+    pub climbing: f32,   // height differences
+    pub water: f32,      // extra for crossing sea and lakes
+    pub snow: f32,       // extra for ice
+    pub terrain: f32,    // extra for the other biomes
+    pub structures: f32, // extra for tunnels and bridges
+    pub reused: f32,     // saved by following existing rails, not positive
+}
+
+impl CostBreakdown {
+    pub fn of(path: &[GridPoint], globe_points: &GlobePoints, config: &Config) -> Self {
+        let terrain = config.terrain_cost();
+        let mut breakdown = Self::default();
+        for (p, q, edge) in path_edges(path, globe_points) {
+            let (distance, climbing) = terrain.unscaled(p, q);
+            let cost = terrain.unbuilt_cost(p, q, edge);
+            let extra = cost - distance - climbing;
+            breakdown.distance += distance;
+            breakdown.climbing += climbing;
+            // The terrain penalty of an edge is the larger one of its ends.
+            let worst = if p.penalty >= q.penalty { p } else { q };
+            match edge.kind {
+                EdgeKind::Tunnel | EdgeKind::Bridge => breakdown.structures += extra,
+                EdgeKind::Surface if worst.water => breakdown.water += extra,
+                EdgeKind::Surface if worst.biome == Biome::Ice => breakdown.snow += extra,
+                EdgeKind::Surface => breakdown.terrain += extra,
+            }
+            if edge.built {
+                breakdown.reused -= cost - cost / config.reduction_factor;
+            }
+        }
+        breakdown
+    }
+
+    pub fn total(&self) -> f32 {
+        self.distance
+            + self.climbing
+            + self.water
+            + self.snow
+            + self.terrain
+            + self.structures
+            + self.reused
+    }
+}
+
+/// Up to `k` different routes from `start` to `end` within the track limits of `config`,
+/// cheapest first. Fewer are returned if the search keeps finding the same ones.
+pub fn alternative_routes(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    config: &Config,
+    k: usize,
+) -> Vec<Route> {
+    let mut finder = RouteFinder::new(start, end, config, k);
+    while finder.search(globe_points) {}
+    finder.routes()
+}
+
+/// The searches of `alternative_routes` one at a time, so that the globe points need not be
+/// locked for all of them at once.
+pub struct RouteFinder {
+    start: GridPoint,
+    end: GridPoint,
+    config: Config,
+    k: usize,
+    searches: usize,
+    overlaps: HashMap<NodeIndex, i32>, // number of routes through each grid point
+    routes: Vec<Route>,
+}
+
+impl RouteFinder {
+    pub fn new(start: GridPoint, end: GridPoint, config: &Config, k: usize) -> Self {
+        Self {
+            start,
+            end,
+            config: config.clone(),
+            k,
+            searches: 0,
+            overlaps: HashMap::new(),
+            routes: Vec::new(),
+        }
+    }
+
+    /// Looks for one more route. Returns false once there are enough of them, or no more.
+    pub fn search(&mut self, globe_points: &GlobePoints) -> bool {
+        if self.routes.len() == self.k || self.searches == 2 * self.k {
+            return false;
+        }
+        self.searches += 1;
+        let model = self.config.cost_model();
+        let avoiding = Avoiding {
+            model: model.as_ref(),
+            overlaps: &self.overlaps,
+        };
+        let limits = self.config.track_limits();
+        let (path, _) = route(self.start, self.end, globe_points, &limits, &avoiding);
+        if path.is_empty() {
+            self.searches = 2 * self.k;
+            return false;
+        }
+        let ends = [self.start, self.end].map(|gridpoint| globe_points.points.index(&gridpoint));
+        for node in path.iter().filter_map(|p| globe_points.points.index(p)) {
+            if !ends.contains(&Some(node)) {
+                *self.overlaps.entry(node).or_default() += 1;
+            }
+        }
+        if !self.routes.iter().any(|route| route.path == path) {
+            self.routes.push(Route {
+                cost: path_edges(&path, globe_points)
+                    .map(|(p, q, edge)| model.cost(p, q, edge))
+                    .sum(),
+                breakdown: CostBreakdown::of(&path, globe_points, &self.config),
+                path,
+            });
+        }
+        true
+    }
+
+    /// The routes found so far, cheapest first.
+    pub fn routes(mut self) -> Vec<Route> {
+        self.routes.sort_by(|a, b| a.cost.total_cmp(&b.cost));
+        self.routes
+    }
+}
+
+// The edges along `path`, skipping steps between grid points that are not neighbours.
+fn path_edges<'a>(
+    path: &'a [GridPoint],
+    globe_points: &'a GlobePoints,
+) -> impl Iterator<Item = (&'a GlobePoint, &'a GlobePoint, &'a Edge)> {
+    let points = &globe_points.points;
+    path.windows(2).filter_map(move |step| {
+        let (from, to) = (points.index(&step[0])?, points.index(&step[1])?);
+        let edge = globe_points.edges(from).iter().find(|edge| edge.to == to)?;
+        Some((&points[from], &points[to], edge))
+    })
+}
+
+// `model`, with the edges into grid points of earlier routes made more expensive. Costs only
+// grow, so the lower bound of `model` still holds.
+struct Avoiding<'a> {
+    model: &'a dyn CostModel,
+    overlaps: &'a HashMap<NodeIndex, i32>,
+}
+
+impl CostModel for Avoiding<'_> {
+    fn cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32 {
+        let cost = self.model.cost(p, q, edge);
+        match self.overlaps.get(&edge.to) {
+            Some(&count) => cost * OVERLAP_PENALTY.powi(count),
+            None => cost,
+        }
+    }
+
+    fn min_cost_per_radian(&self, globe_points: &GlobePoints) -> f32 {
+        self.model.min_cost_per_radian(globe_points)
+    }
+}
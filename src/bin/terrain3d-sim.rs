@@ -0,0 +1,153 @@
+// Runs the automatic city and train expansion without a window and prints statistics about the
+// resulting rail network. With `--export <dir>`, also writes the terrain layers and the rail
+// usage as images, with `--gltf <file.glb>` the globe and the rail network as a 3D model, and
+// with `--geojson <file>` the cities and rails as GeoJSON. `--network <file>` starts from the
+// cities and rails of a GeoJSON file.
+//
+// usage: terrain3d-sim [--seconds <n>] [--export <dir>] [--gltf <file.glb>] [--geojson <file>]
+//                      [--network <file>] [--config <file.toml>] [--<field> <value>]...
+
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bevy::{prelude::*, time::TimeUpdateStrategy};
+use terrain3d::config_file::config_from_args;
+use terrain3d::geojson_file::{export_network, load_network};
+use terrain3d::globe_file::load_or_make_globe;
+use terrain3d::gltf_export::export_world;
+use terrain3d::layer_export::{Projection, export_layers};
+use terrain3d::simulation::{NetworkStats, PlayerActions, SimulationPlugin, seed_cities};
+use terrain3d::state::State;
+
+const DEFAULT_SECONDS: f32 = 60.0;
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let options = take_option(&mut args, "seconds").and_then(|seconds| {
+        let seconds = match seconds {
+            None => DEFAULT_SECONDS,
+            Some(value) => match value.parse::<f32>() {
+                Ok(seconds) if seconds >= 0.0 => seconds,
+                _ => return Err(format!("invalid value for --seconds: `{value}`")),
+            },
+        };
+        let export = take_option(&mut args, "export")?.map(PathBuf::from);
+        let gltf = take_option(&mut args, "gltf")?.map(PathBuf::from);
+        let geojson = take_option(&mut args, "geojson")?.map(PathBuf::from);
+        let network = take_option(&mut args, "network")?.map(PathBuf::from);
+        Ok((seconds, export, gltf, geojson, network))
+    });
+    let (seconds, export, gltf, geojson, network) = match options {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!(
+                "{err}\nusage: terrain3d-sim [--seconds <n>] [--export <dir>] [--gltf <file.glb>] \
+                 [--geojson <file>] [--network <file>] [--config <file.toml>] \
+                 [--<field> <value>]..."
+            );
+            std::process::exit(2);
+        }
+    };
+    let config = match config_from_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+
+    let (globe_points, globe_mesh) = match load_or_make_globe(&config) {
+        Ok(globe) => globe,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+    let actions = match network {
+        None => Vec::new(),
+        Some(path) => match load_network(&path, &globe_points, config.cost_model().as_ref()) {
+            Ok(network) => network.actions(),
+            Err(err) => {
+                eprintln!("Could not read {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        },
+    };
+    let ticks = (seconds * config.tick_rate as f32).round() as u64;
+
+    // Every frame advances the clock by one tick, so the simulation runs as fast as it can.
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, SimulationPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_secs_f64(
+            1.0 / config.tick_rate as f64,
+        )))
+        .insert_resource(State::new(config, globe_points))
+        .insert_resource(PlayerActions(actions));
+    seed_cities(app.world_mut(), 2);
+
+    while app.world().resource::<State>().tick < ticks {
+        app.update();
+    }
+
+    println!("After {seconds} simulated seconds:");
+    println!("{}", NetworkStats::collect(app.world_mut()));
+
+    if let Some(dir) = export {
+        let state = app.world().resource::<State>();
+        let rail_usage: Vec<_> = state
+            .rails
+            .rails
+            .iter()
+            .map(|(rail, info)| (*rail, info.counter.load(Ordering::Relaxed)))
+            .collect();
+        let globe_points = state.globe_points.read().unwrap();
+        let width = 4 * state.config.grid_size;
+        for projection in [Projection::Faces, Projection::Equirectangular { width }] {
+            match export_layers(&dir, &globe_points, &state.config, &rail_usage, projection) {
+                Ok(paths) => println!("Wrote {} images to {}.", paths.len(), dir.display()),
+                Err(err) => {
+                    eprintln!("Could not export layers: {err}");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
+    if let Some(path) = gltf {
+        match export_world(&path, app.world_mut(), &globe_mesh) {
+            Ok(()) => println!("Wrote {}.", path.display()),
+            Err(err) => {
+                eprintln!("Could not write {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if let Some(path) = geojson {
+        match export_network(&path, app.world_mut()) {
+            Ok(()) => println!("Wrote {}.", path.display()),
+            Err(err) => {
+                eprintln!("Could not write {}: {err}", path.display());
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+// Removes `--<name> <value>` or `--<name>=<value>` from the arguments.
+fn take_option(args: &mut Vec<String>, name: &str) -> Result<Option<String>, String> {
+    let flag = format!("--{name}");
+    let Some(i) = args
+        .iter()
+        .position(|arg| *arg == flag || arg.starts_with(&format!("{flag}=")))
+    else {
+        return Ok(None);
+    };
+    let arg = args.remove(i);
+    match arg.strip_prefix(&format!("{flag}=")) {
+        Some(value) => Ok(Some(value.to_string())),
+        None if i < args.len() => Ok(Some(args.remove(i))),
+        None => Err(format!("missing value for {flag}")),
+    }
+}
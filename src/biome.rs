@@ -0,0 +1,145 @@
+use crate::noise::{NoiseSource, noise_source};
+use crate::perlin::PerlinConfig;
+
+use bevy::math::Vec3;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BiomeConfig {
+    pub moisture: PerlinConfig, // noise added to the average moisture of 0.5
+    pub polar_cooling: f32,     // temperature drop from the equator to the poles
+    pub desert_penalty: f32,
+    pub grassland_penalty: f32,
+    pub forest_penalty: f32,
+    pub tundra_penalty: f32,
+    pub swamp_penalty: f32,
+    pub ice_penalty: f32,
+}
+
+impl Default for BiomeConfig {
+    fn default() -> Self {
+        Self {
+            moisture: PerlinConfig {
+                seed: 1848,
+                frequency: 2.0,
+                lacunarity: 2.0,
+                persistence: 0.5,
+                octaves: 4,
+                amplitude: 1.2,
+                ..PerlinConfig::default()
+            },
+            polar_cooling: 1.0,
+            desert_penalty: 1.5,
+            grassland_penalty: 1.0,
+            forest_penalty: 2.0,
+            tundra_penalty: 2.0,
+            swamp_penalty: 4.0,
+            ice_penalty: 3.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Biome {
+    Desert,
+    #[default]
+    Grassland,
+    Forest,
+    Tundra,
+    Swamp,
+    Ice,
+}
+
+// Temperature below which the ground is frozen, and below which only tundra grows.
+const FREEZING: f32 = 0.0;
+const COLD: f32 = 0.25;
+// Temperature above which dry land is desert and wet land is swamp.
+const HOT: f32 = 0.55;
+// Moisture bounds of grassland and forest.
+const DRY: f32 = 0.3;
+const WET: f32 = 0.7;
+
+impl Biome {
+    pub const ALL: [Biome; 6] = [
+        Biome::Desert,
+        Biome::Grassland,
+        Biome::Forest,
+        Biome::Tundra,
+        Biome::Swamp,
+        Biome::Ice,
+    ];
+
+    pub fn classify(temperature: f32, moisture: f32) -> Self {
+        if temperature < FREEZING {
+            Biome::Ice
+        } else if temperature < COLD {
+            Biome::Tundra
+        } else if moisture < DRY {
+            if temperature >= HOT {
+                Biome::Desert
+            } else {
+                Biome::Grassland
+            }
+        } else if moisture < WET {
+            Biome::Grassland
+        } else if temperature >= HOT {
+            Biome::Swamp
+        } else {
+            Biome::Forest
+        }
+    }
+
+    pub fn color(self) -> [f32; 4] {
+        match self {
+            Biome::Desert => [0.76, 0.68, 0.42, 1.0],
+            Biome::Grassland => [0.36, 0.56, 0.2, 1.0],
+            Biome::Forest => [0.1, 0.34, 0.12, 1.0],
+            Biome::Tundra => [0.55, 0.55, 0.45, 1.0],
+            Biome::Swamp => [0.28, 0.34, 0.22, 1.0],
+            Biome::Ice => [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+
+    pub fn penalty(self, config: &BiomeConfig) -> f32 {
+        match self {
+            Biome::Desert => config.desert_penalty,
+            Biome::Grassland => config.grassland_penalty,
+            Biome::Forest => config.forest_penalty,
+            Biome::Tundra => config.tundra_penalty,
+            Biome::Swamp => config.swamp_penalty,
+            Biome::Ice => config.ice_penalty,
+        }
+    }
+}
+
+/// Temperature and moisture over the globe. Temperature is 1 at sea level on the equator,
+/// falls by `polar_cooling` towards the poles and reaches freezing at `snow_level` above the sea.
+pub struct Climate {
+    config: BiomeConfig,
+    snow_level: f32,
+    moisture: Box<dyn NoiseSource>,
+}
+
+impl Climate {
+    pub fn new(config: &BiomeConfig, snow_level: f32) -> Self {
+        Self {
+            config: *config,
+            snow_level,
+            moisture: noise_source(&config.moisture),
+        }
+    }
+
+    pub fn temperature(&self, dir: Vec3, height: f32) -> f32 {
+        1.0 - self.config.polar_cooling * dir.y * dir.y - height.max(0.0) / self.snow_level
+    }
+
+    pub fn moisture(&self, dir: Vec3) -> f32 {
+        (0.5 + self.moisture.noise(dir.x, dir.y, dir.z)).clamp(0.0, 1.0)
+    }
+
+    /// Biome of the ground in direction `dir` from the centre, `height` above sea level.
+    pub fn biome(&self, dir: Vec3, height: f32) -> Biome {
+        Biome::classify(self.temperature(dir, height), self.moisture(dir))
+    }
+}
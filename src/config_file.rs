@@ -0,0 +1,337 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use crate::dijkstra::{MAX_GRID_SIZE, STEPS};
+use crate::erosion::MAX_EROSION_STRENGTH;
+use crate::state::Config;
+
+pub const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String),
+    Usage(String),
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read {path}: {err}"),
+            ConfigError::Parse(msg) => write!(f, "could not parse config: {msg}"),
+            ConfigError::Usage(msg) => write!(
+                f,
+                "{msg}\nusage: terrain3d [--config <file.toml>] [--<field> <value>]..., \
+                 e.g. --grid_size 128 --perlin_config.seed 7"
+            ),
+            ConfigError::Invalid(problems) => write!(f, "invalid config: {}", problems.join("; ")),
+        }
+    }
+}
+
+/// Reads `CONFIG_FILE` (or the file given with `--config`) and applies `--<field> <value>`
+/// overrides from the command line. On the web there is no file, and the overrides come from
+/// the query string instead, e.g. `?grid_size=128&perlin_config.seed=7`.
+pub fn load_config() -> Result<Config, ConfigError> {
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        config_from_args(&args)
+    }
+    #[cfg(target_arch = "wasm32")]
+    {
+        let query = web_sys::window()
+            .and_then(|window| window.location().search().ok())
+            .unwrap_or_default();
+        config_from_query(&query)
+    }
+}
+
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub fn config_from_args(args: &[String]) -> Result<Config, ConfigError> {
+    let (path, overrides) = parse_args(args)?;
+    let text = match path {
+        Some(path) => fs::read_to_string(&path).map_err(|err| ConfigError::Io(path, err))?,
+        None => match fs::read_to_string(CONFIG_FILE) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(ConfigError::Io(CONFIG_FILE.to_string(), err)),
+        },
+    };
+    config_from_str(&text, &overrides)
+}
+
+// `(dotted.field, value)` pairs, applied on top of the config file.
+type Overrides = Vec<(String, String)>;
+
+/// The `--config` path and the `--<field> <value>` overrides in `args`, without reading any
+/// file.
+#[cfg_attr(target_arch = "wasm32", allow(dead_code))]
+pub fn parse_args(args: &[String]) -> Result<(Option<String>, Overrides), ConfigError> {
+    let mut path = None;
+    let mut overrides = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::Usage(format!("unexpected argument `{arg}`")));
+        };
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key, value.to_string()),
+            None => match args.next() {
+                Some(value) => (flag, value.clone()),
+                None => return Err(ConfigError::Usage(format!("missing value for --{flag}"))),
+            },
+        };
+        if key == "config" {
+            path = Some(value);
+        } else {
+            overrides.push((key.replace('-', "_"), value));
+        }
+    }
+    Ok((path, overrides))
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+pub fn config_from_query(query: &str) -> Result<Config, ConfigError> {
+    let overrides: Vec<(String, String)> = query
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, "true"));
+            (key.replace('-', "_"), value.to_string())
+        })
+        .collect();
+    config_from_str("", &overrides)
+}
+
+/// Parses a TOML config, where missing fields keep their defaults, then applies the
+/// `(dotted.field, value)` overrides and validates the result.
+pub fn config_from_str(text: &str, overrides: &[(String, String)]) -> Result<Config, ConfigError> {
+    let mut table: toml::Table = text
+        .parse()
+        .map_err(|err: toml::de::Error| ConfigError::Parse(err.to_string()))?;
+    for (key, value) in overrides {
+        let mut fields: Vec<&str> = key.split('.').collect();
+        let last = fields.pop().unwrap();
+        let mut current = &mut table;
+        for field in fields {
+            let entry = current
+                .entry(field)
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            let Some(next) = entry.as_table_mut() else {
+                return Err(ConfigError::Usage(format!(
+                    "`{field}` in `{key}` is not a table"
+                )));
+            };
+            current = next;
+        }
+        current.insert(last.to_string(), parse_value(value));
+    }
+    let config: Config = table
+        .try_into()
+        .map_err(|err: toml::de::Error| ConfigError::Parse(err.to_string()))?;
+    config.validate()?;
+    Ok(config)
+}
+
+// Overrides are TOML values, so `5`, `2.5` and `true` keep their types. Anything else is
+// taken as a plain string.
+fn parse_value(value: &str) -> toml::Value {
+    format!("value = {value}")
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()))
+}
+
+impl Config {
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        let mut check = |ok: bool, problem: &str| {
+            if !ok {
+                problems.push(problem.to_string());
+            }
+        };
+        check(
+            self.grid_size >= 2 * STEPS as u32,
+            &format!(
+                "grid_size must be at least {} to fit the {STEPS}-step neighbourhood",
+                2 * STEPS
+            ),
+        );
+        check(
+            self.grid_size <= MAX_GRID_SIZE,
+            &format!("grid_size must be at most {MAX_GRID_SIZE}"),
+        );
+        check(self.sea_level > 0.0, "sea_level must be positive");
+        check(self.snow_level > 0.0, "snow_level must be positive");
+        let noises = [
+            ("perlin_config", Some(&self.perlin_config)),
+            ("mountain_config", self.mountain_config.as_ref()),
+            ("biome_config.moisture", Some(&self.biome_config.moisture)),
+        ];
+        for (name, perlin) in noises {
+            let Some(perlin) = perlin else {
+                continue;
+            };
+            check(
+                perlin.octaves > 0,
+                &format!("{name}.octaves must be at least 1"),
+            );
+            check(
+                perlin.frequency > 0.0,
+                &format!("{name}.frequency must be positive"),
+            );
+            check(
+                perlin.lacunarity > 0.0,
+                &format!("{name}.lacunarity must be positive"),
+            );
+            check(
+                perlin.persistence > 0.0,
+                &format!("{name}.persistence must be positive"),
+            );
+            check(
+                perlin.amplitude.is_finite(),
+                &format!("{name}.amplitude must be finite"),
+            );
+            check(
+                perlin.warp.is_finite(),
+                &format!("{name}.warp must be finite"),
+            );
+        }
+        if let Some(heightmap) = &self.heightmap {
+            check(
+                heightmap.equirectangular.is_some() != heightmap.faces.is_some(),
+                "heightmap needs either equirectangular or faces",
+            );
+            check(
+                heightmap.min_height < heightmap.max_height,
+                "heightmap.min_height must be below max_height",
+            );
+            // Decoded in full, so that a corrupt image is reported here rather than failing
+            // `make_globe`.
+            let mut sizes = Vec::new();
+            for path in heightmap.paths() {
+                match image::open(path) {
+                    Ok(image) => sizes.push((image.width(), image.height())),
+                    Err(err) => check(false, &format!("could not read heightmap {path}: {err}")),
+                }
+            }
+            if heightmap.faces.is_some() && sizes.len() == 6 {
+                check(
+                    sizes.iter().all(|&size| size == sizes[0]),
+                    "heightmap faces must all have the same size",
+                );
+            }
+        }
+        check(
+            (0.0..=MAX_EROSION_STRENGTH).contains(&self.erosion_config.strength),
+            &format!("erosion_config.strength must be between 0 and {MAX_EROSION_STRENGTH}"),
+        );
+        check(self.water_penalty > 0.0, "water_penalty must be positive");
+        let biome = &self.biome_config;
+        check(
+            biome.polar_cooling.is_finite(),
+            "biome_config.polar_cooling must be finite",
+        );
+        check(
+            [
+                biome.desert_penalty,
+                biome.grassland_penalty,
+                biome.forest_penalty,
+                biome.tundra_penalty,
+                biome.swamp_penalty,
+                biome.ice_penalty,
+            ]
+            .iter()
+            .all(|&penalty| penalty > 0.0),
+            "biome_config penalties must be positive",
+        );
+        check(
+            self.river_threshold > 0.0,
+            "river_threshold must be positive",
+        );
+        check(
+            self.bridge_penalty >= 0.0,
+            "bridge_penalty must not be negative",
+        );
+        let structures = &self.structure_config;
+        check(
+            structures.clearance >= 0.0,
+            "structure_config.clearance must not be negative",
+        );
+        check(
+            structures.tunnel_cost > 0.0 && structures.bridge_cost > 0.0,
+            "structure_config tunnel_cost and bridge_cost must be positive",
+        );
+        check(
+            structures.pier_cost >= 0.0,
+            "structure_config.pier_cost must not be negative",
+        );
+        check(
+            self.min_city_distance >= 0.0,
+            "min_city_distance must not be negative",
+        );
+        check(
+            self.reduction_factor >= 1.0,
+            "reduction_factor must be at least 1",
+        );
+        check(
+            self.climbing_cost >= 0.0,
+            "climbing_cost must not be negative",
+        );
+        check(self.max_grade > 0.0, "max_grade must be positive");
+        check(
+            self.min_turn_radius >= 0.0,
+            "min_turn_radius must not be negative",
+        );
+        check(
+            self.network_connectivity > 0,
+            "network_connectivity must be at least 1",
+        );
+        check(
+            self.route_alternatives > 0,
+            "route_alternatives must be at least 1",
+        );
+        check(
+            self.num_automatic_trains >= 0,
+            "num_automatic_trains must not be negative",
+        );
+        check(self.tick_rate > 0, "tick_rate must be positive");
+        let lod = &self.lod_config;
+        check(lod.chunk_size > 0, "lod_config.chunk_size must be positive");
+        check(
+            lod.lod_distance > 0.0,
+            "lod_config.lod_distance must be positive",
+        );
+        check(
+            lod.skirt_depth >= 0.0,
+            "lod_config.skirt_depth must not be negative",
+        );
+        let costs = &self.cost_model_config;
+        check(
+            costs.length_exponent > 0.0 && costs.length_exponent <= 1.0,
+            "cost_model_config.length_exponent must be above 0 and at most 1",
+        );
+        check(
+            costs.construction_cost >= 0.0,
+            "cost_model_config.construction_cost must not be negative",
+        );
+        check(
+            costs.operating_cost >= 0.0,
+            "cost_model_config.operating_cost must not be negative",
+        );
+        check(
+            costs.operating_climbing_cost >= 0.0,
+            "cost_model_config.operating_climbing_cost must not be negative",
+        );
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+}
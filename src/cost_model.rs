@@ -0,0 +1,197 @@
+use crate::dijkstra::{Edge, EdgeKind, GlobePoint, GlobePoints};
+use crate::structures::StructureConfig;
+
+use serde::{Deserialize, Serialize};
+
+/// Cost of the edges of the graph, evaluated by the searches for every edge they look at. The
+/// graph only stores where the edges go, their kind and the gap under bridges, so models and
+/// their parameters can be swapped without rebuilding it.
+pub trait CostModel: Send + Sync {
+    /// Cost of the edge from `p` to `q`, `edge.built` telling whether a rail runs along it.
+    fn cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32;
+
+    /// Lower bound on the cost of any edge per radian of great-circle distance it covers, which
+    /// scales the A* heuristic.
+    fn min_cost_per_radian(&self, globe_points: &GlobePoints) -> f32;
+}
+
+/// Length to the power `length_exponent` plus climbing, times the terrain penalty, with
+/// `bridge_penalty` more for crossing a river and tunnels and bridges over valleys priced by
+/// `StructureConfig`. Edges with a rail cost `reduction_factor` times less.
+pub struct TerrainCost {
+    pub reduction_factor: f32,
+    pub length_exponent: f32,
+    pub climbing_cost: f32,
+    pub bridge_penalty: f32,
+    pub structures: StructureConfig,
+}
+
+impl TerrainCost {
+    /// Distance and climbing parts of the cost of the edge from `p` to `q`, before the terrain
+    /// penalty is applied.
+    pub fn unscaled(&self, p: &GlobePoint, q: &GlobePoint) -> (f32, f32) {
+        // Below 1 the exponent slightly prefers longer steps. 5^0.9 = 4.25, for example.
+        (
+            p.pos.distance(q.pos).powf(self.length_exponent),
+            self.climbing_cost * (p.pos.length() - q.pos.length()).abs(),
+        )
+    }
+
+    /// Cost of the edge from `p` to `q` without a rail.
+    pub fn unbuilt_cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32 {
+        let length = p.pos.distance(q.pos);
+        let climbing = self.climbing_cost * (p.pos.length() - q.pos.length()).abs();
+        let structures = &self.structures;
+        match edge.kind {
+            EdgeKind::Tunnel => length * structures.tunnel_cost + climbing,
+            EdgeKind::Bridge if edge.gap > 0.0 => {
+                length * (structures.bridge_cost + structures.pier_cost * edge.gap) + climbing
+            }
+            kind => {
+                let (distance, climbing) = self.unscaled(p, q);
+                let river = if kind == EdgeKind::Bridge {
+                    self.bridge_penalty
+                } else {
+                    0.0
+                };
+                (distance + climbing) * p.penalty.max(q.penalty) + river
+            }
+        }
+    }
+}
+
+impl CostModel for TerrainCost {
+    fn cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32 {
+        let cost = self.unbuilt_cost(p, q, edge);
+        if edge.built {
+            cost / self.reduction_factor
+        } else {
+            cost
+        }
+    }
+
+    // An edge of at most `STEPS` covers at least `min_length_per_radian` of length per radian,
+    // and with an exponent of at most 1 its length to the exponent is at least its length times
+    // `max_step_length` to the exponent minus 1. Tunnels and bridges cost at least their length
+    // times `tunnel_cost` or `bridge_cost`.
+    fn min_cost_per_radian(&self, globe_points: &GlobePoints) -> f32 {
+        if globe_points.min_length_per_radian == 0.0 {
+            return 0.0;
+        }
+        let step = globe_points
+            .max_step_length
+            .powf(self.length_exponent - 1.0)
+            * globe_points.min_penalty;
+        let per_length = step
+            .min(self.structures.tunnel_cost)
+            .min(self.structures.bridge_cost);
+        let min = globe_points.min_length_per_radian * per_length;
+        if globe_points.has_rails {
+            min / self.reduction_factor
+        } else {
+            min
+        }
+    }
+}
+
+/// Length alone, ignoring the terrain. Edges with a rail cost `reduction_factor` times less.
+pub struct DistanceCost {
+    pub reduction_factor: f32,
+}
+
+impl CostModel for DistanceCost {
+    fn cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32 {
+        let length = p.pos.distance(q.pos);
+        if edge.built {
+            length / self.reduction_factor
+        } else {
+            length
+        }
+    }
+
+    fn min_cost_per_radian(&self, globe_points: &GlobePoints) -> f32 {
+        if globe_points.has_rails {
+            globe_points.min_length_per_radian / self.reduction_factor
+        } else {
+            globe_points.min_length_per_radian
+        }
+    }
+}
+
+/// Building a rail once plus running trains on it. Every edge costs the operating cost of its
+/// length and climb, edges without a rail also the construction of their length, times the
+/// terrain penalty or the cost of a tunnel or bridge.
+pub struct ConstructionOperatingCost {
+    pub config: CostModelConfig,
+    pub structures: StructureConfig,
+}
+
+impl CostModel for ConstructionOperatingCost {
+    fn cost(&self, p: &GlobePoint, q: &GlobePoint, edge: &Edge) -> f32 {
+        let length = p.pos.distance(q.pos);
+        let climb = (p.pos.length() - q.pos.length()).abs();
+        let operating =
+            length * self.config.operating_cost + climb * self.config.operating_climbing_cost;
+        if edge.built {
+            return operating;
+        }
+        let terrain = match edge.kind {
+            EdgeKind::Surface => p.penalty.max(q.penalty),
+            EdgeKind::Tunnel => self.structures.tunnel_cost,
+            EdgeKind::Bridge => self.structures.bridge_cost,
+        };
+        operating + length * self.config.construction_cost * terrain
+    }
+
+    // Only the operating cost is paid on every edge.
+    fn min_cost_per_radian(&self, globe_points: &GlobePoints) -> f32 {
+        globe_points.min_length_per_radian * self.config.operating_cost
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CostModelKind {
+    #[default]
+    Terrain,
+    Distance,
+    ConstructionOperating,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CostModelConfig {
+    pub model: CostModelKind,
+    pub length_exponent: f32, // of the length of an edge under `terrain`, at most 1
+    pub construction_cost: f32, // per unit of length of new track
+    pub operating_cost: f32,  // per unit of length
+    pub operating_climbing_cost: f32, // per unit of height difference
+}
+
+impl Default for CostModelConfig {
+    fn default() -> Self {
+        Self {
+            model: CostModelKind::Terrain,
+            length_exponent: 0.9,
+            construction_cost: 1.0,
+            operating_cost: 0.2,
+            operating_climbing_cost: 1.0,
+        }
+    }
+}
+
+impl CostModelConfig {
+    /// The configured model, taking the parameters it shares with `terrain` from it.
+    pub fn cost_model(self, terrain: TerrainCost) -> Box<dyn CostModel> {
+        match self.model {
+            CostModelKind::Terrain => Box::new(terrain),
+            CostModelKind::Distance => Box::new(DistanceCost {
+                reduction_factor: terrain.reduction_factor,
+            }),
+            CostModelKind::ConstructionOperating => Box::new(ConstructionOperatingCost {
+                config: self,
+                structures: terrain.structures,
+            }),
+        }
+    }
+}
@@ -1,32 +1,143 @@
+use crate::biome::Biome;
+use crate::cost_model::CostModel;
+use crate::structures::{StructureConfig, span_targets, structure};
+
 use bevy::math::{Vec2, Vec3};
+use bevy::platform::collections::{HashMap as FastHashMap, HashSet as FastHashSet};
 use ordered_float::OrderedFloat;
 use priority_queue::PriorityQueue;
-use std::collections::{HashMap, VecDeque};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 use std::time::Instant;
 
 // face index, row, column
 pub type GridPoint = (u32, u32, u32);
 
+// Dense index of a grid point, see `GridPoints::index`.
+pub type NodeIndex = u32;
+
 pub struct Edge {
-    pub to: GridPoint,
-    pub cost: f32,
-    pub discounted: bool, // true if cost reduction has been applied
+    pub to: NodeIndex,
+    pub built: bool, // true if a rail runs along the edge
+    pub kind: EdgeKind,
+    pub gap: f32, // mean height of a bridge over a valley above the ground, else 0, see `structure`
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum EdgeKind {
+    #[default]
+    Surface, // following the ground
+    Tunnel, // through terrain rising above the track, see `structure`
+    Bridge, // over a river, or over a valley or water
+}
+
+impl EdgeKind {
+    pub const ALL: [EdgeKind; 3] = [EdgeKind::Surface, EdgeKind::Tunnel, EdgeKind::Bridge];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeKind::Surface => "surface",
+            EdgeKind::Tunnel => "tunnel",
+            EdgeKind::Bridge => "bridge",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 pub struct GlobePoint {
     pub pos: Vec3,
-    pub water: bool,
+    pub water: bool, // sea or lake
     pub penalty: f32,
+    pub river: bool,
+    pub lake: bool,
+    pub flow: f32, // fraction of the globe draining through this point
+    pub biome: Biome,
+}
+
+// All (grid_size + 1)^2 points of each of the six faces, stored face by face, row by row.
+#[derive(Default)]
+pub struct GridPoints {
+    grid_size: u32,
+    points: Vec<GlobePoint>,
+}
+
+impl GridPoints {
+    pub fn new(grid_size: u32) -> Self {
+        let m = grid_size as usize + 1;
+        Self {
+            grid_size,
+            points: vec![GlobePoint::default(); 6 * m * m],
+        }
+    }
+
+    pub fn grid_size(&self) -> u32 {
+        self.grid_size
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn index(&self, grid: &GridPoint) -> Option<NodeIndex> {
+        let m = self.grid_size + 1;
+        if grid.0 >= 6 || grid.1 >= m || grid.2 >= m || self.points.is_empty() {
+            return None;
+        }
+        Some((grid.0 * m + grid.1) * m + grid.2)
+    }
+
+    pub fn gridpoint(&self, idx: NodeIndex) -> GridPoint {
+        let m = self.grid_size + 1;
+        (idx / (m * m), idx / m % m, idx % m)
+    }
+
+    pub fn get(&self, grid: &GridPoint) -> Option<&GlobePoint> {
+        self.index(grid).map(|idx| &self.points[idx as usize])
+    }
+
+    pub fn insert(&mut self, grid: GridPoint, point: GlobePoint) {
+        let idx = self.index(&grid).expect("grid point out of range");
+        self.points[idx as usize] = point;
+    }
+}
+
+impl std::ops::Index<NodeIndex> for GridPoints {
+    type Output = GlobePoint;
+
+    fn index(&self, idx: NodeIndex) -> &GlobePoint {
+        &self.points[idx as usize]
+    }
+}
+
+impl std::ops::IndexMut<NodeIndex> for GridPoints {
+    fn index_mut(&mut self, idx: NodeIndex) -> &mut GlobePoint {
+        &mut self.points[idx as usize]
+    }
 }
 
 #[derive(Default)]
 pub struct GlobePoints {
-    pub points: HashMap<GridPoint, GlobePoint>,
-    pub graph: HashMap<GridPoint, Vec<Edge>>,
+    pub points: GridPoints,
+    // Compressed sparse row adjacency: the edges leaving node `i` are
+    // `edges[offsets[i]..offsets[i + 1]]`.
+    offsets: Vec<u32>,
+    edges: Vec<Edge>,
+    // Smallest edge length per radian of great-circle distance, longest edge of at most `STEPS`
+    // and smallest terrain penalty, from which cost models bound the cost of an edge per radian
+    // to scale the A* heuristic, and whether any edge has a rail.
+    pub min_length_per_radian: f32,
+    pub max_step_length: f32,
+    pub min_penalty: f32,
+    pub has_rails: bool,
 }
 
-fn cubic(grid: GridPoint, size: u32) -> [i32; 3] {
+pub fn cubic(grid: GridPoint, size: u32) -> [i32; 3] {
     let u = grid.1 as i32;
     let v = grid.2 as i32;
     let s = size as i32;
@@ -41,160 +152,495 @@ fn cubic(grid: GridPoint, size: u32) -> [i32; 3] {
     }
 }
 
-fn cost(p: &GlobePoint, q: &GlobePoint, climbing_cost: f32) -> f32 {
-    let penalty = p.penalty.max(q.penalty);
-    let p_height = p.pos.length();
-    let q_height = q.pos.length();
-    // Slightly prefer longer steps. 5^0.9 = 4.25, for example.
-    let unscaled_cost =
-        p.pos.distance(q.pos).powf(0.9) + climbing_cost * (p_height - q_height).abs();
-    unscaled_cost * penalty
+// For every grid point, the index of the first grid point at the same place. Points on the
+// edges of the faces are shared by two or three faces, all other points map to themselves.
+pub fn shared_points(grid_size: u32) -> Vec<NodeIndex> {
+    let m = grid_size + 1;
+    let mut first = HashMap::new();
+    let mut shared = Vec::with_capacity((6 * m * m) as usize);
+    for face in 0..6 {
+        for row in 0..m {
+            for col in 0..m {
+                let idx = (face * m + row) * m + col;
+                let on_edge = row == 0 || col == 0 || row == grid_size || col == grid_size;
+                shared.push(if on_edge {
+                    *first
+                        .entry(cubic((face, row, col), grid_size))
+                        .or_insert(idx)
+                } else {
+                    idx
+                });
+            }
+        }
+    }
+    shared
 }
 
-impl GlobePoints {
-    pub fn build_graph(&mut self, grid_size: u32, climbing_cost: f32) {
-        let steps = 7i32;
-        let size = grid_size as i32;
-        let mut edges = 0;
-        for (pts_done, (&grid, &p)) in self.points.iter().enumerate() {
-            if pts_done % 10000 == 0 {
-                println!(
-                    "Building graph: {}/{}, edges {}",
-                    pts_done,
-                    self.points.len(),
-                    edges
-                );
+// Kind and gap of an edge of at most `STEPS` between two points. Edges crossing a river are
+// short bridges without a gap.
+fn surface_edge(points: &GridPoints, from: NodeIndex, to: NodeIndex) -> (EdgeKind, f32) {
+    if crosses_river(points, from, to) {
+        (EdgeKind::Bridge, 0.0)
+    } else {
+        (EdgeKind::Surface, 0.0)
+    }
+}
+
+/// Whether the edge between two points passes over a river, from one bank to the other. Edges
+/// that start or end on a river run along it rather than crossing it. Within a face the grid point
+/// nearest to every step along the edge is checked, edges across faces never cross.
+pub fn crosses_river(points: &GridPoints, from: NodeIndex, to: NodeIndex) -> bool {
+    if points[from].river || points[to].river {
+        return false;
+    }
+    let (a, b) = (points.gridpoint(from), points.gridpoint(to));
+    if a.0 != b.0 {
+        return false;
+    }
+    let (di, dj) = (b.1 as f32 - a.1 as f32, b.2 as f32 - a.2 as f32);
+    let steps = di.abs().max(dj.abs()) as u32;
+    (1..steps).any(|k| {
+        let t = k as f32 / steps as f32;
+        let row = (a.1 as f32 + di * t).round() as u32;
+        let col = (a.2 as f32 + dj * t).round() as u32;
+        points.get(&(a.0, row, col)).is_some_and(|p| p.river)
+    })
+}
+
+// Smallest terrain penalty of any grid point, 0 if there are none.
+fn min_penalty(points: &GridPoints) -> f32 {
+    let min = points
+        .points
+        .iter()
+        .map(|p| p.penalty)
+        .fold(f32::INFINITY, f32::min);
+    if min.is_finite() { min } else { 0.0 }
+}
+
+// Great-circle distance between the directions of two points, in radians.
+fn great_circle_angle(p: Vec3, q: Vec3) -> f32 {
+    p.cross(q).length().atan2(p.dot(q))
+}
+
+// Maximum step length of an edge, in grid units.
+pub const STEPS: i32 = 7;
+
+// Largest supported grid size, about 25 million grid points.
+pub const MAX_GRID_SIZE: u32 = 2048;
+
+// Origin and the unit directions of the row and column axes of each face in the cube
+// coordinates of `cubic`, for a cube of side `size`.
+pub fn face_frame(face: u32, size: i32) -> ([i32; 3], [i32; 3], [i32; 3]) {
+    let s = size;
+    match face {
+        0 => ([0, 0, s], [1, 0, 0], [0, 1, 0]),
+        1 => ([s, 0, 0], [-1, 0, 0], [0, 1, 0]),
+        2 => ([s, 0, s], [0, 0, -1], [0, 1, 0]),
+        3 => ([0, 0, 0], [0, 0, 1], [0, 1, 0]),
+        4 => ([0, s, s], [1, 0, 0], [0, 0, -1]),
+        5 => ([0, 0, 0], [1, 0, 0], [0, 0, 1]),
+        _ => unreachable!(),
+    }
+}
+
+// Appends the edges leaving `idx` and returns their smallest length per radian and the length
+// of the longest one of at most `STEPS`.
+fn node_edges(
+    points: &GridPoints,
+    idx: NodeIndex,
+    structures: &StructureConfig,
+    edges: &mut Vec<Edge>,
+) -> (f32, f32) {
+    let grid_size = points.grid_size();
+    let size = grid_size as i32;
+    let grid = points.gridpoint(idx);
+    let p = points[idx];
+    let mut min_length_per_radian = f32::INFINITY;
+    let mut max_step_length = 0.0f32;
+    let mut push_edge = |to: NodeIndex, (kind, gap): (EdgeKind, f32), step: bool| {
+        let q = &points[to];
+        let length = p.pos.distance(q.pos);
+        min_length_per_radian =
+            min_length_per_radian.min(length / great_circle_angle(p.pos, q.pos));
+        if step {
+            max_step_length = max_step_length.max(length);
+        }
+        edges.push(Edge {
+            to,
+            built: false,
+            kind,
+            gap,
+        });
+    };
+    let surface = |to| surface_edge(points, idx, to);
+    for di in -STEPS..=STEPS {
+        for dj in -STEPS..=STEPS {
+            if di == 0 && dj == 0 {
+                continue;
             }
-            for di in -steps..=steps {
-                for dj in -steps..=steps {
-                    if di == 0 && dj == 0 {
-                        continue;
-                    }
-                    if di * di + dj * dj > steps * steps {
+            if di * di + dj * dj > STEPS * STEPS {
+                continue;
+            }
+            let neighbor = (
+                grid.0,
+                (grid.1 as i32 + di) as u32,
+                (grid.2 as i32 + dj) as u32,
+            );
+            if let Some(to) = points.index(&neighbor) {
+                push_edge(to, surface(to), true);
+            }
+        }
+    }
+    if !(grid.1 as i32 > STEPS
+        && (grid.1 as i32) < size - STEPS
+        && grid.2 as i32 > STEPS
+        && (grid.2 as i32) < size - STEPS)
+    {
+        // We're within `STEPS` of the edge of a face, so some neighbours may be on the adjacent
+        // faces, if only on their shared edge. Project this point onto the plane of each other
+        // face and look at the grid points around the projection.
+        let this = cubic(grid, grid_size);
+        for other_face in 0..6 {
+            if other_face == grid.0 {
+                continue; // skip the same face
+            }
+            let (origin, row_axis, col_axis) = face_frame(other_face, size);
+            let rel = [0, 1, 2].map(|k| this[k] - origin[k]);
+            let dot = |axis: [i32; 3]| rel[0] * axis[0] + rel[1] * axis[1] + rel[2] * axis[2];
+            let (row, col) = (dot(row_axis), dot(col_axis));
+            let normal2 = rel.iter().map(|x| x * x).sum::<i32>() - row * row - col * col;
+            if normal2 > STEPS * STEPS {
+                continue; // the opposite face
+            }
+            for other_row in (row - STEPS).max(0)..=(row + STEPS).min(size) {
+                for other_col in (col - STEPS).max(0)..=(col + STEPS).min(size) {
+                    let dist2 = (other_row - row).pow(2) + (other_col - col).pow(2) + normal2;
+                    if dist2 > STEPS * STEPS || dist2 == 0 {
                         continue;
                     }
-                    let neighbor = (
-                        grid.0,
-                        (grid.1 as i32 + di) as u32,
-                        (grid.2 as i32 + dj) as u32,
-                    );
-                    if let Some(&q) = self.points.get(&neighbor) {
-                        self.graph.entry(grid).or_default().push(Edge {
-                            to: neighbor,
-                            cost: cost(&p, &q, climbing_cost),
-                            discounted: false,
-                        });
-                        edges += 1;
+                    if let Some(to) =
+                        points.index(&(other_face, other_row as u32, other_col as u32))
+                    {
+                        push_edge(to, surface(to), true);
                     }
                 }
             }
-            if !(grid.1 as i32 >= steps
-                && (grid.1 as i32) <= size - steps
-                && grid.2 as i32 >= steps
-                && (grid.2 as i32) <= size - steps)
-            {
-                // We're within `steps` of the edge of a face, and we want to step at most `steps` far,
-                // so the next point is either on our face, within steps of us (checked above), or on a
-                // different face, within `steps`` of the edge that face.
-                let this = cubic(grid, grid_size);
-                for big in 0..=grid_size as i32 {
-                    for small in 0..=steps {
-                        for which_edge in 0..4 {
-                            for other_face in 0..6 {
-                                if other_face == grid.0 {
-                                    continue; // skip the same face
-                                }
-                                let neighbor = (
-                                    other_face,
-                                    match which_edge {
-                                        0 => small,
-                                        1 => grid_size as i32 - small,
-                                        2 => big,
-                                        3 => grid_size as i32 - big,
-                                        _ => unreachable!(),
-                                    } as u32,
-                                    match which_edge {
-                                        0 => big,
-                                        1 => grid_size as i32 - big,
-                                        2 => small,
-                                        3 => grid_size as i32 - small,
-                                        _ => unreachable!(),
-                                    } as u32,
-                                );
-                                let other = cubic(neighbor, grid_size);
-                                let dist2 = (other[0] - this[0]).pow(2)
-                                    + (other[1] - this[1]).pow(2)
-                                    + (other[2] - this[2]).pow(2);
-                                if dist2 > steps * steps || dist2 == 0 {
-                                    continue;
-                                }
-                                if let Some(q) = self.points.get(&neighbor) {
-                                    self.graph.entry(grid).or_default().push(Edge {
-                                        to: neighbor,
-                                        cost: cost(&p, q, climbing_cost),
-                                        discounted: false,
-                                    });
-                                    edges += 1;
-                                }
+        }
+    }
+    for to in span_targets(points, idx, structures) {
+        if let Some(edge) = structure(points, idx, to, structures) {
+            push_edge(to, edge, false);
+        }
+    }
+    (min_length_per_radian, max_step_length)
+}
+
+// Keeps the heuristic strictly below the true cost despite f32 rounding.
+const HEURISTIC_SLACK: f32 = 0.999;
+
+impl GlobePoints {
+    pub fn new(grid_size: u32) -> Self {
+        Self {
+            points: GridPoints::new(grid_size),
+            ..Default::default()
+        }
+    }
+
+    pub fn from_csr(
+        points: GridPoints,
+        offsets: Vec<u32>,
+        edges: Vec<Edge>,
+        min_length_per_radian: f32,
+        max_step_length: f32,
+    ) -> Self {
+        assert_eq!(offsets.len(), points.len() + 1);
+        assert_eq!(*offsets.last().unwrap() as usize, edges.len());
+        let has_rails = edges.iter().any(|edge| edge.built);
+        let min_penalty = min_penalty(&points);
+        Self {
+            points,
+            offsets,
+            edges,
+            min_length_per_radian,
+            max_step_length,
+            min_penalty,
+            has_rails,
+        }
+    }
+
+    // The raw adjacency arrays, see `offsets` and `edges`.
+    pub fn csr(&self) -> (&[u32], &[Edge]) {
+        (&self.offsets, &self.edges)
+    }
+
+    pub fn edges(&self, node: NodeIndex) -> &[Edge] {
+        if self.offsets.is_empty() {
+            return &[];
+        }
+        let node = node as usize;
+        &self.edges[self.offsets[node] as usize..self.offsets[node + 1] as usize]
+    }
+
+    fn edges_mut(&mut self, node: NodeIndex) -> &mut [Edge] {
+        if self.offsets.is_empty() {
+            return &mut [];
+        }
+        let node = node as usize;
+        &mut self.edges[self.offsets[node] as usize..self.offsets[node + 1] as usize]
+    }
+
+    pub fn build_graph(&mut self, structures: &StructureConfig) {
+        let points = &self.points;
+        let num_points = points.len();
+        let num_workers = thread::available_parallelism().map_or(1, |n| n.get());
+        let chunk_size = num_points.div_ceil(num_workers).max(1);
+        let pts_done = AtomicUsize::new(0);
+
+        // Each worker builds the CSR slice of one contiguous chunk of points.
+        let chunks: Vec<(Vec<u32>, Vec<Edge>, f32, f32)> = thread::scope(|scope| {
+            let workers: Vec<_> = (0..num_points)
+                .step_by(chunk_size)
+                .map(|chunk_start| {
+                    let pts_done = &pts_done;
+                    scope.spawn(move || {
+                        let chunk_end = (chunk_start + chunk_size).min(num_points);
+                        let mut offsets = Vec::with_capacity(chunk_end - chunk_start);
+                        let mut edges = Vec::new();
+                        let mut min_length_per_radian = f32::INFINITY;
+                        let mut max_step_length = 0.0f32;
+                        for idx in chunk_start..chunk_end {
+                            let done = pts_done.fetch_add(1, Ordering::Relaxed);
+                            if done.is_multiple_of(10000) {
+                                println!("Building graph: {done}/{num_points}");
                             }
+                            let (length_per_radian, step_length) =
+                                node_edges(points, idx as NodeIndex, structures, &mut edges);
+                            min_length_per_radian = min_length_per_radian.min(length_per_radian);
+                            max_step_length = max_step_length.max(step_length);
+                            offsets.push(edges.len() as u32);
                         }
+                        (offsets, edges, min_length_per_radian, max_step_length)
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect()
+        });
+
+        let total_edges = chunks.iter().map(|(_, edges, _, _)| edges.len()).sum();
+        let (mut min_length_per_radian, mut max_step_length) = (f32::INFINITY, 0.0f32);
+        self.offsets = Vec::with_capacity(num_points + 1);
+        self.edges = Vec::with_capacity(total_edges);
+        self.offsets.push(0);
+        for (offsets, edges, chunk_length_per_radian, chunk_step_length) in chunks {
+            let base = self.edges.len() as u32;
+            self.offsets
+                .extend(offsets.into_iter().map(|offset| base + offset));
+            self.edges.extend(edges);
+            min_length_per_radian = min_length_per_radian.min(chunk_length_per_radian);
+            max_step_length = max_step_length.max(chunk_step_length);
+        }
+        println!("Graph built: {num_points} points, {total_edges} edges");
+        self.min_length_per_radian = if min_length_per_radian.is_finite() {
+            min_length_per_radian
+        } else {
+            0.0
+        };
+        self.max_step_length = max_step_length;
+        self.min_penalty = min_penalty(&self.points);
+        self.has_rails = false;
+    }
+
+    /// Marks the edges along `path`, in both directions, as having a rail, which cost models
+    /// usually make cheaper to reuse.
+    pub fn mark_built(&mut self, path: &[GridPoint]) {
+        for w in path.windows(2) {
+            let (Some(a), Some(b)) = (self.points.index(&w[0]), self.points.index(&w[1])) else {
+                continue;
+            };
+            for (from, to) in [(a, b), (b, a)] {
+                let mut found = false;
+                for edge in self.edges_mut(from) {
+                    if edge.to == to {
+                        edge.built = true;
+                        found = true;
                     }
                 }
+                self.has_rails |= found;
             }
         }
     }
+
+    /// Removes every rail from the graph.
+    pub fn clear_built(&mut self) {
+        for edge in &mut self.edges {
+            edge.built = false;
+        }
+        self.has_rails = false;
+    }
+
+    /// Kind of the edge from `from` to `to`, if there is one.
+    pub fn edge_kind(&self, from: &GridPoint, to: &GridPoint) -> Option<EdgeKind> {
+        let (from, to) = (self.points.index(from)?, self.points.index(to)?);
+        self.edges(from)
+            .iter()
+            .find(|edge| edge.to == to)
+            .map(|edge| edge.kind)
+    }
+
+    // Admissible and consistent lower bound on the cost of getting from `node` to `goal`, with
+    // `scale` from `heuristic_scale`.
+    fn heuristic(&self, node: NodeIndex, goal: Vec3, scale: f32) -> f32 {
+        great_circle_angle(self.points[node].pos, goal) * scale
+    }
+
+    fn heuristic_scale(&self, model: &dyn CostModel) -> f32 {
+        model.min_cost_per_radian(self) * HEURISTIC_SLACK
+    }
+
+    // Cost of `edge` leaving `from` under `model`.
+    fn edge_cost(&self, model: &dyn CostModel, from: NodeIndex, edge: &Edge) -> f32 {
+        model.cost(&self.points[from], &self.points[edge.to], edge)
+    }
+
+    fn path_from(&self, prev: &[NodeIndex], end: NodeIndex) -> Vec<GridPoint> {
+        let mut path = vec![self.points.gridpoint(end)];
+        let mut current = end;
+        while prev[current as usize] != NO_NODE {
+            current = prev[current as usize];
+            path.push(self.points.gridpoint(current));
+        }
+        path
+    }
 }
 
+// Marks a missing predecessor in the per-node `prev` arrays of the searches.
+const NO_NODE: NodeIndex = NodeIndex::MAX;
+
 #[allow(dead_code)]
-pub fn dijkstra(start: GridPoint, end: GridPoint, globe_points: &GlobePoints) -> Vec<GridPoint> {
+pub fn dijkstra(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> Vec<GridPoint> {
     if start == end {
         return vec![start];
     }
+    let (Some(start_idx), Some(end_idx)) = (
+        globe_points.points.index(&start),
+        globe_points.points.index(&end),
+    ) else {
+        return Vec::new();
+    };
 
     let start_time = Instant::now();
 
+    let n = globe_points.points.len();
     let mut queue = PriorityQueue::new();
-    let mut visited = HashMap::new();
-    let mut come_from = HashMap::new();
-    queue.push(start, OrderedFloat(0.0));
+    let mut visited = vec![false; n];
+    let mut come_from = vec![NO_NODE; n];
+    queue.push(start_idx, OrderedFloat(0.0));
     while let Some((current, current_dist)) = queue.pop() {
-        if visited.contains_key(&current) {
-            continue;
-        }
-        visited.insert(current, current_dist);
-        if current == end {
+        visited[current as usize] = true;
+        if current == end_idx {
             break;
         }
-        if let Some(edges) = globe_points.graph.get(&current) {
-            for edge in edges {
-                if visited.contains_key(&edge.to) {
-                    continue;
-                }
-                let new_neg_dist = current_dist - OrderedFloat(edge.cost);
-                if queue.get_priority(&edge.to).is_none() {
-                    queue.push(edge.to, new_neg_dist);
-                    come_from.insert(edge.to, current);
-                } else if new_neg_dist > *queue.get_priority(&edge.to).unwrap() {
-                    queue.change_priority(&edge.to, new_neg_dist);
-                    come_from.insert(edge.to, current);
-                }
+        for edge in globe_points.edges(current) {
+            if visited[edge.to as usize] {
+                continue;
+            }
+            let new_neg_dist =
+                current_dist - OrderedFloat(globe_points.edge_cost(model, current, edge));
+            if queue
+                .get_priority(&edge.to)
+                .is_none_or(|&d| new_neg_dist > d)
+            {
+                queue.push(edge.to, new_neg_dist);
+                come_from[edge.to as usize] = current;
             }
         }
     }
-    let mut path = Vec::new();
-    let mut current = end;
-    while let Some(&prev) = come_from.get(&current) {
-        path.push(current);
-        if prev == start {
-            path.push(start);
+    // The path runs from `end` back to `start`.
+    let path = if visited[end_idx as usize] {
+        globe_points.path_from(&come_from, end_idx)
+    } else {
+        Vec::new()
+    };
+
+    if cfg!(debug_assertions) {
+        println!(
+            "Path found in {} ms, {} steps",
+            start_time.elapsed().as_millis(),
+            path.len()
+        );
+    }
+
+    path
+}
+
+#[allow(dead_code)]
+pub fn astar(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> Vec<GridPoint> {
+    if start == end {
+        return vec![start];
+    }
+    let (Some(start_idx), Some(end_idx)) = (
+        globe_points.points.index(&start),
+        globe_points.points.index(&end),
+    ) else {
+        return Vec::new();
+    };
+    let goal = globe_points.points[end_idx].pos;
+    let scale = globe_points.heuristic_scale(model);
+
+    let start_time = Instant::now();
+
+    let n = globe_points.points.len();
+    let mut queue = PriorityQueue::new();
+    let mut dist = vec![f32::INFINITY; n];
+    let mut come_from = vec![NO_NODE; n];
+    let mut visited = vec![false; n];
+    dist[start_idx as usize] = 0.0;
+    queue.push(
+        start_idx,
+        -OrderedFloat(globe_points.heuristic(start_idx, goal, scale)),
+    );
+    while let Some((current, _)) = queue.pop() {
+        if current == end_idx {
             break;
         }
-        current = prev;
+        visited[current as usize] = true;
+        let current_dist = dist[current as usize];
+        for edge in globe_points.edges(current) {
+            if visited[edge.to as usize] {
+                continue;
+            }
+            let new_dist = current_dist + globe_points.edge_cost(model, current, edge);
+            if new_dist < dist[edge.to as usize] {
+                dist[edge.to as usize] = new_dist;
+                come_from[edge.to as usize] = current;
+                let estimate = new_dist + globe_points.heuristic(edge.to, goal, scale);
+                queue.push(edge.to, -OrderedFloat(estimate));
+            }
+        }
+    }
+
+    let mut path = Vec::new();
+    if dist[end_idx as usize].is_finite() {
+        path = globe_points.path_from(&come_from, end_idx);
+        path.reverse();
     }
 
     if cfg!(debug_assertions) {
         println!(
-            "Path found in {} ms, {} steps",
+            "A* path found in {} ms, {} steps",
             start_time.elapsed().as_millis(),
             path.len()
         );
@@ -203,165 +649,374 @@ pub fn dijkstra(start: GridPoint, end: GridPoint, globe_points: &GlobePoints) ->
     path
 }
 
-
-#[derive(Hash, PartialEq, Eq, Clone)]
-struct NodeInfo {
-    node: GridPoint,
-    from_start: bool, // true if this node is from the start side of the search
+// One direction of a bidirectional search.
+struct SearchSide {
+    queue: PriorityQueue<NodeIndex, OrderedFloat<f32>>,
+    dist: Vec<f32>,
+    prev: Vec<NodeIndex>,
+    visited: Vec<bool>,
 }
 
+impl SearchSide {
+    fn new(n: usize, root: NodeIndex, key: f32) -> Self {
+        let mut side = Self {
+            queue: PriorityQueue::new(),
+            dist: vec![f32::INFINITY; n],
+            prev: vec![NO_NODE; n],
+            visited: vec![false; n],
+        };
+        side.dist[root as usize] = 0.0;
+        side.queue.push(root, -OrderedFloat(key));
+        side
+    }
 
-#[derive(Hash, PartialEq, Eq, Clone, Debug)]
-struct Priority {
-    dist: OrderedFloat<f32>,
-    prev: Option<GridPoint>,
+    fn top_key(&self) -> Option<f32> {
+        self.queue.peek().map(|(_, key)| -key.0)
+    }
 }
 
-impl Ord for Priority {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.dist.cmp(&other.dist)
+// Bidirectional search using the average of the forward and backward A* heuristics as
+// potential, so that both sides see the same non-negative reduced edge costs. The search stops
+// once the two queue tops together cannot improve on the best meeting cost `mu`, which makes
+// the result optimal. Without a heuristic this is plain bidirectional Dijkstra. Edges steeper than
+// `max_grade` are skipped, which works the same in both directions.
+fn bidirectional_search(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+    use_heuristic: bool,
+    max_grade: f32,
+) -> (Vec<GridPoint>, f32) {
+    if start == end {
+        return (vec![start], 0.0);
     }
-}
+    let (Some(start_idx), Some(end_idx)) = (
+        globe_points.points.index(&start),
+        globe_points.points.index(&end),
+    ) else {
+        return (Vec::new(), f32::INFINITY);
+    };
+    let start_pos = globe_points.points[start_idx].pos;
+    let end_pos = globe_points.points[end_idx].pos;
+    // Potential of the forward search; the backward search uses its negation.
+    let scale = globe_points.heuristic_scale(model);
+    let potential = |node: NodeIndex| {
+        if use_heuristic {
+            (globe_points.heuristic(node, end_pos, scale)
+                - globe_points.heuristic(node, start_pos, scale))
+                / 2.0
+        } else {
+            0.0
+        }
+    };
+
+    let n = globe_points.points.len();
+    let mut sides = [
+        SearchSide::new(n, start_idx, potential(start_idx)),
+        SearchSide::new(n, end_idx, -potential(end_idx)),
+    ];
+    let mut mu = f32::INFINITY;
+    let mut meet_point: Option<NodeIndex> = None;
+
+    while let (Some(top_start), Some(top_end)) = (sides[0].top_key(), sides[1].top_key()) {
+        if top_start + top_end >= mu {
+            break;
+        }
+        let this = if top_start <= top_end { 0 } else { 1 };
+        let sign = if this == 0 { 1.0 } else { -1.0 };
+        let [forward, backward] = &mut sides;
+        let (this_side, other_side) = if this == 0 {
+            (forward, &*backward)
+        } else {
+            (backward, &*forward)
+        };
+
+        let (current, _) = this_side.queue.pop().unwrap();
+        this_side.visited[current as usize] = true;
+        let current_dist = this_side.dist[current as usize];
+        let p = globe_points.points[current].pos;
 
-impl PartialOrd for Priority {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+        for edge in globe_points.edges(current) {
+            let to = edge.to as usize;
+            if this_side.visited[to] || too_steep(p, globe_points.points[edge.to].pos, max_grade) {
+                continue;
+            }
+            let new_dist = current_dist + globe_points.edge_cost(model, current, edge);
+            if new_dist >= this_side.dist[to] {
+                continue;
+            }
+            this_side.dist[to] = new_dist;
+            this_side.prev[to] = current;
+            this_side
+                .queue
+                .push(edge.to, -OrderedFloat(new_dist + sign * potential(edge.to)));
+            if new_dist + other_side.dist[to] < mu {
+                mu = new_dist + other_side.dist[to];
+                meet_point = Some(edge.to);
+            }
+        }
     }
-}
 
+    let mut path = VecDeque::new();
+    if let Some(meet) = meet_point {
+        // Reconstruct the path from start to meet point
+        for grid in globe_points.path_from(&sides[0].prev, meet) {
+            path.push_front(grid);
+        }
+        // Reconstruct the path from meet point to end
+        path.extend(
+            globe_points
+                .path_from(&sides[1].prev, meet)
+                .into_iter()
+                .skip(1),
+        );
+    }
 
+    (path.into_iter().collect(), mu)
+}
+
+#[allow(dead_code)]
 pub fn bidirectional_dijkstra(
     start: GridPoint,
     end: GridPoint,
     globe_points: &GlobePoints,
-) -> Vec<GridPoint> {
-    if start == end {
-        return vec![start];
+    model: &dyn CostModel,
+) -> (Vec<GridPoint>, f32) {
+    let start_time = Instant::now();
+    let (path, path_cost) =
+        bidirectional_search(start, end, globe_points, model, false, f32::INFINITY);
+    if cfg!(debug_assertions) {
+        println!(
+            "Bidirectional path found in {} ms, {} steps",
+            start_time.elapsed().as_millis(),
+            path.len()
+        );
     }
+    (path, path_cost)
+}
 
+pub fn bidirectional_astar(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> (Vec<GridPoint>, f32) {
     let start_time = Instant::now();
+    let (path, path_cost) =
+        bidirectional_search(start, end, globe_points, model, true, f32::INFINITY);
+    if cfg!(debug_assertions) {
+        println!(
+            "Bidirectional A* path found in {} ms, {} steps",
+            start_time.elapsed().as_millis(),
+            path.len()
+        );
+    }
+    (path, path_cost)
+}
 
+// Whether the edge from `p` to `q` rises or falls more than `max_grade` times its length.
+fn too_steep(p: Vec3, q: Vec3, max_grade: f32) -> bool {
+    (p.length() - q.length()).abs() > max_grade * p.distance(q)
+}
 
-    // Priority queue for both directions
-    let mut queue: PriorityQueue<NodeInfo, Priority> = PriorityQueue::new();
-    let mut visited_start: HashMap<GridPoint, Option<GridPoint>> = HashMap::new();
-    let mut visited_end: HashMap<GridPoint, Option<GridPoint>> = HashMap::new();
+/// Limits on the geometry of the track that `route` keeps to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackLimits {
+    pub max_grade: f32,       // height difference over length of an edge
+    pub min_turn_radius: f32, // 0 allows any turn
+}
 
-    queue.push(
-        NodeInfo {
-            node: start,
-            from_start: true,
-        },
-        Priority {
-            dist: OrderedFloat(0.0),
-            prev: None,
-        },
-    );
-    queue.push(
-        NodeInfo {
-            node: end,
-            from_start: false,
-        },
-        Priority {
-            dist: OrderedFloat(0.0),
-            prev: None,
-        },
-    );
+impl TrackLimits {
+    // Whether an arc of `min_turn_radius` fits between the edges `incoming` and `outgoing` of a
+    // turn, using at most half of the shorter edge on either side of the corner.
+    fn turn_fits(&self, incoming: Vec3, outgoing: Vec3) -> bool {
+        let half_angle = incoming.angle_between(outgoing) / 2.0;
+        let room = incoming.length().min(outgoing.length()) / 2.0;
+        room >= self.min_turn_radius * half_angle.tan()
+    }
+}
 
-    let mut meet_point: Option<GridPoint> = None;
+// Cheapest cost from each grid point to `end` within the grade limit, ignoring turns, which is
+// the heuristic of `constrained_astar`. Searched backwards from `end`, only as far as asked for.
+struct CostToEnd<'a> {
+    globe_points: &'a GlobePoints,
+    model: &'a dyn CostModel,
+    max_grade: f32,
+    dist: Vec<f32>,
+    settled: Vec<bool>,
+    queue: BinaryHeap<Reverse<(OrderedFloat<f32>, NodeIndex)>>,
+}
 
-    while let Some((current_info, current_prio)) = queue.pop() {
-        let current = current_info.node;
+impl<'a> CostToEnd<'a> {
+    fn new(
+        end: NodeIndex,
+        globe_points: &'a GlobePoints,
+        model: &'a dyn CostModel,
+        max_grade: f32,
+    ) -> Self {
+        let n = globe_points.points.len();
+        let mut dist = vec![f32::INFINITY; n];
+        dist[end as usize] = 0.0;
+        Self {
+            globe_points,
+            model,
+            max_grade,
+            dist,
+            settled: vec![false; n],
+            queue: BinaryHeap::from([Reverse((OrderedFloat(0.0), end))]),
+        }
+    }
 
-        let this_visited: &mut HashMap<GridPoint, Option<GridPoint>>;
-        let other_visited: &HashMap<GridPoint, Option<GridPoint>>;
-        if current_info.from_start {
-            this_visited = &mut visited_start;
-            other_visited = &visited_end;
-        } else {
-            this_visited = &mut visited_end;
-            other_visited = &visited_start;
+    // Infinite if `end` can't be reached from `node`. Edges cost the same both ways, as in
+    // `bidirectional_search`.
+    fn get(&mut self, node: NodeIndex) -> f32 {
+        let points = &self.globe_points.points;
+        while !self.settled[node as usize] {
+            let Some(Reverse((_, current))) = self.queue.pop() else {
+                return f32::INFINITY;
+            };
+            if std::mem::replace(&mut self.settled[current as usize], true) {
+                continue;
+            }
+            let p = points[current].pos;
+            for edge in self.globe_points.edges(current) {
+                let to = edge.to as usize;
+                if self.settled[to] || too_steep(p, points[edge.to].pos, self.max_grade) {
+                    continue;
+                }
+                let new_dist = self.dist[current as usize]
+                    + self.globe_points.edge_cost(self.model, current, edge);
+                if new_dist < self.dist[to] {
+                    self.dist[to] = new_dist;
+                    self.queue.push(Reverse((OrderedFloat(new_dist), edge.to)));
+                }
+            }
         }
+        self.dist[node as usize]
+    }
+}
+
+/// A* over grid points and the grid point the train arrives from, which skips edges steeper than
+/// `limits.max_grade` and turns sharper than `limits.min_turn_radius` allows. Steep slopes are
+/// climbed in switchbacks, if at all. The turns are checked against the exact edge the train
+/// arrives on, which is part of the search state, so the path is the cheapest one within the
+/// limits. Returns an empty path if the limits leave no way from `start` to `end`.
+pub fn constrained_astar(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    limits: &TrackLimits,
+    model: &dyn CostModel,
+) -> (Vec<GridPoint>, f32) {
+    let start_time = Instant::now();
+    let points = &globe_points.points;
+    let (Some(start_idx), Some(end_idx)) = (points.index(&start), points.index(&end)) else {
+        return (Vec::new(), f32::INFINITY);
+    };
+    let mut cost_to_end = CostToEnd::new(end_idx, globe_points, model, limits.max_grade);
+    let start_estimate = cost_to_end.get(start_idx);
+    if start_estimate == f32::INFINITY {
+        return (Vec::new(), f32::INFINITY);
+    }
 
-        if this_visited.contains_key(&current) {
+    // State `(node, came_from)`, with `NO_NODE` at the start. Few of them are ever reached, so
+    // they are kept in maps. Without decreasing keys in a binary heap, states that were improved
+    // on are popped again later and skipped as closed.
+    let mut queue = BinaryHeap::new();
+    let mut dist: FastHashMap<_, f32> = FastHashMap::new();
+    let mut prev = FastHashMap::new();
+    let mut closed = FastHashSet::new();
+    let start_state = (start_idx, NO_NODE);
+    dist.insert(start_state, 0.0);
+    queue.push(Reverse((OrderedFloat(start_estimate), start_state)));
+    let mut end_state = None;
+    while let Some(Reverse((_, current))) = queue.pop() {
+        if !closed.insert(current) {
             continue;
         }
-        this_visited.insert(current, current_prio.prev);
-        if other_visited.contains_key(&current) {
-            meet_point = Some(current);
-            break; // We found a meeting point
+        let (node, came_from) = current;
+        if node == end_idx {
+            end_state = Some(current);
+            break;
         }
-
-        // Process neighbors
-        if let Some(edges) = globe_points.graph.get(&current) {
-            for edge in edges {
-                let neighbor_info = NodeInfo {
-                    node: edge.to,
-                    from_start: current_info.from_start,
-                };
-                let new_neg_dist = current_prio.dist - OrderedFloat(edge.cost);
-                let new_prio = Priority {
-                    dist: new_neg_dist,
-                    prev: Some(current),
-                };
-                if !this_visited.contains_key(&edge.to) {
-                    queue.push_increase(neighbor_info, new_prio.clone());
+        let p = points[node].pos;
+        let incoming = (came_from != NO_NODE).then(|| p - points[came_from].pos);
+        for edge in globe_points.edges(node) {
+            let q = points[edge.to].pos;
+            if too_steep(p, q, limits.max_grade)
+                || incoming.is_some_and(|incoming| !limits.turn_fits(incoming, q - p))
+            {
+                continue;
+            }
+            let next = (edge.to, node);
+            if closed.contains(&next) {
+                continue;
+            }
+            let new_dist = dist[&current] + globe_points.edge_cost(model, node, edge);
+            if new_dist < dist.get(&next).copied().unwrap_or(f32::INFINITY) {
+                dist.insert(next, new_dist);
+                prev.insert(next, current);
+                let estimate = new_dist + cost_to_end.get(edge.to);
+                if estimate < f32::INFINITY {
+                    queue.push(Reverse((OrderedFloat(estimate), next)));
                 }
             }
         }
     }
 
-    let mut path = VecDeque::new();
-    if let Some(meet) = meet_point {
-        // Reconstruct the path from start to meet point
-        let mut current = meet;
-        while let Some(prev) = visited_start.get(&current) {
-            path.push_front(current);
-            if let Some(p) = prev {
-                current = *p;
-            } else {
-                break; // Reached the start point
-            }
-        }
-        // Reconstruct the path from end to meet point
-        current = meet;
-        while let Some(prev) = visited_end.get(&current) {
-            if let Some(p) = prev {
-                path.push_back(*p);
-                current = *p;
-            } else {
-                break; // Reached the end point
-            }
-        }
+    let Some(end_state) = end_state else {
+        return (Vec::new(), f32::INFINITY);
+    };
+    let mut path = vec![end];
+    let mut current = end_state;
+    while let Some(&state) = prev.get(&current) {
+        current = state;
+        path.push(points.gridpoint(current.0));
     }
+    path.reverse();
 
-    let path: Vec<GridPoint> = path.into_iter().collect();
     if cfg!(debug_assertions) {
         println!(
-            "Bidirectional path found in {} ms, {} steps",
+            "Constrained A* path found in {} ms, {} steps",
             start_time.elapsed().as_millis(),
             path.len()
         );
     }
-    path
+    (path, dist[&end_state])
 }
 
-pub fn get_closest_gridpoint(pos: Vec3, grid_size: u32) -> GridPoint {
-    let idx = argmax(pos.abs());
-    let sign_at_max = if pos[idx] < 0.0 { -1 } else { 1 };
+/// Path for a new railway that keeps to `limits`. Only a minimum turning radius needs the
+/// incoming edge in the search state, a maximum grade alone is left to the much faster
+/// bidirectional A*.
+pub fn route(
+    start: GridPoint,
+    end: GridPoint,
+    globe_points: &GlobePoints,
+    limits: &TrackLimits,
+    model: &dyn CostModel,
+) -> (Vec<GridPoint>, f32) {
+    if limits.min_turn_radius > 0.0 {
+        return constrained_astar(start, end, globe_points, limits, model);
+    }
+    let start_time = Instant::now();
+    let (path, path_cost) =
+        bidirectional_search(start, end, globe_points, model, true, limits.max_grade);
+    if cfg!(debug_assertions) {
+        println!(
+            "Graded bidirectional A* path found in {} ms, {} steps",
+            start_time.elapsed().as_millis(),
+            path.len()
+        );
+    }
+    (path, path_cost)
+}
 
-    let face = match (idx, sign_at_max) {
-        (0, 1) => 2,
-        (0, -1) => 3,
-        (1, 1) => 4,
-        (1, -1) => 5,
-        (2, 1) => 0,
-        (2, -1) => 1,
-        _ => unreachable!(),
-    };
+pub fn get_closest_gridpoint(pos: Vec3, grid_size: u32) -> GridPoint {
+    let gridpoint = nearest_gridpoint(pos, grid_size);
 
     if cfg!(debug_assertions) {
-        let color = match face {
+        let color = match gridpoint.0 {
             0 => "red",
             1 => "green",
             2 => "blue",
@@ -371,7 +1026,35 @@ pub fn get_closest_gridpoint(pos: Vec3, grid_size: u32) -> GridPoint {
             _ => unreachable!(),
         };
         println!("face color: {color:?}");
+        println!("gridpoint: {gridpoint:?}");
     }
+    gridpoint
+}
+
+/// The grid point of a globe of `grid_size` closest to the direction of `pos`.
+pub fn nearest_gridpoint(pos: Vec3, grid_size: u32) -> GridPoint {
+    let (face, xy) = face_coordinates(pos);
+    let [i, j] = (xy * grid_size as f32)
+        .round()
+        .to_array()
+        .map(|c| (c as u32).min(grid_size));
+    (face, i, j)
+}
+
+/// The face `pos` is over, and its position on the face, both coordinates between 0 and 1.
+pub fn face_coordinates(pos: Vec3) -> (u32, Vec2) {
+    let idx = argmax(pos.abs());
+    let sign_at_max = if pos[idx] < 0.0 { -1 } else { 1 };
+
+    let face = match (idx, sign_at_max) {
+        (0, 1) => 2,
+        (0, -1) => 3,
+        (1, 1) => 4,
+        (1, -1) => 5,
+        (2, 1) => 0,
+        (2, -1) => 1,
+        _ => unreachable!(),
+    };
 
     let norm_pos = pos * 0.5 / pos[idx];
 
@@ -384,14 +1067,7 @@ pub fn get_closest_gridpoint(pos: Vec3, grid_size: u32) -> GridPoint {
         5 => Vec2::new(-norm_pos.x, -norm_pos.z),
         _ => unreachable!(),
     };
-    let grid_x = ((xy.x + 0.5) * grid_size as f32).round() as u32;
-    let grid_y = ((xy.y + 0.5) * grid_size as f32).round() as u32;
-
-    let gridpoint = (face, grid_x, grid_y);
-    if cfg!(debug_assertions) {
-        println!("gridpoint: {gridpoint:?}");
-    }
-    gridpoint
+    (face, xy + Vec2::splat(0.5))
 }
 
 fn argmax(v: Vec3) -> usize {
@@ -0,0 +1,221 @@
+use crate::dijkstra::{face_frame, shared_points};
+
+use bevy::math::{Vec2, Vec3};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErosionConfig {
+    pub iterations: u32, // number of droplets, 0 disables erosion
+    pub strength: f32,   // scales how fast droplets erode and deposit sediment
+    pub seed: u64,
+}
+
+impl Default for ErosionConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 0,
+            strength: 1.0,
+            seed: 1,
+        }
+    }
+}
+
+// Droplet parameters, in grid cells and units of height.
+const INERTIA: f32 = 0.05; // how much a droplet keeps its direction instead of going downhill
+const CAPACITY: f32 = 4.0; // sediment carried per unit of speed, water and slope
+const MIN_CAPACITY: f32 = 0.01;
+const ERODE_SPEED: f32 = 0.3;
+const DEPOSIT_SPEED: f32 = 0.3;
+const EVAPORATE_SPEED: f32 = 0.01;
+const GRAVITY: f32 = 4.0;
+const MAX_LIFETIME: u32 = 30;
+
+// Beyond this a droplet would erode or deposit more than the sediment difference per step.
+pub const MAX_EROSION_STRENGTH: f32 = 1.0 / ERODE_SPEED;
+
+/// Droplet based hydraulic erosion of the heights of the cube-sphere grid, indexed like
+/// `GridPoints`. Droplets run downhill, picking up sediment where they speed up and dropping it
+/// where they slow down, and carve valleys and ridges into the terrain. Droplets cross between
+/// the faces, and the grid points shared by adjacent faces keep equal heights.
+pub fn erode(heights: &mut [f32], grid_size: u32, config: &ErosionConfig) {
+    let field = HeightField::new(grid_size);
+    let mut rng = ChaCha12Rng::seed_from_u64(config.seed);
+    let size = grid_size as f32;
+    for iteration in 0..config.iterations {
+        if iteration > 0 && iteration.is_multiple_of(100000) {
+            println!("Eroded with {iteration} droplets");
+        }
+        let mut droplet = Droplet {
+            face: rng.random_range(0..6),
+            pos: Vec2::new(rng.random_range(0.0..size), rng.random_range(0.0..size)),
+            dir: Vec2::ZERO,
+            speed: 1.0,
+            water: 1.0,
+            sediment: 0.0,
+        };
+        for _ in 0..MAX_LIFETIME {
+            if !field.step(heights, &mut droplet, config.strength) {
+                break;
+            }
+        }
+    }
+    // Copy the shared heights back to every face.
+    for idx in 0..heights.len() {
+        heights[idx] = heights[field.canonical[idx] as usize];
+    }
+}
+
+struct Droplet {
+    face: u32,
+    pos: Vec2, // row and column on `face`
+    dir: Vec2,
+    speed: f32,
+    water: f32,
+    sediment: f32,
+}
+
+struct HeightField {
+    size: u32,
+    // Grid points on the edges of the faces appear on several faces, all of them read and write
+    // the height of the first one.
+    canonical: Vec<u32>,
+    frames: [(Vec3, Vec3, Vec3, Vec3); 6], // origin, row axis, column axis, outward normal
+}
+
+impl HeightField {
+    fn new(size: u32) -> Self {
+        let canonical = shared_points(size);
+        let half = size as f32 / 2.0;
+        let frames = std::array::from_fn(|face| {
+            let (origin, row, col) = face_frame(face as u32, size as i32);
+            let [origin, row, col] = [origin, row, col].map(|v| Vec3::from(v.map(|x| x as f32)));
+            let normal = (origin + (row + col) * half - Vec3::splat(half)) / half;
+            (origin, row, col, normal)
+        });
+        Self {
+            size,
+            canonical,
+            frames,
+        }
+    }
+
+    fn index(&self, face: u32, row: u32, col: u32) -> usize {
+        let m = self.size + 1;
+        self.canonical[((face * m + row) * m + col) as usize] as usize
+    }
+
+    // The cell containing `pos` and the offset of `pos` within it.
+    fn cell(&self, pos: Vec2) -> (u32, u32, f32, f32) {
+        let max = self.size - 1;
+        let row = (pos.x.floor().max(0.0) as u32).min(max);
+        let col = (pos.y.floor().max(0.0) as u32).min(max);
+        (row, col, pos.x - row as f32, pos.y - col as f32)
+    }
+
+    // Indices of the corners of the cell containing `pos` and their bilinear weights.
+    fn corners(&self, face: u32, pos: Vec2) -> [(usize, f32); 4] {
+        let (row, col, x, y) = self.cell(pos);
+        [
+            (self.index(face, row, col), (1.0 - x) * (1.0 - y)),
+            (self.index(face, row + 1, col), x * (1.0 - y)),
+            (self.index(face, row, col + 1), (1.0 - x) * y),
+            (self.index(face, row + 1, col + 1), x * y),
+        ]
+    }
+
+    fn height_and_gradient(&self, heights: &[f32], face: u32, pos: Vec2) -> (f32, Vec2) {
+        let [(a, wa), (b, wb), (c, wc), (d, wd)] = self.corners(face, pos);
+        let (ha, hb, hc, hd) = (heights[a], heights[b], heights[c], heights[d]);
+        let height = ha * wa + hb * wb + hc * wc + hd * wd;
+        let (_, _, x, y) = self.cell(pos);
+        let gradient = Vec2::new(
+            (hb - ha) * (1.0 - y) + (hd - hc) * y,
+            (hc - ha) * (1.0 - x) + (hd - hb) * x,
+        );
+        (height, gradient)
+    }
+
+    fn contains(&self, pos: Vec2) -> bool {
+        let size = self.size as f32;
+        (0.0..=size).contains(&pos.x) && (0.0..=size).contains(&pos.y)
+    }
+
+    // Cube coordinates of a point in the plane of `face`.
+    fn cube_point(&self, face: u32, pos: Vec2) -> Vec3 {
+        let (origin, row, col, _) = self.frames[face as usize];
+        origin + row * pos.x + col * pos.y
+    }
+
+    // Projects a point outside the cube through its centre onto the face it is over, the same
+    // way the grid is projected onto the sphere.
+    fn project_onto_face(&self, point: Vec3) -> (u32, Vec2) {
+        let half = self.size as f32 / 2.0;
+        let rel = point - Vec3::splat(half);
+        let face = (0..6)
+            .max_by(|&a, &b| {
+                let along = |face: u32| rel.dot(self.frames[face as usize].3);
+                along(a).total_cmp(&along(b))
+            })
+            .unwrap();
+        let (origin, row, col, normal) = self.frames[face as usize];
+        let on_face = Vec3::splat(half) + rel * (half / rel.dot(normal)) - origin;
+        (face, Vec2::new(on_face.dot(row), on_face.dot(col)))
+    }
+
+    // Moves the droplet one cell, eroding or depositing at its old position. Returns false when
+    // the droplet stops.
+    fn step(&self, heights: &mut [f32], droplet: &mut Droplet, strength: f32) -> bool {
+        let (height, gradient) = self.height_and_gradient(heights, droplet.face, droplet.pos);
+        let dir = droplet.dir * INERTIA - gradient * (1.0 - INERTIA);
+        if dir.length_squared() == 0.0 {
+            return false;
+        }
+        droplet.dir = dir.normalize();
+        let (mut face, mut pos) = (droplet.face, droplet.pos + droplet.dir);
+        if !self.contains(pos) {
+            // Continue on the adjacent face, turning the direction over the edge.
+            let ahead = self.cube_point(droplet.face, pos + droplet.dir);
+            (face, pos) = self.project_onto_face(self.cube_point(droplet.face, pos));
+            let (ahead_face, ahead_pos) = self.project_onto_face(ahead);
+            if !self.contains(pos) || ahead_face != face {
+                return false; // at a corner of the cube
+            }
+            droplet.dir = (ahead_pos - pos).normalize_or_zero();
+        }
+
+        let (new_height, _) = self.height_and_gradient(heights, face, pos);
+        let delta_height = new_height - height;
+        let capacity = (-delta_height * droplet.speed * droplet.water * CAPACITY).max(MIN_CAPACITY);
+        let corners = self.corners(droplet.face, droplet.pos);
+        if droplet.sediment > capacity || delta_height > 0.0 {
+            // Fill the pit the droplet is leaving, or drop what it can't carry.
+            let deposit = if delta_height > 0.0 {
+                delta_height.min(droplet.sediment)
+            } else {
+                (droplet.sediment - capacity) * DEPOSIT_SPEED * strength
+            };
+            droplet.sediment -= deposit;
+            for (idx, weight) in corners {
+                heights[idx] += deposit * weight;
+            }
+        } else {
+            // Never dig deeper than the height the droplet is moving down to.
+            let erode = ((capacity - droplet.sediment) * ERODE_SPEED * strength).min(-delta_height);
+            droplet.sediment += erode;
+            for (idx, weight) in corners {
+                heights[idx] -= erode * weight;
+            }
+        }
+
+        droplet.speed = (droplet.speed * droplet.speed + delta_height * GRAVITY)
+            .max(0.0)
+            .sqrt();
+        droplet.water *= 1.0 - EVAPORATE_SPEED;
+        droplet.face = face;
+        droplet.pos = pos;
+        true
+    }
+}
@@ -0,0 +1,276 @@
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::cost_model::CostModel;
+use crate::dijkstra::{
+    GlobePoints, GridPoint, bidirectional_astar, crosses_river, nearest_gridpoint,
+};
+use crate::simulation::{City, PlayerAction, Position};
+use crate::state::{Config, Rail, State};
+
+use bevy::prelude::*;
+use serde_json::{Value, json};
+
+pub const GEOJSON_FILE: &str = "terrain3d.geojson";
+
+/*
+
+Rail network as a GeoJSON FeatureCollection, positions in degrees of longitude and latitude with
+the same projection as `HeightmapConfig::equirectangular`:
+
+cities: a Point per city, with its grid point and elevation
+rails:  a LineString per rail, with its usage, length, mean elevation, whether it crosses water
+        and whether it is a tunnel or a bridge
+
+*/
+
+/// Longitude and latitude in degrees of the direction of `pos` from the centre.
+pub fn lon_lat(pos: Vec3) -> [f64; 2] {
+    let dir = pos.as_dvec3().normalize();
+    let longitude = dir.x.atan2(dir.z);
+    let latitude = dir.y.clamp(-1.0, 1.0).asin();
+    [longitude.to_degrees(), latitude.to_degrees()]
+}
+
+/// Unit vector pointing at `longitude`, `latitude` in degrees, the inverse of `lon_lat`.
+pub fn direction(longitude: f64, latitude: f64) -> Vec3 {
+    let (longitude, latitude) = (longitude.to_radians(), latitude.to_radians());
+    Vec3::new(
+        (latitude.cos() * longitude.sin()) as f32,
+        latitude.sin() as f32,
+        (latitude.cos() * longitude.cos()) as f32,
+    )
+}
+
+/// Writes the cities and rails of `world` as GeoJSON.
+pub fn export_network(path: &Path, world: &mut World) -> io::Result<()> {
+    let mut cities: Vec<GridPoint> = world
+        .query_filtered::<&Position, With<City>>()
+        .iter(world)
+        .map(|position| position.gridpoint)
+        .collect();
+    cities.sort();
+    let state = world.resource::<State>();
+    let rails: Vec<(Rail, usize)> = state
+        .rails
+        .rails
+        .iter()
+        .map(|(rail, info)| (*rail, info.counter.load(Ordering::Relaxed)))
+        .collect();
+    let globe_points = state
+        .globe_points
+        .read()
+        .map_err(|_| io::Error::other("could not lock the globe points"))?;
+    let geojson = network_geojson(&globe_points, &state.config, &cities, &rails);
+    let text = serde_json::to_string_pretty(&geojson).map_err(io::Error::other)?;
+    std::fs::write(path, text)
+}
+
+/// FeatureCollection of the `cities` and the `rails` with how many trains passed them.
+pub fn network_geojson(
+    globe_points: &GlobePoints,
+    config: &Config,
+    cities: &[GridPoint],
+    rails: &[(Rail, usize)],
+) -> Value {
+    let points = &globe_points.points;
+    let mut features = Vec::new();
+    for city in cities {
+        let Some(point) = points.get(city) else {
+            continue;
+        };
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "Point", "coordinates": lon_lat(point.pos)},
+            "properties": {
+                "kind": "city",
+                "gridpoint": city,
+                "elevation": point.pos.length() - config.sea_level,
+            },
+        }));
+    }
+
+    // The rails are kept in a hash map, sort them for a stable file.
+    let mut rails = rails.to_vec();
+    rails.sort_by_key(|(rail, _)| (rail.from, rail.to));
+    for (rail, usage) in rails {
+        let (Some(from), Some(to)) = (points.index(&rail.from), points.index(&rail.to)) else {
+            continue;
+        };
+        let (p, q) = (points[from], points[to]);
+        features.push(json!({
+            "type": "Feature",
+            "geometry": {"type": "LineString", "coordinates": [lon_lat(p.pos), lon_lat(q.pos)]},
+            "properties": {
+                "kind": "rail",
+                "from": rail.from,
+                "to": rail.to,
+                "usage": usage,
+                "length": p.pos.distance(q.pos),
+                "elevation": (p.pos.length() + q.pos.length()) / 2.0 - config.sea_level,
+                "water": p.water || q.water,
+                "river": crosses_river(points, from, to),
+                "structure": rail.kind.name(),
+            },
+        }));
+    }
+
+    json!({"type": "FeatureCollection", "features": features})
+}
+
+/// Cities and rail paths read from GeoJSON, snapped to the grid of the globe.
+#[derive(Debug, Default, PartialEq)]
+pub struct Network {
+    pub cities: Vec<GridPoint>,
+    pub paths: Vec<Vec<GridPoint>>, // each a path of neighbouring grid points
+}
+
+impl Network {
+    /// Player actions that place the cities and lay the rails.
+    pub fn actions(self) -> Vec<PlayerAction> {
+        let cities = self.cities.into_iter().map(PlayerAction::PlaceCity);
+        let paths = self.paths.into_iter().map(PlayerAction::Build);
+        cities.chain(paths).collect()
+    }
+}
+
+/// Cities and rail lines read from GeoJSON, as directions from the centre of the globe.
+#[derive(Debug, Default)]
+pub struct Features {
+    pub cities: Vec<Vec3>,
+    pub lines: Vec<(usize, Vec<Vec3>)>, // index of the feature and its positions
+}
+
+impl Features {
+    /// Moves every city to the closest grid point of a globe of `grid_size`, and the lines onto
+    /// the grid with `snap`, see `snap_line`.
+    pub fn to_network(
+        &self,
+        grid_size: u32,
+        mut snap: impl FnMut(&[Vec3]) -> Result<Vec<GridPoint>, String>,
+    ) -> Result<Network, String> {
+        let cities = self
+            .cities
+            .iter()
+            .map(|&dir| nearest_gridpoint(dir, grid_size))
+            .collect();
+        let mut paths = Vec::new();
+        for (i, line) in &self.lines {
+            let path = snap(line).map_err(|err| format!("feature {i}: {err}"))?;
+            if path.len() > 1 {
+                paths.push(path);
+            }
+        }
+        Ok(Network { cities, paths })
+    }
+}
+
+pub fn load_network(
+    path: &Path,
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> Result<Network, String> {
+    if globe_points.points.is_empty() {
+        return Err("the globe is not ready yet".to_string());
+    }
+    let grid_size = globe_points.points.grid_size();
+    read_features(path)?.to_network(grid_size, |line| snap_line(line, globe_points, model))
+}
+
+pub fn read_features(path: &Path) -> Result<Features, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    let geojson: Value = serde_json::from_str(&text).map_err(|err| err.to_string())?;
+    parse_features(&geojson)
+}
+
+/// Reads Point and MultiPoint features as cities, and LineString and MultiLineString features as
+/// rail lines. Other geometries are ignored.
+pub fn parse_features(geojson: &Value) -> Result<Features, String> {
+    let features = match geojson["type"].as_str() {
+        Some("FeatureCollection") => geojson["features"]
+            .as_array()
+            .ok_or("a FeatureCollection needs a `features` array")?
+            .iter()
+            .collect(),
+        Some("Feature") => vec![geojson],
+        _ => return Err("expected a FeatureCollection or a Feature".to_string()),
+    };
+
+    let mut parsed = Features::default();
+    for (i, feature) in features.into_iter().enumerate() {
+        let geometry = &feature["geometry"];
+        let coordinates = &geometry["coordinates"];
+        let context = |err: String| format!("feature {i}: {err}");
+        match geometry["type"].as_str() {
+            Some("Point") => parsed.cities.push(position(coordinates).map_err(context)?),
+            Some("MultiPoint") => parsed
+                .cities
+                .extend(positions(coordinates).map_err(context)?),
+            Some("LineString") => parsed
+                .lines
+                .push((i, positions(coordinates).map_err(context)?)),
+            Some("MultiLineString") => {
+                for line in coordinates.as_array().into_iter().flatten() {
+                    parsed.lines.push((i, positions(line).map_err(context)?));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(parsed)
+}
+
+// Direction of a `[longitude, latitude]` or `[longitude, latitude, altitude]` position.
+fn position(value: &Value) -> Result<Vec3, String> {
+    match value.as_array().map(Vec::as_slice) {
+        Some([longitude, latitude, ..]) => match (longitude.as_f64(), latitude.as_f64()) {
+            (Some(longitude), Some(latitude)) => Ok(direction(longitude, latitude)),
+            _ => Err(format!("invalid position {value}")),
+        },
+        _ => Err(format!("invalid position {value}")),
+    }
+}
+
+fn positions(value: &Value) -> Result<Vec<Vec3>, String> {
+    value
+        .as_array()
+        .ok_or_else(|| format!("invalid positions {value}"))?
+        .iter()
+        .map(position)
+        .collect()
+}
+
+/// Moves every position of `line` to the closest grid point, and joins consecutive ones that are
+/// not neighbours by the cheapest path between them under `model`.
+pub fn snap_line(
+    line: &[Vec3],
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> Result<Vec<GridPoint>, String> {
+    let points = &globe_points.points;
+    let mut path: Vec<GridPoint> = Vec::new();
+    for &dir in line {
+        let gridpoint = nearest_gridpoint(dir, points.grid_size());
+        let Some(&last) = path.last() else {
+            path.push(gridpoint);
+            continue;
+        };
+        if last == gridpoint {
+            continue;
+        }
+        let (Some(from), Some(to)) = (points.index(&last), points.index(&gridpoint)) else {
+            return Err(format!("no grid point at {gridpoint:?}"));
+        };
+        if globe_points.edges(from).iter().any(|edge| edge.to == to) {
+            path.push(gridpoint);
+        } else {
+            let (detour, _) = bidirectional_astar(last, gridpoint, globe_points, model);
+            if detour.is_empty() {
+                return Err(format!("no path from {last:?} to {gridpoint:?}"));
+            }
+            path.extend_from_slice(&detour[1..]);
+        }
+    }
+    Ok(path)
+}
@@ -0,0 +1,228 @@
+use crate::meshes_materials::globe_mesh;
+
+use bevy::prelude::*;
+use bevy::render::mesh::VertexAttributeValues;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LodConfig {
+    pub chunk_size: u32,   // quads along each side of a chunk
+    pub lod_distance: f32, // chunks closer to the camera than this many radii are split
+    pub skirt_depth: f32,  // how far the skirts hanging from the chunk borders reach down
+}
+
+impl Default for LodConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 32,
+            lod_distance: 3.0,
+            skirt_depth: 0.05,
+        }
+    }
+}
+
+/*
+
+Every face of the globe is the root of a quadtree, a chunk at `depth` covers 1 / 2^depth of the
+face along each side. Every chunk has at most `chunk_size` quads along each side, taking every
+grid point only at the deepest level, where a chunk has at most `chunk_size` grid cells.
+
+     face
++-----+-----+
+|     |  |  |    chunks near the camera are split further
+|     +--+--+
+|     |  |  |
++-----+-----+
+|     |     |
+|     |     |
++-----+-----+
+
+Neighbouring chunks of different depths don't share all border vertices. Skirts, strips hanging
+down from the borders, fill the cracks between them.
+
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ChunkKey {
+    pub face: u32,
+    pub depth: u32,
+    pub x: u32, // position of the chunk along i, between 0 and 2^depth
+    pub y: u32, // along j
+}
+
+impl ChunkKey {
+    pub fn root(face: u32) -> Self {
+        Self {
+            face,
+            depth: 0,
+            x: 0,
+            y: 0,
+        }
+    }
+
+    pub fn children(self) -> [ChunkKey; 4] {
+        [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dy)| ChunkKey {
+            face: self.face,
+            depth: self.depth + 1,
+            x: 2 * self.x + dx,
+            y: 2 * self.y + dy,
+        })
+    }
+}
+
+/// The globe mesh of `make_globe`, drawn as chunks of varying resolution. Only rendering uses
+/// the chunks, the grid of `GlobePoints` used for path finding stays the same.
+pub struct GlobeChunks {
+    pub mesh: Mesh, // the full resolution mesh, one vertex per grid point
+    grid_size: u32,
+    config: LodConfig,
+}
+
+impl GlobeChunks {
+    pub fn new(mesh: Mesh, grid_size: u32, config: LodConfig) -> Self {
+        let m = grid_size as usize + 1;
+        assert_eq!(
+            mesh.count_vertices(),
+            6 * m * m,
+            "the globe mesh needs a vertex per grid point"
+        );
+        Self {
+            mesh,
+            grid_size,
+            config,
+        }
+    }
+
+    // Grid points covered by `key` along i and j, both ends included.
+    fn range(&self, key: ChunkKey) -> ([u32; 2], [u32; 2]) {
+        let cells = 1 << key.depth;
+        let bound = |k: u32| (k as u64 * self.grid_size as u64 / cells) as u32;
+        (
+            [bound(key.x), bound(key.x + 1)],
+            [bound(key.y), bound(key.y + 1)],
+        )
+    }
+
+    fn is_leaf(&self, key: ChunkKey) -> bool {
+        let ([i0, i1], [j0, j1]) = self.range(key);
+        (i1 - i0).max(j1 - j0) <= self.config.chunk_size
+    }
+
+    fn position(&self, face: u32, i: u32, j: u32) -> Vec3 {
+        let m = self.grid_size + 1;
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            self.mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            unreachable!("the globe mesh has positions");
+        };
+        Vec3::from(positions[((face * m + i) * m + j) as usize])
+    }
+
+    /// Centre of the chunk and the distance from it to the farthest corner.
+    pub fn bounds(&self, key: ChunkKey) -> (Vec3, f32) {
+        let ([i0, i1], [j0, j1]) = self.range(key);
+        let centre = self.position(key.face, (i0 + i1) / 2, (j0 + j1) / 2);
+        let radius = [(i0, j0), (i1, j0), (i0, j1), (i1, j1)]
+            .map(|(i, j)| centre.distance(self.position(key.face, i, j)))
+            .into_iter()
+            .fold(0.0, f32::max);
+        (centre, radius)
+    }
+
+    /// Chunks to draw for a camera at `camera`, together covering the whole globe. Chunks are
+    /// split while the camera is within `lod_distance` times their radius.
+    pub fn select(&self, camera: Vec3) -> Vec<ChunkKey> {
+        let mut chunks = Vec::new();
+        let mut stack: Vec<ChunkKey> = (0..6).rev().map(ChunkKey::root).collect();
+        while let Some(key) = stack.pop() {
+            let (centre, radius) = self.bounds(key);
+            if !self.is_leaf(key) && camera.distance(centre) < self.config.lod_distance * radius {
+                stack.extend(key.children().into_iter().rev());
+            } else {
+                chunks.push(key);
+            }
+        }
+        chunks
+    }
+
+    /// Mesh of one chunk, with its skirts.
+    pub fn chunk_mesh(&self, key: ChunkKey) -> Mesh {
+        let (
+            Some(VertexAttributeValues::Float32x3(all_positions)),
+            Some(VertexAttributeValues::Float32x4(all_colors)),
+            Some(VertexAttributeValues::Float32x3(all_normals)),
+        ) = (
+            self.mesh.attribute(Mesh::ATTRIBUTE_POSITION),
+            self.mesh.attribute(Mesh::ATTRIBUTE_COLOR),
+            self.mesh.attribute(Mesh::ATTRIBUTE_NORMAL),
+        )
+        else {
+            unreachable!("the globe mesh has positions, colours and normals");
+        };
+        let m = self.grid_size + 1;
+        let ([i0, i1], [j0, j1]) = self.range(key);
+        let is = samples(i0, i1, self.config.chunk_size);
+        let js = samples(j0, j1, self.config.chunk_size);
+        let (ni, nj) = (is.len() as u32, js.len() as u32);
+
+        let mut positions = Vec::new();
+        let mut colors = Vec::new();
+        let mut normals = Vec::new();
+        for &i in &is {
+            for &j in &js {
+                let idx = ((key.face * m + i) * m + j) as usize;
+                positions.push(all_positions[idx]);
+                colors.push(all_colors[idx]);
+                normals.push(all_normals[idx]);
+            }
+        }
+
+        // Same winding as the full mesh.
+        let local = |i: u32, j: u32| i * nj + j;
+        let mut indices = Vec::new();
+        for i in 0..ni - 1 {
+            for j in 0..nj - 1 {
+                let a = local(i, j);
+                let b = local(i, j + 1);
+                let c = local(i + 1, j + 1);
+                let d = local(i + 1, j);
+                indices.extend([b, a, c, d, c, a]);
+            }
+        }
+
+        let borders: [Vec<u32>; 4] = [
+            (0..nj).map(|j| local(0, j)).collect(),
+            (0..nj).map(|j| local(ni - 1, j)).collect(),
+            (0..ni).map(|i| local(i, 0)).collect(),
+            (0..ni).map(|i| local(i, nj - 1)).collect(),
+        ];
+        for border in borders {
+            let start = positions.len() as u32;
+            for &v in &border {
+                let pos = Vec3::from(positions[v as usize]);
+                let skirt = pos - pos.normalize() * self.config.skirt_depth;
+                positions.push(skirt.to_array());
+                colors.push(colors[v as usize]);
+                normals.push(normals[v as usize]);
+            }
+            // Drawn from both sides, as the crack may be on either.
+            for (k, pair) in border.windows(2).enumerate() {
+                let (top0, top1) = (pair[0], pair[1]);
+                let (bottom0, bottom1) = (start + k as u32, start + k as u32 + 1);
+                indices.extend([top0, bottom0, top1, top1, bottom0, bottom1]);
+                indices.extend([top1, bottom0, top0, bottom1, bottom0, top1]);
+            }
+        }
+
+        globe_mesh(positions, colors, normals, indices)
+    }
+}
+
+// `n` or fewer evenly spread grid coordinates from `lo` to `hi`, both included.
+fn samples(lo: u32, hi: u32, n: u32) -> Vec<u32> {
+    let n = n.min(hi - lo).max(1);
+    (0..=n)
+        .map(|k| lo + ((hi - lo) as u64 * k as u64 / n as u64) as u32)
+        .collect()
+}
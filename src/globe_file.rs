@@ -0,0 +1,276 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::biome::Biome;
+use crate::dijkstra::{Edge, EdgeKind, GlobePoint, GlobePoints, GridPoints, MAX_GRID_SIZE};
+use crate::meshes_materials::{globe_mesh, make_globe};
+use crate::state::Config;
+
+use bevy::math::Vec3;
+use bevy::render::mesh::{Indices, Mesh, VertexAttributeValues};
+
+pub const GLOBE_FILE: &str = "globe.t3d";
+
+const MAGIC: &[u8; 4] = b"T3DG";
+// Bump whenever the layout below or the building of the graph changes, so that old files are
+// regenerated.
+const VERSION: u32 = 8;
+
+/*
+
+Layout, all little endian:
+
+magic "T3DG", version u32, config world hash u64
+grid_size u32, min_length_per_radian f32, max_step_length f32
+points:  count u32, then per point pos 3 x f32, water u8, penalty f32, river u8, lake u8,
+         flow f32, biome u8 (index into `Biome::ALL`)
+offsets: count u32, then u32 each
+edges:   count u32, then per edge to u32, built u8, kind u8 (index into `EdgeKind::ALL`),
+         gap f32
+mesh:    positions (count u32, 3 x f32 each), colors (count u32, 4 x f32 each),
+         normals (count u32, 3 x f32 each), indices (count u32, u32 each)
+
+*/
+
+/// Loads the globe from `GLOBE_FILE` if it was generated with the same world config,
+/// otherwise generates it with `make_globe` and saves it for the next launch.
+pub fn load_or_make_globe(config: &Config) -> Result<(GlobePoints, Mesh), String> {
+    let path = Path::new(GLOBE_FILE);
+    match load_globe(path, config.world_hash()) {
+        Ok(Some(globe)) => {
+            println!("Loaded globe from {GLOBE_FILE}.");
+            return Ok(globe);
+        }
+        Ok(None) => println!("{GLOBE_FILE} is out of date, regenerating globe."),
+        Err(err) => println!("Could not load {GLOBE_FILE}: {err}"),
+    }
+    let (globe_points, mesh) = make_globe(config)?;
+    match save_globe(path, config.world_hash(), &globe_points, &mesh) {
+        Ok(()) => println!("Saved globe to {GLOBE_FILE}."),
+        Err(err) => println!("Could not save {GLOBE_FILE}: {err}"),
+    }
+    Ok((globe_points, mesh))
+}
+
+pub fn save_globe(
+    path: &Path,
+    config_hash: u64,
+    globe_points: &GlobePoints,
+    mesh: &Mesh,
+) -> io::Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    w.write_all(MAGIC)?;
+    write_u32(&mut w, VERSION)?;
+    write_u64(&mut w, config_hash)?;
+
+    let points = &globe_points.points;
+    write_u32(&mut w, points.grid_size())?;
+    write_f32(&mut w, globe_points.min_length_per_radian)?;
+    write_f32(&mut w, globe_points.max_step_length)?;
+    write_u32(&mut w, points.len() as u32)?;
+    for idx in 0..points.len() as u32 {
+        let p = &points[idx];
+        write_f32s(&mut w, &p.pos.to_array())?;
+        w.write_all(&[p.water as u8])?;
+        write_f32(&mut w, p.penalty)?;
+        w.write_all(&[p.river as u8, p.lake as u8])?;
+        write_f32(&mut w, p.flow)?;
+        w.write_all(&[Biome::ALL.iter().position(|&b| b == p.biome).unwrap() as u8])?;
+    }
+
+    let (offsets, edges) = globe_points.csr();
+    write_vec(&mut w, offsets, |w, &offset| write_u32(w, offset))?;
+    write_vec(&mut w, edges, |w, edge| {
+        write_u32(w, edge.to)?;
+        w.write_all(&[edge.built as u8])?;
+        w.write_all(&[EdgeKind::ALL.iter().position(|&k| k == edge.kind).unwrap() as u8])?;
+        write_f32(w, edge.gap)
+    })?;
+
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        return Err(invalid("mesh has no positions"));
+    };
+    let Some(VertexAttributeValues::Float32x4(colors)) = mesh.attribute(Mesh::ATTRIBUTE_COLOR)
+    else {
+        return Err(invalid("mesh has no colors"));
+    };
+    let Some(VertexAttributeValues::Float32x3(normals)) = mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+    else {
+        return Err(invalid("mesh has no normals"));
+    };
+    let Some(Indices::U32(indices)) = mesh.indices() else {
+        return Err(invalid("mesh has no u32 indices"));
+    };
+    write_vec(&mut w, positions, |w, v| write_f32s(w, v))?;
+    write_vec(&mut w, colors, |w, c| write_f32s(w, c))?;
+    write_vec(&mut w, normals, |w, n| write_f32s(w, n))?;
+    write_vec(&mut w, indices, |w, &i| write_u32(w, i))?;
+    w.flush()
+}
+
+/// Returns `Ok(None)` if the file was written by another version or for another world config.
+pub fn load_globe(path: &Path, config_hash: u64) -> io::Result<Option<(GlobePoints, Mesh)>> {
+    let mut r = BufReader::new(File::open(path)?);
+    let mut magic = [0; 4];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(invalid("not a globe file"));
+    }
+    if read_u32(&mut r)? != VERSION {
+        return Ok(None);
+    }
+    if read_u64(&mut r)? != config_hash {
+        return Ok(None);
+    }
+
+    let grid_size = read_u32(&mut r)?;
+    if grid_size == 0 || grid_size > MAX_GRID_SIZE {
+        return Err(invalid("grid size out of range"));
+    }
+    let min_length_per_radian = read_f32(&mut r)?;
+    let max_step_length = read_f32(&mut r)?;
+    let mut points = GridPoints::new(grid_size);
+    if read_u32(&mut r)? as usize != points.len() {
+        return Err(invalid("point count does not match grid size"));
+    }
+    for idx in 0..points.len() as u32 {
+        let pos = Vec3::from_array(read_f32s(&mut r)?);
+        let water = read_u8(&mut r)? != 0;
+        let penalty = read_f32(&mut r)?;
+        let river = read_u8(&mut r)? != 0;
+        let lake = read_u8(&mut r)? != 0;
+        let flow = read_f32(&mut r)?;
+        let Some(&biome) = Biome::ALL.get(read_u8(&mut r)? as usize) else {
+            return Err(invalid("unknown biome"));
+        };
+        points[idx] = GlobePoint {
+            pos,
+            water,
+            penalty,
+            river,
+            lake,
+            flow,
+            biome,
+        };
+    }
+
+    let offsets = read_vec(&mut r, read_u32)?;
+    let edges = read_vec(&mut r, |r| {
+        let to = read_u32(r)?;
+        let built = read_u8(r)? != 0;
+        let Some(&kind) = EdgeKind::ALL.get(read_u8(r)? as usize) else {
+            return Err(invalid("unknown edge kind"));
+        };
+        let gap = read_f32(r)?;
+        Ok(Edge {
+            to,
+            built,
+            kind,
+            gap,
+        })
+    })?;
+    // A corrupt graph would panic on the first search, reject it so that it gets regenerated.
+    if offsets.len() != points.len() + 1
+        || offsets.first() != Some(&0)
+        || offsets.last().copied() != Some(edges.len() as u32)
+        || offsets.windows(2).any(|w| w[0] > w[1])
+        || edges.iter().any(|edge| edge.to as usize >= points.len())
+    {
+        return Err(invalid("inconsistent graph"));
+    }
+
+    let positions = read_vec(&mut r, read_f32s::<3>)?;
+    let colors = read_vec(&mut r, read_f32s::<4>)?;
+    let normals = read_vec(&mut r, read_f32s::<3>)?;
+    let indices = read_vec(&mut r, read_u32)?;
+    // Likewise a mesh index past the vertices, which would panic when drawn.
+    if colors.len() != positions.len()
+        || normals.len() != positions.len()
+        || indices.iter().any(|&i| i as usize >= positions.len())
+    {
+        return Err(invalid("inconsistent mesh"));
+    }
+
+    Ok(Some((
+        GlobePoints::from_csr(
+            points,
+            offsets,
+            edges,
+            min_length_per_radian,
+            max_step_length,
+        ),
+        globe_mesh(positions, colors, normals, indices),
+    )))
+}
+
+pub fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub fn write_u32(w: &mut impl Write, x: u32) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn write_u64(w: &mut impl Write, x: u64) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn write_f32(w: &mut impl Write, x: f32) -> io::Result<()> {
+    w.write_all(&x.to_le_bytes())
+}
+
+pub fn write_f32s(w: &mut impl Write, xs: &[f32]) -> io::Result<()> {
+    xs.iter().try_for_each(|&x| write_f32(w, x))
+}
+
+pub fn write_vec<W: Write, T>(
+    w: &mut W,
+    xs: &[T],
+    write: impl Fn(&mut W, &T) -> io::Result<()>,
+) -> io::Result<()> {
+    write_u32(w, xs.len() as u32)?;
+    xs.iter().try_for_each(|x| write(w, x))
+}
+
+pub fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+pub fn read_f32(r: &mut impl Read) -> io::Result<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+pub fn read_f32s<const N: usize>(r: &mut impl Read) -> io::Result<[f32; N]> {
+    let mut xs = [0.0; N];
+    for x in &mut xs {
+        *x = read_f32(r)?;
+    }
+    Ok(xs)
+}
+
+pub fn read_vec<R: Read, T>(
+    r: &mut R,
+    read: impl Fn(&mut R) -> io::Result<T>,
+) -> io::Result<Vec<T>> {
+    let count = read_u32(r)?;
+    (0..count).map(|_| read(r)).collect()
+}
@@ -0,0 +1,261 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use crate::globe_file::{invalid, write_u32};
+use crate::meshes_materials::{CITY_COLOR, RAIL_USAGE_LEVELS, rail_usage_color, rail_usage_level};
+use crate::simulation::{City, RailPiece};
+use crate::state::{Rail, State};
+
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, VertexAttributeValues};
+use serde_json::{Value, json};
+
+pub const GLTF_FILE: &str = "terrain3d.glb";
+
+/*
+
+Scene of the export, in the same coordinates as the app:
+
+globe:  one mesh with vertex colours
+cities: a node per city, all sharing one cube mesh
+rails:  a node per rail, sharing a unit cube mesh per usage level, scaled to the rail's length
+
+*/
+
+/// Writes the globe mesh and the cities and rails of `world` as a binary glTF 2.0 file.
+pub fn export_world(path: &Path, world: &mut World, globe: &Mesh) -> io::Result<()> {
+    let cities: Vec<Transform> = world
+        .query_filtered::<&Transform, With<City>>()
+        .iter(world)
+        .copied()
+        .collect();
+    let mut rails: Vec<(Rail, Transform)> = world
+        .query::<(&RailPiece, &Transform)>()
+        .iter(world)
+        .map(|(piece, transform)| (piece.0, *transform))
+        .collect();
+    // Queries run in archetype order, sort for a stable file.
+    rails.sort_by_key(|(rail, _)| (rail.from, rail.to));
+    let state = world.resource::<State>();
+    let rails: Vec<(Rail, Transform, usize)> = rails
+        .into_iter()
+        .map(|(rail, transform)| {
+            let usage = state
+                .rails
+                .rails
+                .get(&rail)
+                .map_or(0, |info| info.counter.load(Ordering::Relaxed));
+            (rail, transform, usage)
+        })
+        .collect();
+    export_glb(path, globe, &rails, &cities)
+}
+
+/// Writes the globe, the rails with how many trains passed them, and the cities as a binary
+/// glTF 2.0 file. Rails are coloured by their usage like in the app.
+pub fn export_glb(
+    path: &Path,
+    globe: &Mesh,
+    rails: &[(Rail, Transform, usize)],
+    cities: &[Transform],
+) -> io::Result<()> {
+    let mut gltf = Gltf::default();
+
+    let globe_material = gltf.material("globe", Color::WHITE);
+    let globe_mesh = gltf.mesh("globe", globe, globe_material)?;
+    let mut nodes = vec![gltf.node(json!({"name": "globe", "mesh": globe_mesh}))];
+
+    let city_material = gltf.material("city", CITY_COLOR);
+    let city_cube = Mesh::from(Cuboid {
+        half_size: Vec3::splat(0.1),
+    });
+    let city_mesh = gltf.mesh("city", &city_cube, city_material)?;
+    for (i, transform) in cities.iter().enumerate() {
+        nodes.push(gltf.node(trs(
+            json!({"name": format!("city {i}"), "mesh": city_mesh}),
+            transform,
+        )));
+    }
+
+    let max_usage = rails.iter().map(|&(_, _, usage)| usage).max().unwrap_or(0);
+    let rail_cube = Mesh::from(Cuboid::default());
+    let mut rail_meshes = [None; RAIL_USAGE_LEVELS];
+    for (rail, transform, usage) in rails {
+        let level = rail_usage_level(*usage, max_usage);
+        let mesh = match rail_meshes[level] {
+            Some(mesh) => mesh,
+            None => {
+                let name = format!("rail usage {level}");
+                let material = gltf.material(&name, rail_usage_color(level));
+                let mesh = gltf.mesh(&name, &rail_cube, material)?;
+                rail_meshes[level] = Some(mesh);
+                mesh
+            }
+        };
+        let name = format!("rail {:?} {:?}", rail.from, rail.to);
+        nodes.push(gltf.node(trs(json!({"name": name, "mesh": mesh}), transform)));
+    }
+
+    gltf.write(path, nodes)
+}
+
+fn trs(mut node: Value, transform: &Transform) -> Value {
+    node["translation"] = json!(transform.translation.to_array());
+    node["rotation"] = json!(transform.rotation.to_array());
+    node["scale"] = json!(transform.scale.to_array());
+    node
+}
+
+// Accumulates the JSON arrays of the glTF document and the single binary buffer.
+#[derive(Default)]
+struct Gltf {
+    buffer: Vec<u8>,
+    buffer_views: Vec<Value>,
+    accessors: Vec<Value>,
+    materials: Vec<Value>,
+    meshes: Vec<Value>,
+    nodes: Vec<Value>,
+}
+
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+impl Gltf {
+    fn material(&mut self, name: &str, color: Color) -> usize {
+        self.materials.push(json!({
+            "name": name,
+            "pbrMetallicRoughness": {
+                "baseColorFactor": color.to_linear().to_f32_array(),
+                "metallicFactor": 0.0,
+                "roughnessFactor": 1.0,
+            },
+        }));
+        self.materials.len() - 1
+    }
+
+    fn mesh(&mut self, name: &str, mesh: &Mesh, material: usize) -> io::Result<usize> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return Err(invalid("mesh has no positions"));
+        };
+        let (min, max) = positions.iter().fold(
+            ([f32::INFINITY; 3], [f32::NEG_INFINITY; 3]),
+            |(min, max), p| {
+                (
+                    std::array::from_fn(|a| min[a].min(p[a])),
+                    std::array::from_fn(|a| max[a].max(p[a])),
+                )
+            },
+        );
+        let mut attributes = json!({});
+        let position = self.accessor(positions, "VEC3", FLOAT, ARRAY_BUFFER);
+        self.accessors[position]["min"] = json!(min);
+        self.accessors[position]["max"] = json!(max);
+        attributes["POSITION"] = json!(position);
+        if let Some(VertexAttributeValues::Float32x3(normals)) =
+            mesh.attribute(Mesh::ATTRIBUTE_NORMAL)
+        {
+            attributes["NORMAL"] = json!(self.accessor(normals, "VEC3", FLOAT, ARRAY_BUFFER));
+        }
+        if let Some(VertexAttributeValues::Float32x4(colors)) =
+            mesh.attribute(Mesh::ATTRIBUTE_COLOR)
+        {
+            attributes["COLOR_0"] = json!(self.accessor(colors, "VEC4", FLOAT, ARRAY_BUFFER));
+        }
+        let indices: Vec<[u32; 1]> = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices.iter().map(|&i| [i]).collect(),
+            Some(Indices::U16(indices)) => indices.iter().map(|&i| [i as u32]).collect(),
+            None => return Err(invalid("mesh has no indices")),
+        };
+        let indices = self.accessor(&indices, "SCALAR", UNSIGNED_INT, ELEMENT_ARRAY_BUFFER);
+        self.meshes.push(json!({
+            "name": name,
+            "primitives": [{"attributes": attributes, "indices": indices, "material": material}],
+        }));
+        Ok(self.meshes.len() - 1)
+    }
+
+    // Appends the little endian `values` to the buffer, in a view of their own.
+    fn accessor(
+        &mut self,
+        values: &[impl Bytes],
+        kind: &str,
+        component_type: u32,
+        target: u32,
+    ) -> usize {
+        let offset = self.buffer.len();
+        for value in values {
+            value.append_to(&mut self.buffer);
+        }
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": offset,
+            "byteLength": self.buffer.len() - offset,
+            "target": target,
+        }));
+        self.accessors.push(json!({
+            "bufferView": self.buffer_views.len() - 1,
+            "componentType": component_type,
+            "count": values.len(),
+            "type": kind,
+        }));
+        self.accessors.len() - 1
+    }
+
+    fn node(&mut self, node: Value) -> usize {
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    fn write(self, path: &Path, roots: Vec<usize>) -> io::Result<()> {
+        let document = json!({
+            "asset": {"version": "2.0", "generator": "terrain3d"},
+            "scene": 0,
+            "scenes": [{"nodes": roots}],
+            "nodes": self.nodes,
+            "meshes": self.meshes,
+            "materials": self.materials,
+            "accessors": self.accessors,
+            "bufferViews": self.buffer_views,
+            "buffers": [{"byteLength": self.buffer.len()}],
+        });
+        let mut json = serde_json::to_vec(&document).map_err(io::Error::other)?;
+        // Chunks are padded to four bytes, JSON with spaces and binary with zeros.
+        json.resize(json.len().next_multiple_of(4), b' ');
+        let mut buffer = self.buffer;
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(b"glTF")?;
+        write_u32(&mut w, 2)?;
+        write_u32(&mut w, (12 + 8 + json.len() + 8 + buffer.len()) as u32)?;
+        write_u32(&mut w, json.len() as u32)?;
+        w.write_all(b"JSON")?;
+        w.write_all(&json)?;
+        write_u32(&mut w, buffer.len() as u32)?;
+        w.write_all(b"BIN\0")?;
+        w.write_all(&buffer)?;
+        w.flush()
+    }
+}
+
+trait Bytes {
+    fn append_to(&self, buffer: &mut Vec<u8>);
+}
+
+impl<const N: usize> Bytes for [f32; N] {
+    fn append_to(&self, buffer: &mut Vec<u8>) {
+        self.iter().for_each(|x| buffer.extend(x.to_le_bytes()));
+    }
+}
+
+impl<const N: usize> Bytes for [u32; N] {
+    fn append_to(&self, buffer: &mut Vec<u8>) {
+        self.iter().for_each(|x| buffer.extend(x.to_le_bytes()));
+    }
+}
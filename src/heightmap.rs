@@ -0,0 +1,135 @@
+use std::f32::consts::PI;
+use std::path::Path;
+
+use crate::noise::NoiseSource;
+
+use bevy::math::Vec3;
+use image::{ImageBuffer, Luma};
+use serde::{Deserialize, Serialize};
+
+/// Terrain read from grayscale images instead of generated from noise. Exactly one of
+/// `equirectangular` and `faces` is set.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeightmapConfig {
+    // Longitude left to right, latitude from the north pole at the top.
+    pub equirectangular: Option<String>,
+    // Cube map faces in the order +x, -x, +y, -y, +z, -z, oriented as OpenGL cube maps.
+    pub faces: Option<[String; 6]>,
+    pub min_height: f32, // height of black pixels above the globe radius
+    pub max_height: f32, // height of white pixels
+}
+
+impl Default for HeightmapConfig {
+    fn default() -> Self {
+        Self {
+            equirectangular: None,
+            faces: None,
+            min_height: -0.5,
+            max_height: 0.75,
+        }
+    }
+}
+
+impl HeightmapConfig {
+    pub fn paths(&self) -> Vec<&str> {
+        let equirectangular = self.equirectangular.iter().map(String::as_str);
+        let faces = self.faces.iter().flatten().map(String::as_str);
+        equirectangular.chain(faces).collect()
+    }
+}
+
+type Gray = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+pub struct Heightmap {
+    config: HeightmapConfig,
+    images: Vec<Gray>,
+}
+
+impl Heightmap {
+    /// Reads the images, 8 or 16 bit, grayscale or colour, of which only the luma is used.
+    pub fn load(config: &HeightmapConfig) -> Result<Self, String> {
+        let images = config
+            .paths()
+            .into_iter()
+            .map(|path| {
+                image::open(Path::new(path))
+                    .map(|image| image.into_luma16())
+                    .map_err(|err| format!("could not read heightmap {path}: {err}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(config, images))
+    }
+
+    pub fn new(config: &HeightmapConfig, images: Vec<Gray>) -> Self {
+        Self {
+            config: config.clone(),
+            images,
+        }
+    }
+
+    /// Brightness of the images in direction `dir` from the centre, between 0 and 1.
+    pub fn sample(&self, dir: Vec3) -> f32 {
+        if self.config.equirectangular.is_some() {
+            let image = &self.images[0];
+            let longitude = dir.x.atan2(dir.z);
+            let latitude = (dir.y / dir.length()).clamp(-1.0, 1.0).asin();
+            let x = (longitude / (2.0 * PI) + 0.5) * image.width() as f32;
+            let y = (0.5 - latitude / PI) * image.height() as f32;
+            bilinear(image, x, y, true)
+        } else {
+            // The face is picked by the largest coordinate, the others give the position on it.
+            let abs = dir.abs();
+            let (face, s, t, major) = if abs.x >= abs.y && abs.x >= abs.z {
+                if dir.x > 0.0 {
+                    (0, -dir.z, -dir.y, abs.x)
+                } else {
+                    (1, dir.z, -dir.y, abs.x)
+                }
+            } else if abs.y >= abs.z {
+                if dir.y > 0.0 {
+                    (2, dir.x, dir.z, abs.y)
+                } else {
+                    (3, dir.x, -dir.z, abs.y)
+                }
+            } else if dir.z > 0.0 {
+                (4, dir.x, -dir.y, abs.z)
+            } else {
+                (5, -dir.x, -dir.y, abs.z)
+            };
+            let image = &self.images[face];
+            let x = (s / major + 1.0) / 2.0 * image.width() as f32;
+            let y = (t / major + 1.0) / 2.0 * image.height() as f32;
+            bilinear(image, x, y, false)
+        }
+    }
+}
+
+impl NoiseSource for Heightmap {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        let (min, max) = (self.config.min_height, self.config.max_height);
+        min + (max - min) * self.sample(Vec3::new(x, y, z))
+    }
+}
+
+// Interpolates between the centres of the four nearest pixels of `x`, `y` in pixel units,
+// wrapping around horizontally or clamping at the border.
+fn bilinear(image: &Gray, x: f32, y: f32, wrap_x: bool) -> f32 {
+    let (width, height) = (image.width() as i64, image.height() as i64);
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |px: i64, py: i64| {
+        let px = if wrap_x {
+            px.rem_euclid(width)
+        } else {
+            px.clamp(0, width - 1)
+        };
+        let py = py.clamp(0, height - 1);
+        image.get_pixel(px as u32, py as u32)[0] as f32 / u16::MAX as f32
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1, y0) * fx;
+    let bottom = pixel(x0, y0 + 1) * (1.0 - fx) + pixel(x0 + 1, y0 + 1) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
@@ -0,0 +1,145 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::dijkstra::{NodeIndex, shared_points};
+
+use ordered_float::OrderedFloat;
+
+/// Where the water goes on the height field, for every grid point, indexed like `GridPoints`.
+pub struct Drainage {
+    // Height of the water surface once every depression has been filled into a lake. Equal to
+    // the ground height everywhere else.
+    pub level: Vec<f32>,
+    // Fraction of the grid points whose rain drains through each point.
+    pub flow: Vec<f32>,
+}
+
+impl Drainage {
+    pub fn is_lake(&self, heights: &[f32], idx: usize) -> bool {
+        self.level[idx] > heights[idx]
+    }
+}
+
+// Marks the sea, where the water stops flowing.
+const NO_RECEIVER: NodeIndex = NodeIndex::MAX;
+
+/// Computes the drainage of `heights`, the distances of the grid points from the centre.
+/// Points at or below `sea_level` drain into the sea. Water is flooded inwards from the sea
+/// along the lowest path, which fills depressions up to the height of their outlet and gives
+/// every land point the neighbour it drains to. Rain falls on every land point and is
+/// accumulated downstream, across the faces.
+pub fn drain(heights: &[f32], grid_size: u32, sea_level: f32) -> Drainage {
+    let shared = shared_points(grid_size);
+    let neighbours = neighbours(&shared, grid_size);
+    let num_points = heights.len();
+
+    let mut level = heights.to_vec();
+    let mut receiver = vec![NO_RECEIVER; num_points];
+    let mut visited = vec![false; num_points];
+    let mut order = Vec::with_capacity(num_points);
+    let mut queue = BinaryHeap::new();
+    for idx in 0..num_points {
+        if shared[idx] as usize == idx && heights[idx] <= sea_level {
+            visited[idx] = true;
+            queue.push(Reverse((OrderedFloat(heights[idx]), idx as NodeIndex)));
+        }
+    }
+    if queue.is_empty() {
+        // No sea, everything drains to the lowest point.
+        let lowest = (0..num_points)
+            .filter(|&idx| shared[idx] as usize == idx)
+            .min_by(|&a, &b| heights[a].total_cmp(&heights[b]))
+            .unwrap();
+        visited[lowest] = true;
+        queue.push(Reverse((
+            OrderedFloat(heights[lowest]),
+            lowest as NodeIndex,
+        )));
+    }
+    while let Some(Reverse((OrderedFloat(current_level), current))) = queue.pop() {
+        order.push(current);
+        for &next in neighbours.of(current) {
+            let next_idx = next as usize;
+            if visited[next_idx] {
+                continue;
+            }
+            visited[next_idx] = true;
+            level[next_idx] = heights[next_idx].max(current_level);
+            receiver[next_idx] = current;
+            queue.push(Reverse((OrderedFloat(level[next_idx]), next)));
+        }
+    }
+
+    // Upstream points come later in the flooding order.
+    let num_shared = order.len() as f32;
+    let mut flow = vec![0.0; num_points];
+    for &idx in order.iter().rev() {
+        let idx = idx as usize;
+        if heights[idx] > sea_level {
+            flow[idx] += 1.0 / num_shared;
+        }
+        if receiver[idx] != NO_RECEIVER {
+            flow[receiver[idx] as usize] += flow[idx];
+        }
+    }
+
+    for idx in 0..num_points {
+        let first = shared[idx] as usize;
+        level[idx] = level[first];
+        flow[idx] = flow[first];
+    }
+    Drainage { level, flow }
+}
+
+// The up to eight neighbours of every shared grid point, on all the faces it is on, in CSR form.
+struct Neighbours {
+    offsets: Vec<u32>,
+    targets: Vec<NodeIndex>,
+}
+
+impl Neighbours {
+    fn of(&self, idx: NodeIndex) -> &[NodeIndex] {
+        let idx = idx as usize;
+        &self.targets[self.offsets[idx] as usize..self.offsets[idx + 1] as usize]
+    }
+}
+
+fn neighbours(shared: &[NodeIndex], grid_size: u32) -> Neighbours {
+    let m = grid_size as i32 + 1;
+    let mut lists = vec![Vec::new(); shared.len()];
+    for face in 0..6 {
+        for row in 0..m {
+            for col in 0..m {
+                let idx = shared[((face * m + row) * m + col) as usize];
+                for (dr, dc) in [
+                    (-1, -1),
+                    (-1, 0),
+                    (-1, 1),
+                    (0, -1),
+                    (0, 1),
+                    (1, -1),
+                    (1, 0),
+                    (1, 1),
+                ] {
+                    let (r, c) = (row + dr, col + dc);
+                    if r < 0 || c < 0 || r >= m || c >= m {
+                        continue;
+                    }
+                    let next = shared[((face * m + r) * m + c) as usize];
+                    let list: &mut Vec<NodeIndex> = &mut lists[idx as usize];
+                    if next != idx && !list.contains(&next) {
+                        list.push(next);
+                    }
+                }
+            }
+        }
+    }
+    let mut offsets = Vec::with_capacity(shared.len() + 1);
+    let mut targets = Vec::new();
+    offsets.push(0);
+    for list in lists {
+        targets.extend(list);
+        offsets.push(targets.len() as u32);
+    }
+    Neighbours { offsets, targets }
+}
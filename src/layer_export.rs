@@ -0,0 +1,155 @@
+use std::f32::consts::PI;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dijkstra::{GlobePoints, NodeIndex, nearest_gridpoint, shared_points};
+use crate::state::{Config, Rail};
+
+use bevy::math::Vec3;
+use image::{ImageBuffer, Luma};
+
+type Gray = ImageBuffer<Luma<u16>, Vec<u16>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Height,    // height of the ground or the water surface, from the lowest to the highest
+    Water,     // white for sea and lakes
+    Penalty,   // path penalty of the points, up to the highest
+    RailUsage, // trains that passed the rails at each point, up to the busiest point
+}
+
+impl Layer {
+    pub const ALL: [Layer; 4] = [
+        Layer::Height,
+        Layer::Water,
+        Layer::Penalty,
+        Layer::RailUsage,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layer::Height => "height",
+            Layer::Water => "water",
+            Layer::Penalty => "penalty",
+            Layer::RailUsage => "rail_usage",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Projection {
+    Faces, // one image per face of the cube, with a pixel per grid point
+    Equirectangular { width: u32 },
+}
+
+/// Values of `layer` between 0 and 1 for every grid point, indexed like `GridPoints`.
+/// `rail_usage` is how many trains passed each rail, as in `RailInfo::counter`.
+pub fn layer_values(
+    layer: Layer,
+    globe_points: &GlobePoints,
+    config: &Config,
+    rail_usage: &[(Rail, usize)],
+) -> Vec<f32> {
+    let points = &globe_points.points;
+    let values: Vec<f32> = match layer {
+        Layer::Height => (0..points.len() as NodeIndex)
+            .map(|idx| points[idx].pos.length() - config.sea_level)
+            .collect(),
+        Layer::Water => (0..points.len() as NodeIndex)
+            .map(|idx| points[idx].water as u8 as f32)
+            .collect(),
+        Layer::Penalty => (0..points.len() as NodeIndex)
+            .map(|idx| points[idx].penalty)
+            .collect(),
+        Layer::RailUsage => {
+            // Summed on the shared copy of the points on the face edges.
+            let shared = shared_points(points.grid_size());
+            let mut usage = vec![0.0; points.len()];
+            for (rail, count) in rail_usage {
+                for end in [rail.from, rail.to] {
+                    if let Some(idx) = points.index(&end) {
+                        usage[shared[idx as usize] as usize] += *count as f32;
+                    }
+                }
+            }
+            (0..points.len())
+                .map(|idx| usage[shared[idx] as usize])
+                .collect()
+        }
+    };
+    let min = match layer {
+        Layer::Height => values.iter().copied().fold(f32::INFINITY, f32::min),
+        _ => 0.0,
+    };
+    let max = values.iter().copied().fold(min, f32::max);
+    let range = if max > min { max - min } else { 1.0 };
+    values.iter().map(|v| (v - min) / range).collect()
+}
+
+/// Image of one face, pixel (i, grid_size - j) showing grid point (face, i, j).
+pub fn face_image(values: &[f32], grid_size: u32, face: u32) -> Gray {
+    let m = grid_size + 1;
+    ImageBuffer::from_fn(m, m, |x, y| {
+        let (i, j) = (x, grid_size - y);
+        luma(values[((face * m + i) * m + j) as usize])
+    })
+}
+
+/// Image in equirectangular projection, `width` by `width / 2`, each pixel showing the closest
+/// grid point. Uses the same projection as `HeightmapConfig::equirectangular`.
+pub fn equirectangular_image(values: &[f32], grid_size: u32, width: u32) -> Gray {
+    let m = grid_size + 1;
+    let height = (width / 2).max(1);
+    ImageBuffer::from_fn(width, height, |x, y| {
+        let longitude = ((x as f32 + 0.5) / width as f32 - 0.5) * 2.0 * PI;
+        let latitude = (0.5 - (y as f32 + 0.5) / height as f32) * PI;
+        let dir = Vec3::new(
+            latitude.cos() * longitude.sin(),
+            latitude.sin(),
+            latitude.cos() * longitude.cos(),
+        );
+        let (face, i, j) = nearest_gridpoint(dir, grid_size);
+        luma(values[((face * m + i) * m + j) as usize])
+    })
+}
+
+fn luma(value: f32) -> Luma<u16> {
+    Luma([(value.clamp(0.0, 1.0) * u16::MAX as f32).round() as u16])
+}
+
+/// Writes every layer as 16 bit grayscale PNGs into `dir`, named like `height_face0.png` or
+/// `height_equirectangular.png`. Returns the written paths.
+pub fn export_layers(
+    dir: &Path,
+    globe_points: &GlobePoints,
+    config: &Config,
+    rail_usage: &[(Rail, usize)],
+    projection: Projection,
+) -> io::Result<Vec<PathBuf>> {
+    std::fs::create_dir_all(dir)?;
+    let grid_size = globe_points.points.grid_size();
+    let mut paths = Vec::new();
+    for layer in Layer::ALL {
+        let values = layer_values(layer, globe_points, config, rail_usage);
+        let images = match projection {
+            Projection::Faces => (0..6)
+                .map(|face| {
+                    let name = format!("{}_face{face}.png", layer.name());
+                    (name, face_image(&values, grid_size, face))
+                })
+                .collect(),
+            Projection::Equirectangular { width } => vec![(
+                format!("{}_equirectangular.png", layer.name()),
+                equirectangular_image(&values, grid_size, width),
+            )],
+        };
+        for (name, image) in images {
+            let path = dir.join(name);
+            image.save(&path).map_err(|err| {
+                io::Error::other(format!("could not write {}: {err}", path.display()))
+            })?;
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
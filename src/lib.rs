@@ -0,0 +1,26 @@
+pub mod alternatives;
+pub mod biome;
+pub mod config_file;
+pub mod cost_model;
+pub mod dijkstra;
+pub mod erosion;
+pub mod geojson_file;
+pub mod globe_chunks;
+pub mod globe_file;
+pub mod gltf_export;
+pub mod heightmap;
+pub mod hydrology;
+pub mod layer_export;
+pub mod meshes_materials;
+pub mod network_planner;
+pub mod noise;
+pub mod perlin;
+pub mod save_file;
+pub mod simulation;
+pub mod state;
+pub mod structures;
+pub mod train;
+pub mod ui;
+
+#[cfg(test)]
+mod test;
@@ -1,13 +1,3 @@
-mod dijkstra;
-mod meshes_materials;
-mod perlin;
-mod state;
-mod train;
-mod ui;
-
 fn main() {
-    ui::init();
+    terrain3d::ui::init();
 }
-
-#[cfg(test)]
-mod test;
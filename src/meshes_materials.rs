@@ -1,5 +1,8 @@
+use crate::biome::Climate;
 use crate::dijkstra::{GlobePoint, GlobePoints};
-use crate::perlin::Perlin;
+use crate::erosion::erode;
+use crate::hydrology::drain;
+use crate::noise::{NoiseSource, Terrain};
 use bevy::{asset::RenderAssetUsages, prelude::*, render::mesh::Mesh};
 
 #[derive(Resource)]
@@ -8,13 +11,20 @@ pub struct Materials {
     pub selected_city: Handle<StandardMaterial>,
     pub highlighted_city: Handle<StandardMaterial>,
     pub train: Handle<StandardMaterial>,
+    pub tunnel: Handle<StandardMaterial>, // portals at the ends of tunnels
+    pub pier: Handle<StandardMaterial>,
+    // Rails fade from white to red as their usage approaches the most used rail's.
+    pub rail_usage: Vec<Handle<StandardMaterial>>,
 }
 
+pub const RAIL_USAGE_LEVELS: usize = 16;
+pub const CITY_COLOR: Color = Color::srgb_u8(124, 144, 255);
+
 impl Materials {
     pub fn new(material_assets: &mut Assets<StandardMaterial>) -> Self {
         Self {
             city: material_assets.add(StandardMaterial {
-                base_color: Color::srgb_u8(124, 144, 255),
+                base_color: CITY_COLOR,
                 perceptual_roughness: 0.0,
                 metallic: 0.0,
                 ..default()
@@ -37,8 +47,45 @@ impl Materials {
                 metallic: 0.0,
                 ..default()
             }),
+            tunnel: material_assets.add(StandardMaterial {
+                base_color: Color::srgb_u8(60, 60, 60),
+                perceptual_roughness: 0.0,
+                metallic: 0.0,
+                ..default()
+            }),
+            pier: material_assets.add(StandardMaterial {
+                base_color: Color::srgb_u8(170, 170, 160),
+                perceptual_roughness: 0.0,
+                metallic: 0.0,
+                ..default()
+            }),
+            rail_usage: (0..RAIL_USAGE_LEVELS)
+                .map(|level| {
+                    material_assets.add(StandardMaterial {
+                        base_color: rail_usage_color(level),
+                        perceptual_roughness: 0.0,
+                        metallic: 0.0,
+                        ..default()
+                    })
+                })
+                .collect(),
         }
     }
+
+    pub fn rail(&self, count: usize, max_rail_usage: usize) -> Handle<StandardMaterial> {
+        self.rail_usage[rail_usage_level(count, max_rail_usage)].clone()
+    }
+}
+
+pub fn rail_usage_color(level: usize) -> Color {
+    let color = 1.0 - level as f32 / (RAIL_USAGE_LEVELS - 1) as f32;
+    Color::srgb(1.0, color, color)
+}
+
+// Index into `Materials::rail_usage`, 0 for unused rails and the last level for the most used.
+pub fn rail_usage_level(count: usize, max_rail_usage: usize) -> usize {
+    let ratio = count.min(max_rail_usage) as f32 / max_rail_usage.max(1) as f32;
+    (ratio * (RAIL_USAGE_LEVELS - 1) as f32).round() as usize
 }
 
 #[derive(Resource)]
@@ -63,21 +110,20 @@ impl Meshes {
     }
 }
 
-pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
+/// Fails if the terrain can't be built, e.g. from a heightmap that changed after validation.
+pub fn make_globe(config: &crate::state::Config) -> Result<(GlobePoints, Mesh), String> {
     let mut positions = Vec::new();
     let mut colors = Vec::new();
     let mut normals = Vec::new();
     let mut indices = Vec::new();
 
-    let mut globe_points = GlobePoints::default();
     let grid_size = config.grid_size;
+    let mut globe_points = GlobePoints::new(grid_size);
     let m = grid_size + 1;
     let sea_level = config.sea_level;
-    let snow = config.snow_level;
+    let climate = Climate::new(&config.biome_config, config.snow_level);
 
-    let perlin = Perlin {
-        config: config.perlin_config,
-    };
+    let terrain = Terrain::new(config)?;
 
     println!("Making globe");
 
@@ -97,42 +143,76 @@ pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
         }
     };
 
-    let surface = |u, v, face: u32| {
+    let direction = |face: u32, i: u32, j: u32| {
+        let u = i as f32 / grid_size as f32;
+        let v = j as f32 / grid_size as f32;
         let (nx, ny, nz) = sphere(u, v, face);
-        let nr = 5.0;
-        // let color = [u, v, (1 + face) as f32 / 8.0, 1.0];
-        let noise = perlin.noise(nx, ny, nz) * 1.0;
-        (
-            nr + noise,
-            [nx * (nr + noise), ny * (nr + noise), nz * (nr + noise)],
-        )
+        Vec3::new(nx, ny, nz)
     };
 
-    let normvec = |u, v, face| {
-        let (_, p) = surface(u, v, face);
-        let (_, q) = surface(u + 0.001, v, face);
-        let (_, r) = surface(u, v + 0.001, face);
-        let a = [p[0] - q[0], p[1] - q[1], p[2] - q[2]];
-        let b = [p[0] - r[0], p[1] - r[1], p[2] - r[2]];
-        let n = [
-            a[1] * b[2] - a[2] * b[1],
-            a[2] * b[0] - a[0] * b[2],
-            a[0] * b[1] - a[1] * b[0],
-        ];
-        let r = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
-        [n[0] / r, n[1] / r, n[2] / r]
+    // Distance of every grid point from the centre, indexed like `GridPoints`.
+    let nr = 5.0;
+    let mut directions = Vec::with_capacity((6 * m * m) as usize);
+    for face in 0..6 {
+        for i in 0..m {
+            for j in 0..m {
+                directions.push(direction(face, i, j));
+            }
+        }
+    }
+    let mut heights = vec![0.0; directions.len()];
+    terrain.noise_batch(&directions, &mut heights);
+    for height in &mut heights {
+        *height += nr;
+    }
+    let eroded = config.erosion_config.iterations > 0;
+    if eroded {
+        println!("Eroding");
+        erode(&mut heights, grid_size, &config.erosion_config);
+    }
+
+    println!("Draining");
+    let drainage = drain(&heights, grid_size, sea_level);
+
+    let surface = |face: u32, i: u32, j: u32| {
+        let idx = ((face * m + i) * m + j) as usize;
+        direction(face, i, j) * heights[idx]
+    };
+
+    // Without erosion the heights are the noise itself, so its normals can be sampled more finely
+    // than the grid.
+    let normvec = |face, i: u32, j: u32| {
+        if !eroded {
+            let u = i as f32 / grid_size as f32;
+            let v = j as f32 / grid_size as f32;
+            let noise_surface = |u, v| {
+                let (nx, ny, nz) = sphere(u, v, face);
+                Vec3::new(nx, ny, nz) * (nr + terrain.noise(nx, ny, nz))
+            };
+            let p = noise_surface(u, v);
+            let a = p - noise_surface(u + 0.001, v);
+            let b = p - noise_surface(u, v + 0.001);
+            return a.cross(b).normalize().to_array();
+        }
+        let du = surface(face, (i + 1).min(grid_size), j) - surface(face, i.saturating_sub(1), j);
+        let dv = surface(face, i, (j + 1).min(grid_size)) - surface(face, i, j.saturating_sub(1));
+        du.cross(dv).normalize().to_array()
     };
 
     for face in 0..6 {
         println!("Making face {face}");
         for i in 0..m {
             for j in 0..m {
-                let u = i as f32 / grid_size as f32;
-                let v = j as f32 / grid_size as f32;
-
-                let (noise, pos) = surface(u, v, face);
+                let idx = ((face * m + i) * m + j) as usize;
+                let noise = heights[idx];
+                let pos = surface(face, i, j).to_array();
 
                 let height = noise - sea_level;
+                let lake = drainage.is_lake(&heights, idx);
+                let water = height <= 0.0 || lake;
+                let river = !water && drainage.flow[idx] >= config.river_threshold;
+                let water_level = if lake { drainage.level[idx] } else { sea_level };
+                let biome = climate.biome(direction(face, i, j), height);
                 let color = if cfg!(debug_assertions) {
                     // for debugging, use different colors for each face
                     match face {
@@ -143,23 +223,18 @@ pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
                         4 => [1.0, 0.4, 0.4, 1.0], // pink
                         _ => [0.5, 0.5, 0.5, 1.0], // gray
                     }
-                } else if height > snow {
-                    [1.0, 1.0, 1.0, 1.0] // white for snow
+                } else if river {
+                    [0.1, 0.3, 0.8, 1.0] // blue for rivers
                 } else {
-                    let v = height / snow;
-                    [v / 2.5, (1.5 - v) / 3.0, v / 5.0, 1.0] // gradient color for land
+                    biome.color()
                 };
-                if height > 0.0 {
+                let normpos = [pos[0] / noise, pos[1] / noise, pos[2] / noise];
+                if !water {
                     positions.push(pos);
                     colors.push(color);
-                    normals.push(normvec(u, v, face));
+                    normals.push(normvec(face, i, j));
                 } else {
-                    let normpos = [pos[0] / noise, pos[1] / noise, pos[2] / noise];
-                    positions.push([
-                        normpos[0] * sea_level,
-                        normpos[1] * sea_level,
-                        normpos[2] * sea_level,
-                    ]);
+                    positions.push(normpos.map(|x| x * water_level));
                     let blueify = |c: [f32; 4], depth: f32| {
                         let depth_ratio = (depth / 0.02).clamp(0.0, 1.0).sqrt();
                         [
@@ -169,23 +244,25 @@ pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
                             c[3],
                         ]
                     };
-                    colors.push(blueify(color, sea_level - noise));
+                    colors.push(blueify(color, water_level - noise));
                     normals.push(normpos);
                 }
-                let render_pos =
-                    pos.map(|x| (sea_level + height.max(0.0)) / (sea_level + height) * x);
+                // Points on water are placed on its surface.
+                let render_pos = normpos.map(|x| x * noise.max(water_level));
                 globe_points.points.insert(
                     (face, i, j),
                     GlobePoint {
                         pos: Vec3::from(render_pos),
-                        water: height <= 0.0,
-                        penalty: if height <= 0.0 {
+                        water,
+                        penalty: if water {
                             config.water_penalty
-                        } else if height >= snow {
-                            config.snow_penalty
                         } else {
-                            1.0
+                            biome.penalty(&config.biome_config)
                         },
+                        river,
+                        lake,
+                        flow: drainage.flow[idx],
+                        biome,
                     },
                 );
             }
@@ -202,9 +279,19 @@ pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
     }
 
     println!("Building graph.");
-    globe_points.build_graph(grid_size, config.climbing_cost);
+    globe_points.build_graph(&config.structure_config);
 
     println!("Making mesh.");
+    let mesh = globe_mesh(positions, colors, normals, indices);
+    Ok((globe_points, mesh))
+}
+
+pub fn globe_mesh(
+    positions: Vec<[f32; 3]>,
+    colors: Vec<[f32; 4]>,
+    normals: Vec<[f32; 3]>,
+    indices: Vec<u32>,
+) -> Mesh {
     let mut mesh = Mesh::new(
         bevy::render::mesh::PrimitiveTopology::TriangleList,
         RenderAssetUsages::RENDER_WORLD | RenderAssetUsages::MAIN_WORLD,
@@ -213,5 +300,5 @@ pub fn make_globe(config: &crate::state::Config) -> (GlobePoints, Mesh) {
     mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
     mesh.insert_indices(bevy::render::mesh::Indices::U32(indices));
-    (globe_points, mesh)
+    mesh
 }
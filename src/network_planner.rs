@@ -0,0 +1,305 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, VecDeque};
+
+use crate::cost_model::CostModel;
+use crate::dijkstra::{GlobePoints, GridPoint, NodeIndex};
+use crate::state::Rail;
+
+use ordered_float::OrderedFloat;
+
+/*
+
+Batch planning of a rail network between all cities, instead of connecting pairs one at a time.
+
+1. Steiner tree, after Mehlhorn: a search from all cities at once splits the graph into the
+   regions closest to each city. Every edge between two regions links their cities through it,
+   and a minimum spanning tree of the cheapest links, each expanded into its two shortest paths,
+   connects all cities. It costs at most twice the optimal tree.
+
+2. Redundancy: while fewer than `connectivity` edge-disjoint paths lead from the first city to
+   another one, the cheapest new track across the minimum cut between them is added.
+
+*/
+
+// Edges of the planned network, each as (smaller, larger) node index.
+type Network = BTreeSet<(NodeIndex, NodeIndex)>;
+
+/// Rails of a network connecting all `cities`, priced by `model`, such that every two cities
+/// are joined by at least `connectivity` paths without a rail in common, as far as the graph
+/// allows. A `connectivity` of 1 gives a tree.
+pub fn plan_network(
+    cities: &[GridPoint],
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+    connectivity: u32,
+) -> Vec<Rail> {
+    let mut terminals: Vec<NodeIndex> = cities
+        .iter()
+        .filter_map(|city| globe_points.points.index(city))
+        .collect();
+    terminals.sort();
+    terminals.dedup();
+    if terminals.len() < 2 {
+        return Vec::new();
+    }
+
+    let mut network = steiner_tree(&terminals, globe_points, model);
+    if connectivity > 1 {
+        add_redundancy(&mut network, &terminals, globe_points, model, connectivity);
+    }
+
+    network
+        .into_iter()
+        .map(|(a, b)| {
+            let (from, to) = (
+                globe_points.points.gridpoint(a),
+                globe_points.points.gridpoint(b),
+            );
+            Rail {
+                from,
+                to,
+                kind: globe_points.edge_kind(&from, &to).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+fn edge_key(a: NodeIndex, b: NodeIndex) -> (NodeIndex, NodeIndex) {
+    (a.min(b), a.max(b))
+}
+
+// Shortest paths from the closest of several sources.
+struct Search {
+    dist: Vec<f32>,
+    prev: Vec<Option<NodeIndex>>,
+    origin: Vec<Option<NodeIndex>>, // the source each node was reached from
+}
+
+impl Search {
+    // Dijkstra from all `sources` at once, skipping the edges for which `skip` holds. Stops at
+    // the first node for which `stop` holds and returns it.
+    fn run(
+        sources: impl Iterator<Item = NodeIndex>,
+        globe_points: &GlobePoints,
+        model: &dyn CostModel,
+        skip: impl Fn(NodeIndex, NodeIndex) -> bool,
+        stop: impl Fn(NodeIndex) -> bool,
+    ) -> (Self, Option<NodeIndex>) {
+        let n = globe_points.points.len();
+        let mut search = Self {
+            dist: vec![f32::INFINITY; n],
+            prev: vec![None; n],
+            origin: vec![None; n],
+        };
+        let mut queue = BinaryHeap::new();
+        for source in sources {
+            search.dist[source as usize] = 0.0;
+            search.origin[source as usize] = Some(source);
+            queue.push(Reverse((OrderedFloat(0.0), source)));
+        }
+        let points = &globe_points.points;
+        while let Some(Reverse((OrderedFloat(dist), current))) = queue.pop() {
+            if dist > search.dist[current as usize] {
+                continue;
+            }
+            if stop(current) {
+                return (search, Some(current));
+            }
+            for edge in globe_points.edges(current) {
+                if skip(current, edge.to) {
+                    continue;
+                }
+                let new_dist = dist + model.cost(&points[current], &points[edge.to], edge);
+                if new_dist < search.dist[edge.to as usize] {
+                    search.dist[edge.to as usize] = new_dist;
+                    search.prev[edge.to as usize] = Some(current);
+                    search.origin[edge.to as usize] = search.origin[current as usize];
+                    queue.push(Reverse((OrderedFloat(new_dist), edge.to)));
+                }
+            }
+        }
+        (search, None)
+    }
+
+    // Adds the edges of the path from the source of `node` to `node` to `network`.
+    fn add_path(&self, mut node: NodeIndex, network: &mut Network) {
+        while let Some(prev) = self.prev[node as usize] {
+            network.insert(edge_key(prev, node));
+            node = prev;
+        }
+    }
+}
+
+fn steiner_tree(
+    terminals: &[NodeIndex],
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+) -> Network {
+    let (search, _) = Search::run(
+        terminals.iter().copied(),
+        globe_points,
+        model,
+        |_, _| false,
+        |_| false,
+    );
+
+    // The cheapest link between every two neighbouring regions.
+    let points = &globe_points.points;
+    let mut links: BTreeMap<(NodeIndex, NodeIndex), (OrderedFloat<f32>, NodeIndex, NodeIndex)> =
+        BTreeMap::new();
+    for from in 0..points.len() as NodeIndex {
+        let Some(a) = search.origin[from as usize] else {
+            continue;
+        };
+        for edge in globe_points.edges(from) {
+            let Some(b) = search.origin[edge.to as usize] else {
+                continue;
+            };
+            if a >= b {
+                continue; // every link is seen from both sides
+            }
+            let cost = search.dist[from as usize]
+                + model.cost(&points[from], &points[edge.to], edge)
+                + search.dist[edge.to as usize];
+            let link = (OrderedFloat(cost), from, edge.to);
+            links
+                .entry((a, b))
+                .and_modify(|best| *best = (*best).min(link))
+                .or_insert(link);
+        }
+    }
+
+    // Kruskal over the links.
+    let mut links: Vec<_> = links.into_iter().collect();
+    links.sort_by_key(|&(terminals, (cost, _, _))| (cost, terminals));
+    let mut components = UnionFind::new(terminals);
+    let mut network = Network::new();
+    for ((a, b), (_, from, to)) in links {
+        if components.union(a, b) {
+            search.add_path(from, &mut network);
+            network.insert(edge_key(from, to));
+            search.add_path(to, &mut network);
+        }
+    }
+    network
+}
+
+struct UnionFind {
+    parent: BTreeMap<NodeIndex, NodeIndex>,
+}
+
+impl UnionFind {
+    fn new(nodes: &[NodeIndex]) -> Self {
+        Self {
+            parent: nodes.iter().map(|&node| (node, node)).collect(),
+        }
+    }
+
+    fn find(&mut self, mut node: NodeIndex) -> NodeIndex {
+        while self.parent[&node] != node {
+            let grandparent = self.parent[&self.parent[&node]];
+            self.parent.insert(node, grandparent);
+            node = grandparent;
+        }
+        node
+    }
+
+    // Merges the sets of `a` and `b`, returns false if they already were one.
+    fn union(&mut self, a: NodeIndex, b: NodeIndex) -> bool {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent.insert(a, b);
+        a != b
+    }
+}
+
+fn add_redundancy(
+    network: &mut Network,
+    terminals: &[NodeIndex],
+    globe_points: &GlobePoints,
+    model: &dyn CostModel,
+    connectivity: u32,
+) {
+    let root = terminals[0];
+    for &terminal in &terminals[1..] {
+        loop {
+            let (flow, reachable, reaching) = max_flow(network, root, terminal, connectivity);
+            if flow >= connectivity {
+                break;
+            }
+            // A new path across the cut adds a disjoint path only if it ends at a node that can
+            // still reach `terminal`, not on a side branch that merely hangs off the other side.
+            let (search, found) = Search::run(
+                reachable.iter().copied(),
+                globe_points,
+                model,
+                |a, b| network.contains(&edge_key(a, b)),
+                |node| reaching.contains(&node),
+            );
+            let Some(found) = found else {
+                break; // the graph has no more disjoint paths to offer
+            };
+            search.add_path(found, network);
+        }
+    }
+}
+
+// Number of edge-disjoint paths from `source` to `sink` in `network`, counting at most to
+// `limit`, and once they are all used the nodes still reachable from `source` and the nodes
+// that can still reach `sink`. If there are fewer than `limit`, the edges leaving the former
+// form a minimum cut, and a new path from the former to the latter adds another one.
+fn max_flow(
+    network: &Network,
+    source: NodeIndex,
+    sink: NodeIndex,
+    limit: u32,
+) -> (u32, BTreeSet<NodeIndex>, BTreeSet<NodeIndex>) {
+    let mut neighbours: BTreeMap<NodeIndex, Vec<NodeIndex>> = BTreeMap::new();
+    for &(a, b) in network {
+        neighbours.entry(a).or_default().push(b);
+        neighbours.entry(b).or_default().push(a);
+    }
+    // Flow along every edge in the direction from the first to the second node, -1, 0 or 1.
+    let mut flow: BTreeMap<(NodeIndex, NodeIndex), i32> = BTreeMap::new();
+    let residual = |flow: &BTreeMap<_, i32>, a: NodeIndex, b: NodeIndex| {
+        1 - flow.get(&(a, b)).copied().unwrap_or(0)
+    };
+
+    let mut paths = 0;
+    while paths < limit {
+        // Breadth first search for a path with capacity left.
+        let mut prev = BTreeMap::from([(source, source)]);
+        let mut queue = VecDeque::from([source]);
+        while let Some(node) = queue.pop_front() {
+            if node == sink {
+                break;
+            }
+            for &next in neighbours.get(&node).into_iter().flatten() {
+                if !prev.contains_key(&next) && residual(&flow, node, next) > 0 {
+                    prev.insert(next, node);
+                    queue.push_back(next);
+                }
+            }
+        }
+        if !prev.contains_key(&sink) {
+            let mut reaching = BTreeSet::from([sink]);
+            let mut queue = VecDeque::from([sink]);
+            while let Some(node) = queue.pop_front() {
+                for &before in neighbours.get(&node).into_iter().flatten() {
+                    if residual(&flow, before, node) > 0 && reaching.insert(before) {
+                        queue.push_back(before);
+                    }
+                }
+            }
+            return (paths, prev.into_keys().collect(), reaching);
+        }
+        let mut node = sink;
+        while node != source {
+            let from = prev[&node];
+            *flow.entry((from, node)).or_default() += 1;
+            *flow.entry((node, from)).or_default() -= 1;
+            node = from;
+        }
+        paths += 1;
+    }
+    (paths, BTreeSet::new(), BTreeSet::new())
+}
@@ -0,0 +1,253 @@
+use crate::heightmap::Heightmap;
+use crate::perlin::{Perlin, PerlinConfig};
+use crate::state::Config;
+
+use bevy::math::Vec3;
+use serde::{Deserialize, Serialize};
+
+/// A source of terrain noise over the unit sphere.
+pub trait NoiseSource {
+    /// Noise at a point, mostly between -amplitude and amplitude of its config.
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32;
+
+    /// Writes the noise at each of `points` to `out`, the same as `noise` would.
+    fn noise_batch(&self, points: &[Vec3], out: &mut [f32]) {
+        for (point, out) in points.iter().zip(out) {
+            *out = self.noise(point.x, point.y, point.z);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoiseKind {
+    #[default]
+    Perlin, // fractal Perlin noise
+    Simplex, // fractal simplex noise, with fewer grid artefacts than Perlin
+    Ridged,  // ridged multifractal, sharp mountain ranges
+    Billow,  // rounded hills and valleys
+    Warped,  // fractal Perlin noise sampled at positions displaced by `warp` times itself
+}
+
+/// The noise source selected by `config.kind`.
+pub fn noise_source(config: &PerlinConfig) -> Box<dyn NoiseSource> {
+    let perlin = Perlin::new(*config);
+    match config.kind {
+        NoiseKind::Perlin => Box::new(perlin),
+        NoiseKind::Simplex => Box::new(Simplex { config: *config }),
+        NoiseKind::Ridged => Box::new(Ridged { perlin }),
+        NoiseKind::Billow => Box::new(Billow { perlin }),
+        NoiseKind::Warped => Box::new(Warped { perlin }),
+    }
+}
+
+// Frequency and amplitude of each octave.
+pub fn octaves(config: &PerlinConfig) -> impl Iterator<Item = (f32, f32)> {
+    let (lacunarity, persistence) = (config.lacunarity, config.persistence);
+    std::iter::successors(
+        Some((config.frequency, config.amplitude)),
+        move |&(f, a)| Some((f * lacunarity, a * persistence)),
+    )
+    .take(config.octaves as usize)
+}
+
+/// Heights of the terrain of a `Config`: `perlin_config` or `heightmap`, with `mountain_config`
+/// raised on the higher ground, so that e.g. ridged mountain ranges rise out of rolling plains.
+pub struct Terrain {
+    base: Box<dyn NoiseSource>,
+    base_amplitude: f32,
+    mountains: Option<Box<dyn NoiseSource>>,
+}
+
+// Fraction of the base amplitude above which the mountains reach their full height.
+const MOUNTAIN_LEVEL: f32 = 0.5;
+
+impl Terrain {
+    /// Fails if the `heightmap` images, which replace `perlin_config`, can't be read.
+    pub fn new(config: &Config) -> Result<Self, String> {
+        let (base, base_amplitude): (Box<dyn NoiseSource>, f32) = match &config.heightmap {
+            Some(heightmap) => (Box::new(Heightmap::load(heightmap)?), heightmap.max_height),
+            None => (
+                noise_source(&config.perlin_config),
+                config.perlin_config.amplitude,
+            ),
+        };
+        Ok(Self {
+            base,
+            base_amplitude,
+            mountains: config.mountain_config.as_ref().map(noise_source),
+        })
+    }
+}
+
+impl NoiseSource for Terrain {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        let base = self.base.noise(x, y, z);
+        let Some(mountains) = &self.mountains else {
+            return base;
+        };
+        self.raise(base, mountains.noise(x, y, z))
+    }
+
+    fn noise_batch(&self, points: &[Vec3], out: &mut [f32]) {
+        self.base.noise_batch(points, out);
+        let Some(mountains) = &self.mountains else {
+            return;
+        };
+        let mut heights = vec![0.0; points.len()];
+        mountains.noise_batch(points, &mut heights);
+        for (out, height) in out.iter_mut().zip(heights) {
+            *out = self.raise(*out, height);
+        }
+    }
+}
+
+impl Terrain {
+    fn raise(&self, base: f32, mountains: f32) -> f32 {
+        let t = (base / (MOUNTAIN_LEVEL * self.base_amplitude)).clamp(0.0, 1.0);
+        base + t * t * (3.0 - 2.0 * t) * mountains
+    }
+}
+
+pub struct Simplex {
+    pub config: PerlinConfig,
+}
+
+impl NoiseSource for Simplex {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        octaves(&self.config)
+            .map(|(f, a)| self.single_layer(x * f, y * f, z * f) * a)
+            .sum()
+    }
+}
+
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0],
+    [-1.0, 1.0, 0.0],
+    [1.0, -1.0, 0.0],
+    [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0],
+    [-1.0, 0.0, 1.0],
+    [1.0, 0.0, -1.0],
+    [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0],
+    [0.0, -1.0, 1.0],
+    [0.0, 1.0, -1.0],
+    [0.0, -1.0, -1.0],
+];
+
+impl Simplex {
+    // 3D simplex noise, scaled to about the spread of `Perlin::single_layer`. Sums the
+    // contributions of the four corners of the tetrahedron containing the point, instead of the
+    // eight corners of a cube.
+    pub fn single_layer(&self, x: f32, y: f32, z: f32) -> f32 {
+        const SKEW: f32 = 1.0 / 3.0;
+        const UNSKEW: f32 = 1.0 / 6.0;
+        let s = (x + y + z) * SKEW;
+        let (i, j, k) = ((x + s).floor(), (y + s).floor(), (z + s).floor());
+        let t = (i + j + k) * UNSKEW;
+        let first = [x - i + t, y - j + t, z - k + t];
+        let [x0, y0, z0] = first;
+
+        // The two middle corners, walking along the axes in decreasing order of the offsets.
+        let (second, third) = if x0 >= y0 {
+            if y0 >= z0 {
+                ([1, 0, 0], [1, 1, 0])
+            } else if x0 >= z0 {
+                ([1, 0, 0], [1, 0, 1])
+            } else {
+                ([0, 0, 1], [1, 0, 1])
+            }
+        } else if y0 < z0 {
+            ([0, 0, 1], [0, 1, 1])
+        } else if x0 < z0 {
+            ([0, 1, 0], [0, 1, 1])
+        } else {
+            ([0, 1, 0], [1, 1, 0])
+        };
+
+        let cell = [i as i32, j as i32, k as i32];
+        let corner = |steps: [i32; 3], n: f32| {
+            let offset: [f32; 3] = std::array::from_fn(|a| first[a] - steps[a] as f32 + n * UNSKEW);
+            let falloff = 0.6 - offset.iter().map(|d| d * d).sum::<f32>();
+            if falloff <= 0.0 {
+                return 0.0;
+            }
+            let gradient = GRADIENTS[self.hash(std::array::from_fn(|a| cell[a] + steps[a])) % 12];
+            let dot: f32 = (0..3).map(|a| gradient[a] * offset[a]).sum();
+            falloff.powi(4) * dot
+        };
+        16.0 * (corner([0, 0, 0], 0.0)
+            + corner(second, 1.0)
+            + corner(third, 2.0)
+            + corner([1, 1, 1], 3.0))
+    }
+
+    fn hash(&self, [x, y, z]: [i32; 3]) -> usize {
+        let mut h = self.config.seed.wrapping_mul(0x9e3779b9);
+        for c in [x, y, z] {
+            h ^= c as u32;
+            h = h.wrapping_mul(0x2bd1e995);
+            h ^= h >> 15;
+        }
+        h as usize
+    }
+}
+
+// Ridges form where the Perlin noise crosses zero. Each octave is weighted by the previous one,
+// so the detail piles up on the ridges and the valleys between them stay smooth.
+pub struct Ridged {
+    pub perlin: Perlin,
+}
+
+const RIDGE_GAIN: f32 = 2.0;
+// Subtracted from every octave, so that the noise averages about zero like Perlin noise.
+const RIDGE_MEAN: f32 = 0.75;
+
+impl NoiseSource for Ridged {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        let mut total = 0.0;
+        let mut weight = 1.0;
+        for (f, a) in octaves(&self.perlin.config) {
+            let ridge = 1.0 - self.perlin.single_layer(x * f, y * f, z * f).abs();
+            let signal = ridge * ridge * weight;
+            weight = (signal * RIDGE_GAIN).clamp(0.0, 1.0);
+            total += (signal - RIDGE_MEAN) * a;
+        }
+        total
+    }
+}
+
+// Folding the Perlin noise at zero makes rounded hills with creases between them.
+pub struct Billow {
+    pub perlin: Perlin,
+}
+
+// Average of the folded Perlin noise, see `RIDGE_MEAN`.
+const BILLOW_MEAN: f32 = 0.15;
+
+impl NoiseSource for Billow {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        octaves(&self.perlin.config)
+            .map(|(f, a)| {
+                (self.perlin.single_layer(x * f, y * f, z * f).abs() - BILLOW_MEAN) * 2.0 * a
+            })
+            .sum()
+    }
+}
+
+pub struct Warped {
+    pub perlin: Perlin,
+}
+
+// Offsets decorrelating the three displacement components from each other and the terrain.
+const WARP_OFFSETS: [[f32; 3]; 3] = [[5.2, 1.3, 7.1], [1.7, 9.2, 2.8], [8.3, 2.8, 4.6]];
+
+impl NoiseSource for Warped {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        let [dx, dy, dz] = WARP_OFFSETS.map(|[ox, oy, oz]| {
+            self.perlin.noise(x + ox, y + oy, z + oz) * self.perlin.config.warp
+        });
+        self.perlin.noise(x + dx, y + dy, z + dz)
+    }
+}
@@ -1,35 +1,185 @@
+use crate::noise::{NoiseKind, NoiseSource, octaves};
+
+use bevy::math::Vec3;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha12Rng;
+use serde::{Deserialize, Serialize};
 use statrs::distribution::{ContinuousCDF, Normal};
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct PerlinConfig {
+    pub kind: NoiseKind,
     pub seed: u32,
     pub frequency: f32,
     pub lacunarity: f32,
     pub persistence: f32,
     pub octaves: u32,
     pub amplitude: f32,
+    pub warp: f32, // displacement of the sample positions, for `NoiseKind::Warped`
+    // Hash the gradients of every sample like the first versions did, so that the seeds shared
+    // from them give the same worlds. Much slower than the gradient table used by default.
+    pub legacy_gradients: bool,
+}
+
+impl Default for PerlinConfig {
+    fn default() -> Self {
+        Self {
+            kind: NoiseKind::Perlin,
+            seed: 485,
+            frequency: 3.0,
+            lacunarity: 1.57,
+            persistence: 0.5,
+            octaves: 6,
+            amplitude: 0.75,
+            warp: 0.5,
+            legacy_gradients: false,
+        }
+    }
 }
 
 pub struct Perlin {
     pub config: PerlinConfig,
+    // Random unit gradients, and a shuffle of their indices hashing the lattice points.
+    gradients: [[f32; 3]; TABLE_SIZE],
+    permutation: [u8; TABLE_SIZE],
+}
+
+const TABLE_SIZE: usize = 256;
+
+// Points evaluated together by `noise_batch`, short enough for the lanes to stay in registers.
+const LANES: usize = 8;
+
+impl Perlin {
+    pub fn new(config: PerlinConfig) -> Self {
+        let mut rng = ChaCha12Rng::seed_from_u64(config.seed as u64);
+        let gradients = std::array::from_fn(|_| {
+            loop {
+                // Uniform in the unit ball, so that the directions are uniform on the sphere.
+                let v = Vec3::new(
+                    rng.random_range(-1.0..=1.0),
+                    rng.random_range(-1.0..=1.0),
+                    rng.random_range(-1.0..=1.0),
+                );
+                let r2 = v.length_squared();
+                if r2 > 1e-6 && r2 <= 1.0 {
+                    break v.normalize().to_array();
+                }
+            }
+        });
+        let mut permutation = std::array::from_fn(|i| i as u8);
+        permutation.shuffle(&mut rng);
+        Self {
+            config,
+            gradients,
+            permutation,
+        }
+    }
+}
+
+impl NoiseSource for Perlin {
+    fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
+        octaves(&self.config)
+            .map(|(f, a)| self.single_layer(x * f, y * f, z * f) * a)
+            .sum()
+    }
+
+    fn noise_batch(&self, points: &[Vec3], out: &mut [f32]) {
+        if self.config.legacy_gradients {
+            for (point, out) in points.iter().zip(out) {
+                *out = self.noise(point.x, point.y, point.z);
+            }
+            return;
+        }
+        out.fill(0.0);
+        for (f, a) in octaves(&self.config) {
+            for (points, out) in points.chunks(LANES).zip(out.chunks_mut(LANES)) {
+                let mut lanes = [[0.0; LANES]; 3];
+                for (lane, point) in points.iter().enumerate() {
+                    lanes[0][lane] = point.x * f;
+                    lanes[1][lane] = point.y * f;
+                    lanes[2][lane] = point.z * f;
+                }
+                let layer = self.table_layers(lanes);
+                for (out, layer) in out.iter_mut().zip(layer) {
+                    *out += layer * a;
+                }
+            }
+        }
+    }
+}
+
+fn fade(t: f32) -> f32 {
+    3.0 * t * t - 2.0 * t * t * t
+}
+
+fn lerp(t: f32, a: f32, b: f32) -> f32 {
+    a + t * (b - a)
+}
+
+// Blends the dot products at the eight corners of a cell, indexed by the corner's x + 2y + 4z.
+fn interpolate(dots: [f32; 8], [u, v, w]: [f32; 3]) -> f32 {
+    lerp(
+        w,
+        lerp(v, lerp(u, dots[0], dots[1]), lerp(u, dots[2], dots[3])),
+        lerp(v, lerp(u, dots[4], dots[5]), lerp(u, dots[6], dots[7])),
+    )
 }
 
 impl Perlin {
-    pub fn noise(&self, x: f32, y: f32, z: f32) -> f32 {
-        let mut total = 0.0;
-        let mut frequency = self.config.frequency;
-        let mut amplitude = self.config.amplitude;
-
-        for _ in 0..self.config.octaves {
-            total += self.single_layer(x * frequency, y * frequency, z * frequency) * amplitude;
-            frequency *= self.config.lacunarity;
-            amplitude *= self.config.persistence;
+    pub fn single_layer(&self, x: f32, y: f32, z: f32) -> f32 {
+        if self.config.legacy_gradients {
+            return self.legacy_layer(x, y, z);
         }
+        let cell = [x.floor(), y.floor(), z.floor()];
+        let offset = [x - cell[0], y - cell[1], z - cell[2]];
+        let cell = cell.map(|c| c as i32);
+        let dots = std::array::from_fn(|corner| self.corner_dot(cell, offset, corner));
+        interpolate(dots, offset.map(fade))
+    }
+
+    // `single_layer` of `LANES` points at once, given as x, y and z lanes. Each step runs over
+    // all lanes before the next one, so that the arithmetic can be vectorised.
+    fn table_layers(&self, points: [[f32; LANES]; 3]) -> [f32; LANES] {
+        let cells = points.map(|axis| axis.map(f32::floor));
+        let offsets: [[f32; LANES]; 3] =
+            std::array::from_fn(|a| std::array::from_fn(|lane| points[a][lane] - cells[a][lane]));
+        let fades = offsets.map(|axis| axis.map(fade));
+        let cells = cells.map(|axis| axis.map(|c| c as i32));
+        let dots: [[f32; LANES]; 8] = std::array::from_fn(|corner| {
+            std::array::from_fn(|lane| {
+                self.corner_dot(
+                    [cells[0][lane], cells[1][lane], cells[2][lane]],
+                    [offsets[0][lane], offsets[1][lane], offsets[2][lane]],
+                    corner,
+                )
+            })
+        });
+        std::array::from_fn(|lane| {
+            interpolate(
+                dots.map(|corner| corner[lane]),
+                [fades[0][lane], fades[1][lane], fades[2][lane]],
+            )
+        })
+    }
 
-        total
+    // Dot product of the gradient at a corner of the cell with the offset from that corner.
+    fn corner_dot(&self, cell: [i32; 3], offset: [f32; 3], corner: usize) -> f32 {
+        let step = [corner & 1, (corner >> 1) & 1, (corner >> 2) & 1];
+        let hash = (0..3).fold(0, |hash, a| {
+            let c = cell[a] + step[a] as i32;
+            self.permutation[(hash + c).rem_euclid(TABLE_SIZE as i32) as usize] as i32
+        });
+        let g = self.gradients[hash as usize];
+        g[0] * (offset[0] - step[0] as f32)
+            + g[1] * (offset[1] - step[1] as f32)
+            + g[2] * (offset[2] - step[2] as f32)
     }
 
-    fn single_layer(&self, x: f32, y: f32, z: f32) -> f32 {
+    // The gradients of the first versions, hashed from the lattice point and turned into
+    // normally distributed vectors on every sample.
+    fn legacy_layer(&self, x: f32, y: f32, z: f32) -> f32 {
         let x = x + self.config.seed as f32;
         let y = y + self.config.seed as f32;
         let z = z + self.config.seed as f32;
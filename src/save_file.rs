@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use crate::dijkstra::{EdgeKind, GridPoint};
+use crate::globe_file::{
+    invalid, read_f32, read_f32s, read_u8, read_u32, read_u64, read_vec, write_f32, write_f32s,
+    write_u32, write_u64, write_vec,
+};
+use crate::state::{Config, Rail};
+use crate::train::Train;
+
+use bevy::prelude::*;
+use rand_chacha::ChaCha12Rng;
+use rand_chacha::rand_core::SeedableRng;
+
+pub const SAVE_FILE: &str = "save.t3s";
+
+const MAGIC: &[u8; 4] = b"T3DS";
+// Bump whenever the layout changes. Old saves are rejected rather than misread.
+const VERSION: u32 = 4;
+
+/// Everything needed to resume a session on top of a globe generated from `config`.
+pub struct SaveGame {
+    pub config: Config,
+    pub cities: Vec<GridPoint>,
+    pub rails: Vec<(Rail, usize)>, // rail and its usage counter
+    pub trains: Vec<Train>,
+    pub expansion_rng: ChaCha12Rng,
+    pub tick: u64,
+    pub create_new_city_next: bool,
+    pub max_rail_usage: usize,
+    // Path search in flight and the tick its result is applied at.
+    pub dijkstra_task: Option<(GridPoint, GridPoint, u64)>,
+    // Path searches waiting for `dijkstra_task`, oldest first.
+    pub dijkstra_queue: Vec<(GridPoint, GridPoint)>,
+}
+
+impl SaveGame {
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut w = BufWriter::new(File::create(path)?);
+        w.write_all(MAGIC)?;
+        write_u32(&mut w, VERSION)?;
+        write_config(&mut w, &self.config)?;
+        write_vec(&mut w, &self.cities, |w, &city| write_gridpoint(w, city))?;
+        write_vec(&mut w, &self.rails, |w, &(rail, counter)| {
+            write_rail(w, rail)?;
+            write_u64(w, counter as u64)
+        })?;
+        write_vec(&mut w, &self.trains, write_train)?;
+        w.write_all(&self.expansion_rng.get_seed())?;
+        write_u64(&mut w, self.expansion_rng.get_stream())?;
+        w.write_all(&self.expansion_rng.get_word_pos().to_le_bytes())?;
+        write_u64(&mut w, self.tick)?;
+        w.write_all(&[self.create_new_city_next as u8])?;
+        write_u64(&mut w, self.max_rail_usage as u64)?;
+        match self.dijkstra_task {
+            Some((start, end, ready_tick)) => {
+                w.write_all(&[1])?;
+                write_gridpoint(&mut w, start)?;
+                write_gridpoint(&mut w, end)?;
+                write_u64(&mut w, ready_tick)?;
+            }
+            None => w.write_all(&[0])?,
+        }
+        write_vec(&mut w, &self.dijkstra_queue, |w, &(start, end)| {
+            write_gridpoint(w, start)?;
+            write_gridpoint(w, end)
+        })?;
+        w.flush()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut r = BufReader::new(File::open(path)?);
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a save file"));
+        }
+        if read_u32(&mut r)? != VERSION {
+            return Err(invalid("save file was written by another version"));
+        }
+        let config = read_config(&mut r)?;
+        let cities = read_vec(&mut r, read_gridpoint)?;
+        let rails = read_vec(&mut r, |r| Ok((read_rail(r)?, read_u64(r)? as usize)))?;
+        let trains = read_vec(&mut r, read_train)?;
+        let mut seed = [0; 32];
+        r.read_exact(&mut seed)?;
+        let mut expansion_rng = ChaCha12Rng::from_seed(seed);
+        expansion_rng.set_stream(read_u64(&mut r)?);
+        let mut word_pos = [0; 16];
+        r.read_exact(&mut word_pos)?;
+        expansion_rng.set_word_pos(u128::from_le_bytes(word_pos));
+        let tick = read_u64(&mut r)?;
+        let create_new_city_next = read_u8(&mut r)? != 0;
+        let max_rail_usage = read_u64(&mut r)? as usize;
+        let dijkstra_task = match read_u8(&mut r)? {
+            0 => None,
+            _ => Some((
+                read_gridpoint(&mut r)?,
+                read_gridpoint(&mut r)?,
+                read_u64(&mut r)?,
+            )),
+        };
+        let dijkstra_queue = read_vec(&mut r, |r| Ok((read_gridpoint(r)?, read_gridpoint(r)?)))?;
+        Ok(Self {
+            config,
+            cities,
+            rails,
+            trains,
+            expansion_rng,
+            tick,
+            create_new_city_next,
+            max_rail_usage,
+            dijkstra_task,
+            dijkstra_queue,
+        })
+    }
+}
+
+// The config is stored as TOML so that new fields don't need to be spelled out here.
+fn write_config(w: &mut impl Write, config: &Config) -> io::Result<()> {
+    let text = toml::to_string(config).map_err(|err| invalid(&err.to_string()))?;
+    write_u32(w, text.len() as u32)?;
+    w.write_all(text.as_bytes())
+}
+
+fn read_config(r: &mut impl Read) -> io::Result<Config> {
+    let mut text = vec![0; read_u32(r)? as usize];
+    r.read_exact(&mut text)?;
+    let text = String::from_utf8(text).map_err(|_| invalid("config is not UTF-8"))?;
+    toml::from_str(&text).map_err(|err| invalid(&err.to_string()))
+}
+
+fn write_gridpoint(w: &mut impl Write, grid: GridPoint) -> io::Result<()> {
+    write_u32(w, grid.0)?;
+    write_u32(w, grid.1)?;
+    write_u32(w, grid.2)
+}
+
+fn read_gridpoint(r: &mut impl Read) -> io::Result<GridPoint> {
+    Ok((read_u32(r)?, read_u32(r)?, read_u32(r)?))
+}
+
+fn write_rail(w: &mut impl Write, rail: Rail) -> io::Result<()> {
+    write_gridpoint(w, rail.from)?;
+    write_gridpoint(w, rail.to)?;
+    w.write_all(&[EdgeKind::ALL.iter().position(|&k| k == rail.kind).unwrap() as u8])
+}
+
+fn read_rail(r: &mut impl Read) -> io::Result<Rail> {
+    let from = read_gridpoint(r)?;
+    let to = read_gridpoint(r)?;
+    let Some(&kind) = EdgeKind::ALL.get(read_u8(r)? as usize) else {
+        return Err(invalid("unknown rail kind"));
+    };
+    Ok(Rail { from, to, kind })
+}
+
+fn write_train<W: Write>(w: &mut W, train: &Train) -> io::Result<()> {
+    write_vec(w, &train.transforms, |w, (transform, rail)| {
+        write_f32s(w, &transform.translation.to_array())?;
+        write_f32s(w, &transform.rotation.to_array())?;
+        write_f32s(w, &transform.scale.to_array())?;
+        write_rail(w, *rail)
+    })?;
+    write_u32(w, train.idx as u32)?;
+    write_u32(w, train.next_idx as u32)?;
+    w.write_all(&[train.forward as u8])?;
+    write_f32(w, train.seconds_spent_within_segment)?;
+    write_f32(w, train.segment_duration.unwrap_or(-1.0))
+}
+
+fn read_train<R: Read>(r: &mut R) -> io::Result<Train> {
+    let transforms = read_vec(r, |r| {
+        let transform = Transform {
+            translation: Vec3::from_array(read_f32s(r)?),
+            rotation: Quat::from_array(read_f32s(r)?),
+            scale: Vec3::from_array(read_f32s(r)?),
+        };
+        Ok((transform, read_rail(r)?))
+    })?;
+    let idx = read_u32(r)? as usize;
+    let next_idx = read_u32(r)? as usize;
+    if transforms.len() < 2 || idx >= transforms.len() || next_idx >= transforms.len() {
+        return Err(invalid("train position out of range"));
+    }
+    let forward = read_u8(r)? != 0;
+    let seconds_spent_within_segment = read_f32(r)?;
+    let segment_duration = Some(read_f32(r)?).filter(|&d| d >= 0.0);
+    Ok(Train {
+        transforms,
+        idx,
+        next_idx,
+        forward,
+        seconds_spent_within_segment,
+        segment_duration,
+    })
+}
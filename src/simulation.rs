@@ -0,0 +1,828 @@
+use std::collections::VecDeque;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use crate::cost_model::CostModel;
+use crate::dijkstra::{GlobePoint, GlobePoints, GridPoint, TrackLimits, route};
+use crate::network_planner::plan_network;
+use crate::save_file::SaveGame;
+use crate::state::{Config, Rail, RailInfo, Rails, State};
+use crate::train::Train;
+
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+use crossbeam_channel::{Receiver, Sender, bounded};
+use rand::Rng;
+
+/// City spawning, path finding, rail discounting and train movement, without any rendering.
+/// The UI adds meshes and materials to the entities spawned here.
+///
+/// The simulation advances in ticks of `Config::tick_seconds`, run from `FixedUpdate`, so that
+/// given the same seed and player actions it plays out the same regardless of the frame rate.
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        let (sender, receiver) = bounded(1);
+        app.insert_resource(DijkstraCommunication {
+            sender,
+            receiver,
+            task: None,
+            ready_tick: 0,
+            queue: VecDeque::new(),
+        })
+        .insert_resource(NetworkPlanning::new())
+        .init_resource::<SimSpeed>()
+        .init_resource::<PlayerActions>()
+        .add_event::<RailUsageChanged>()
+        .init_schedule(SimulationTick)
+        .add_systems(
+            SimulationTick,
+            (
+                apply_player_actions,
+                lay_planned_network,
+                start_queued_search,
+                create_path_if_dijkstra_ready,
+                move_trains,
+                start_network_planning,
+                advance_tick,
+            )
+                .chain(),
+        )
+        .add_systems(First, sync_tick_rate)
+        .add_systems(FixedUpdate, run_simulation_ticks);
+    }
+}
+
+/// One step of the simulation, run `SimSpeed` times per fixed timestep.
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SimulationTick;
+
+/// Simulation ticks per fixed timestep, 0 pauses the simulation.
+#[derive(Resource)]
+pub struct SimSpeed(pub u32);
+
+impl Default for SimSpeed {
+    fn default() -> Self {
+        SimSpeed(1)
+    }
+}
+
+pub const SIM_SPEEDS: [u32; 4] = [0, 1, 4, 16];
+
+// Simulated time a path search is given. Its result is applied exactly this long after it was
+// started, holding the simulation back if needed, so that the outcome doesn't depend on how fast
+// it ran.
+const PATH_SEARCH_SECONDS: f32 = 0.25;
+
+#[derive(Component)]
+pub struct City;
+
+#[derive(Component)]
+pub struct Position {
+    pub gridpoint: GridPoint,
+    pub globe_point: GlobePoint,
+}
+
+// A piece of rail between two neighbouring grid points, see `State::rails`.
+#[derive(Component)]
+pub struct RailPiece(pub Rail);
+
+/// Sent whenever a train enters a rail piece and so increases its usage counter.
+#[derive(Event)]
+pub struct RailUsageChanged {
+    pub rail: Rail,
+}
+
+pub enum PlayerAction {
+    PlaceCity(GridPoint),
+    Connect(GridPoint, GridPoint),
+    Build(Vec<GridPoint>), // lays rails along a path of neighbouring grid points, without a train
+    Follow(Vec<GridPoint>), // as `Build`, and sends a train along the path
+    PlanNetwork,           // lays the rails of `plan_network` between all cities, without a train
+}
+
+/// Player actions waiting for the next simulation tick. Going through the tick rather than
+/// changing the world directly makes a game reproducible from the ticks of its actions.
+///
+/// Actions that lay rails wait here while a path search runs, so that the search sees the same
+/// rails however fast it runs.
+#[derive(Resource, Default)]
+pub struct PlayerActions(pub Vec<PlayerAction>);
+
+#[derive(Resource)]
+pub struct DijkstraCommunication {
+    sender: Sender<Option<Vec<GridPoint>>>,
+    receiver: Receiver<Option<Vec<GridPoint>>>,
+    pub task: Option<(GridPoint, GridPoint)>,
+    pub ready_tick: u64, // tick at which the result of `task` is applied
+    pub queue: VecDeque<(GridPoint, GridPoint)>, // searches waiting for `task`, oldest first
+}
+
+impl DijkstraCommunication {
+    /// True if the result of `task` is due at `tick` but the search is still running.
+    pub fn is_overdue(&self, tick: u64) -> bool {
+        self.task.is_some() && tick >= self.ready_tick && self.receiver.is_empty()
+    }
+}
+
+/// The planner of `PlayerAction::PlanNetwork`, which runs on a background thread. It is started
+/// at the end of the tick the action was applied in and its rails are laid at the next one, which
+/// waits for them, so the plan sees the same network however long it takes.
+#[derive(Resource)]
+pub struct NetworkPlanning {
+    sender: Sender<Vec<Rail>>,
+    receiver: Receiver<Vec<Rail>>,
+    pub task: Option<Vec<GridPoint>>, // cities to connect, until their rails are laid
+    pub ready_tick: u64,              // tick at which the rails of `task` are laid
+}
+
+impl NetworkPlanning {
+    fn new() -> Self {
+        let (sender, receiver) = bounded(1);
+        Self {
+            sender,
+            receiver,
+            task: None,
+            ready_tick: 0,
+        }
+    }
+
+    /// True if the rails of `task` are due at `tick` but the planner is still running.
+    pub fn is_overdue(&self, tick: u64) -> bool {
+        self.task.is_some() && tick >= self.ready_tick && self.receiver.is_empty()
+    }
+}
+
+/// Queues a path search from `start` to `end`, see `start_queued_search`.
+pub fn start_dijkstra(
+    dijkstra_communication: &mut DijkstraCommunication,
+    start: GridPoint,
+    end: GridPoint,
+) {
+    dijkstra_communication.queue.push_back((start, end));
+}
+
+// Starts the next queued path search once the previous one was applied, after the player's
+// actions and any planned network of this tick. Rails are not laid again until its result is
+// applied, see `PlayerActions`, and the held back actions get their turn in between searches.
+fn start_queued_search(
+    state: Res<State>,
+    mut dijkstra_communication: ResMut<DijkstraCommunication>,
+    network_planning: Res<NetworkPlanning>,
+) {
+    if dijkstra_communication.task.is_some() || network_planning.task.is_some() {
+        return;
+    }
+    start_next_search(&mut dijkstra_communication, &state);
+}
+
+fn start_next_search(dijkstra_communication: &mut DijkstraCommunication, state: &State) {
+    let Some((start, end)) = dijkstra_communication.queue.pop_front() else {
+        return;
+    };
+    let search_ticks = (PATH_SEARCH_SECONDS * state.config.tick_rate as f32).ceil() as u64;
+    search_path(
+        dijkstra_communication,
+        &state.globe_points,
+        state.config.track_limits(),
+        state.config.cost_model(),
+        start,
+        end,
+        state.tick + search_ticks,
+    );
+}
+
+fn search_path(
+    dijkstra_communication: &mut DijkstraCommunication,
+    globe_points: &Arc<RwLock<GlobePoints>>,
+    limits: TrackLimits,
+    model: Box<dyn CostModel>,
+    start: GridPoint,
+    end: GridPoint,
+    ready_tick: u64,
+) {
+    let globe_points_lock = Arc::clone(globe_points);
+    let sender = dijkstra_communication.sender.clone();
+    dijkstra_communication.task = Some((start, end));
+    dijkstra_communication.ready_tick = ready_tick;
+    thread::spawn({
+        move || {
+            let Ok(globe_points) = globe_points_lock.read() else {
+                println!("Failed to lock globe points. This should never happen.");
+                sender.send(None).unwrap();
+                return;
+            };
+            let (path, _) = route(start, end, &globe_points, &limits, model.as_ref());
+            // Unlock before the result can be applied, which locks the globe points for writing.
+            drop(globe_points);
+            sender.send(Some(path)).unwrap();
+        }
+    });
+}
+
+fn run_simulation_ticks(world: &mut World) {
+    for _ in 0..world.resource::<SimSpeed>().0 {
+        // Wait for a late path search or plan on the next frame instead of blocking this one.
+        let tick = world.resource::<State>().tick;
+        if world.resource::<DijkstraCommunication>().is_overdue(tick)
+            || world.resource::<NetworkPlanning>().is_overdue(tick)
+        {
+            break;
+        }
+        world.run_schedule(SimulationTick);
+    }
+}
+
+fn advance_tick(mut state: ResMut<State>) {
+    state.tick += 1;
+}
+
+fn sync_tick_rate(state: Res<State>, mut time: ResMut<Time<Fixed>>) {
+    let timestep = Duration::from_secs_f64(1.0 / state.config.tick_rate as f64);
+    if time.timestep() != timestep {
+        time.set_timestep(timestep);
+    }
+}
+
+fn apply_player_actions(
+    mut commands: Commands,
+    mut state: ResMut<State>,
+    mut dijkstra_communication: ResMut<DijkstraCommunication>,
+    mut network_planning: ResMut<NetworkPlanning>,
+    mut actions: ResMut<PlayerActions>,
+    cities: Query<&Position, With<City>>,
+) {
+    let mut pending = std::mem::take(&mut actions.0).into_iter();
+    for action in pending.by_ref() {
+        let lays_rails = matches!(
+            action,
+            PlayerAction::Build(_) | PlayerAction::Follow(_) | PlayerAction::PlanNetwork
+        );
+        if lays_rails && dijkstra_communication.task.is_some() {
+            // Later actions wait as well, to keep their order.
+            actions.0.push(action);
+            break;
+        }
+        match action {
+            PlayerAction::PlaceCity(gridpoint) => {
+                let Ok(globe_points) = state.globe_points.read() else {
+                    println!("Failed to lock globe points. This should never happen.");
+                    continue;
+                };
+                if let Some(&globe_point) = globe_points.points.get(&gridpoint) {
+                    spawn_city(&mut commands, gridpoint, globe_point);
+                }
+            }
+            PlayerAction::Connect(start, end) => {
+                if dijkstra_communication.task.is_some() {
+                    println!("Dijkstra is busy, connecting {start:?} and {end:?} after it.");
+                }
+                start_dijkstra(&mut dijkstra_communication, start, end);
+            }
+            PlayerAction::Build(path) => {
+                let globe_points_lock = Arc::clone(&state.globe_points);
+                let Ok(mut globe_points) = globe_points_lock.write() else {
+                    println!("Failed to lock globe points. This should never happen.");
+                    continue;
+                };
+                lay_rails(&mut commands, &mut state.rails, &mut globe_points, &path);
+            }
+            PlayerAction::Follow(path) => {
+                let globe_points_lock = Arc::clone(&state.globe_points);
+                let Ok(mut globe_points) = globe_points_lock.write() else {
+                    println!("Failed to lock globe points. This should never happen.");
+                    continue;
+                };
+                let train_transforms =
+                    lay_rails(&mut commands, &mut state.rails, &mut globe_points, &path);
+                if let Some(train) = Train::new(train_transforms) {
+                    spawn_train(&mut commands, train);
+                }
+            }
+            PlayerAction::PlanNetwork => {
+                if network_planning.task.is_some() {
+                    println!("Already planning a network, skipping.");
+                    continue;
+                }
+                let mut cities: Vec<GridPoint> =
+                    cities.iter().map(|position| position.gridpoint).collect();
+                // Queries run in archetype order, sort for a reproducible plan.
+                cities.sort();
+                network_planning.task = Some(cities);
+                network_planning.ready_tick = state.tick + 1;
+            }
+        }
+    }
+    actions.0.extend(pending);
+}
+
+// Runs the planner asked for in this tick, after all the other changes of the tick were made.
+// Until its rails are laid the simulation holds still, so it can keep the globe points locked.
+fn start_network_planning(state: Res<State>, network_planning: Res<NetworkPlanning>) {
+    let Some(cities) = &network_planning.task else {
+        return;
+    };
+    if network_planning.ready_tick != state.tick + 1 {
+        return;
+    }
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let sender = network_planning.sender.clone();
+    let cities = cities.clone();
+    let model = state.config.cost_model();
+    let connectivity = state.config.network_connectivity;
+    thread::spawn(move || {
+        let Ok(globe_points) = globe_points_lock.read() else {
+            println!("Failed to lock globe points. This should never happen.");
+            sender.send(Vec::new()).unwrap();
+            return;
+        };
+        let rails = plan_network(&cities, &globe_points, model.as_ref(), connectivity);
+        drop(globe_points);
+        sender.send(rails).unwrap();
+    });
+}
+
+fn lay_planned_network(
+    mut commands: Commands,
+    mut state: ResMut<State>,
+    mut network_planning: ResMut<NetworkPlanning>,
+) {
+    let Some(cities) = &network_planning.task else {
+        return;
+    };
+    if state.tick < network_planning.ready_tick {
+        return;
+    }
+    // Never empty here, `run_simulation_ticks` holds the tick back until the rails arrived.
+    let Ok(rails) = network_planning.receiver.try_recv() else {
+        return;
+    };
+    println!(
+        "Planned {} rails between {} cities.",
+        rails.len(),
+        cities.len()
+    );
+    network_planning.task = None;
+
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let Ok(mut globe_points) = globe_points_lock.write() else {
+        println!("Failed to lock globe points. This should never happen.");
+        return;
+    };
+    for rail in rails {
+        lay_rails(
+            &mut commands,
+            &mut state.rails,
+            &mut globe_points,
+            &[rail.from, rail.to],
+        );
+    }
+}
+
+// Marks the edges of `path` as built and spawns the rails along it that don't exist yet. Returns
+// the transforms of a train on each rail of the path.
+fn lay_rails(
+    commands: &mut Commands,
+    rails: &mut Rails,
+    globe_points: &mut GlobePoints,
+    path: &[GridPoint],
+) -> Vec<(Transform, Rail)> {
+    globe_points.mark_built(path);
+
+    let mut train_transforms = Vec::new();
+
+    for line in path.windows(2) {
+        let (from, to) = (line[0], line[1]);
+        if let Some(from_point) = globe_points.points.get(&from)
+            && let Some(to_point) = globe_points.points.get(&to)
+        {
+            let rail = Rail {
+                from: from.min(to),
+                to: from.max(to),
+                kind: globe_points.edge_kind(&from, &to).unwrap_or_default(),
+            };
+
+            // compute the transform of both the path segment and train
+            let (mid_point, length, rotation) = rail_placement(from_point.pos, to_point.pos);
+
+            // Pieces of rail shared with earlier paths are reused.
+            if let std::collections::hash_map::Entry::Vacant(e) = rails.rails.entry(rail) {
+                // Otherwise, create a new entity for the rail and store it in the
+                // Rails resource.
+                let entity = spawn_rail(commands, rail, mid_point, length, rotation);
+                e.insert(RailInfo {
+                    entity,
+                    counter: 0.into(),
+                    // Other details can be added here.
+                });
+            }
+
+            train_transforms.push((
+                Transform::from_translation(mid_point * 1.005).with_rotation(rotation),
+                rail,
+            ));
+        }
+    }
+    train_transforms
+}
+
+fn create_path_if_dijkstra_ready(
+    mut commands: Commands,
+    mut state: ResMut<State>,
+    mut dijkstra_communication: ResMut<DijkstraCommunication>,
+    cities: Query<(Entity, &Position), With<City>>,
+) {
+    let Some(_) = dijkstra_communication.task else {
+        return;
+    };
+    if state.tick < dijkstra_communication.ready_tick {
+        return;
+    }
+    // Never empty here, `run_simulation_ticks` holds the tick back until the result arrived.
+    let Ok(dijkstra_result) = dijkstra_communication.receiver.try_recv() else {
+        return;
+    };
+
+    // Clear task to signal Dijkstra is ready for another task.
+    dijkstra_communication.task = None;
+
+    let Some(path) = dijkstra_result else {
+        println!("Dijkstra returned None, skipping path creation.");
+        return;
+    };
+
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let Ok(mut globe_points) = globe_points_lock.write() else {
+        println!("Failed to lock globe points. This should never happen.");
+        return;
+    };
+
+    println!("Dijkstra done, path length: {}", path.len());
+
+    let train_transforms = lay_rails(&mut commands, &mut state.rails, &mut globe_points, &path);
+
+    // spawn a train at the first point of the path
+    if let Some(train) = Train::new(train_transforms) {
+        spawn_train(&mut commands, train);
+    }
+
+    let add_another_train = state.config.num_automatic_trains > 0;
+    if add_another_train {
+        state.config.num_automatic_trains -= 1;
+        println!(
+            "Adding another train, {} left to add.",
+            state.config.num_automatic_trains
+        );
+
+        let prev_city_index = state.expansion_rng.random_range(0..cities.iter().len());
+        let Some(prev_city) = cities
+            .iter()
+            .nth(prev_city_index)
+            .map(|(_, pos)| pos.gridpoint)
+        else {
+            panic!("Getting previous city failed.");
+        };
+
+        let mut other_city;
+
+        let other_prev_city_index = loop {
+            let candidate = state.expansion_rng.random_range(0..cities.iter().len());
+            if candidate != prev_city_index {
+                break candidate;
+            }
+        };
+
+        other_city = cities
+            .iter()
+            .nth(other_prev_city_index)
+            .map(|(_, pos)| pos.gridpoint);
+
+        if state.create_new_city_next {
+            let city_positions: Vec<Vec3> =
+                cities.iter().map(|(_, pos)| pos.globe_point.pos).collect();
+            let State {
+                expansion_rng,
+                config,
+                ..
+            } = &mut *state;
+            let (gridpoint, globe_point) =
+                find_city_site(expansion_rng, &globe_points, config, &city_positions);
+            spawn_city(&mut commands, gridpoint, globe_point);
+            other_city = Some(gridpoint);
+        }
+
+        state.create_new_city_next = !state.create_new_city_next;
+        let Some(target_city) = other_city else {
+            panic!("No target city found, skipping train creation.");
+        };
+
+        start_dijkstra(&mut dijkstra_communication, prev_city, target_city);
+    }
+}
+
+/// Picks a random place for a new city: on land, away from the other cities, and more likely
+/// the lower it is.
+pub fn find_city_site(
+    rng: &mut impl Rng,
+    globe_points: &GlobePoints,
+    config: &Config,
+    city_positions: &[Vec3],
+) -> (GridPoint, GlobePoint) {
+    let grid_size = config.grid_size;
+    loop {
+        let candidate_gridpoint = (
+            rng.random_range(0..6),
+            rng.random_range(0..=grid_size),
+            rng.random_range(0..=grid_size),
+        );
+        let height_threshold = rng.random::<f32>().powf(3.0) + 0.01;
+        let Some(&globe_point) = globe_points.points.get(&candidate_gridpoint) else {
+            continue; // Skip if no GlobePoint found for this gridpoint
+        };
+        if globe_point.water {
+            continue; // Skip water points
+        }
+        if city_positions
+            .iter()
+            .any(|&pos| (pos - globe_point.pos).length() < config.min_city_distance)
+        {
+            continue; // Skip if a city already exists at this point
+        }
+        let height_ratio = (globe_point.pos.length() - config.sea_level) / config.snow_level;
+        if height_ratio < height_threshold {
+            return (candidate_gridpoint, globe_point);
+        }
+    }
+}
+
+/// Spawns `count` cities at random sites and starts connecting the first two, which kicks off
+/// the automatic expansion. Used when there is no player to place the first cities.
+pub fn seed_cities(world: &mut World, count: usize) {
+    let mut positions = Vec::new();
+    let mut gridpoints = Vec::new();
+    world.resource_scope(|world, mut state: Mut<State>| {
+        let globe_points_lock = Arc::clone(&state.globe_points);
+        let globe_points = globe_points_lock.read().unwrap();
+        let State {
+            expansion_rng,
+            config,
+            ..
+        } = &mut *state;
+        for _ in 0..count {
+            let (gridpoint, globe_point) =
+                find_city_site(expansion_rng, &globe_points, config, &positions);
+            world.spawn(city_bundle(gridpoint, globe_point));
+            positions.push(globe_point.pos);
+            gridpoints.push(gridpoint);
+        }
+    });
+    if let [start, end, ..] = gridpoints[..] {
+        let mut dijkstra_communication = world.resource_mut::<DijkstraCommunication>();
+        start_dijkstra(&mut dijkstra_communication, start, end);
+    }
+}
+
+// Midpoint, length and rotation of the rail cuboid between two points.
+pub fn rail_placement(from: Vec3, to: Vec3) -> (Vec3, f32, Quat) {
+    let direction = to - from;
+    let length = direction.length();
+    let mid_point = (from + to) / 2.0;
+
+    let dir_norm = direction.normalize();
+    let up = Vec3::cross(Vec3::cross(dir_norm, mid_point.normalize()), dir_norm);
+    let rotation = Quat::from_mat3(&Mat3::from_cols(Vec3::cross(dir_norm, up), dir_norm, up));
+    (mid_point, length, rotation)
+}
+
+pub fn spawn_rail(
+    commands: &mut Commands,
+    rail: Rail,
+    mid_point: Vec3,
+    length: f32,
+    rotation: Quat,
+) -> Entity {
+    commands
+        .spawn((
+            RailPiece(rail),
+            Transform::from_scale(Vec3 {
+                x: 0.06,
+                y: length,
+                z: 0.04,
+            })
+            .with_translation(mid_point)
+            .with_rotation(rotation),
+        ))
+        .id()
+}
+
+fn city_bundle(gridpoint: GridPoint, globe_point: GlobePoint) -> impl Bundle {
+    (
+        City,
+        Position {
+            gridpoint,
+            globe_point,
+        },
+        Transform::from_xyz(globe_point.pos[0], globe_point.pos[1], globe_point.pos[2])
+            .looking_at(Vec3::ZERO, Vec3::Z),
+    )
+}
+
+pub fn spawn_city(commands: &mut Commands, gridpoint: GridPoint, globe_point: GlobePoint) {
+    commands.spawn(city_bundle(gridpoint, globe_point));
+}
+
+pub fn spawn_train(commands: &mut Commands, mut train: Train) {
+    let transform = train.current_transform();
+    commands.spawn((train, transform));
+}
+
+fn move_trains(
+    state: Res<State>,
+    mut trains: Query<(&mut Train, &mut Transform), With<Train>>,
+    mut rail_usage_changed: EventWriter<RailUsageChanged>,
+) {
+    let time_passed_seconds = state.config.tick_seconds();
+
+    for (mut train, mut transform) in trains.iter_mut() {
+        if let Some(rail) = train.update(&mut transform, time_passed_seconds, &state) {
+            rail_usage_changed.write(RailUsageChanged { rail });
+        }
+    }
+}
+
+/// Captures the current session, see `SaveGame`.
+pub fn snapshot_session<'a>(
+    state: &State,
+    dijkstra_communication: &DijkstraCommunication,
+    cities: impl Iterator<Item = &'a Position>,
+    trains: impl Iterator<Item = &'a Train>,
+) -> SaveGame {
+    SaveGame {
+        config: state.config.clone(),
+        cities: cities.map(|pos| pos.gridpoint).collect(),
+        rails: state
+            .rails
+            .rails
+            .iter()
+            .map(|(&rail, info)| (rail, info.counter.load(Ordering::Relaxed)))
+            .collect(),
+        trains: trains.cloned().collect(),
+        expansion_rng: state.expansion_rng.clone(),
+        tick: state.tick,
+        create_new_city_next: state.create_new_city_next,
+        max_rail_usage: state.max_rail_usage.load(Ordering::Relaxed),
+        dijkstra_task: dijkstra_communication
+            .task
+            .map(|(start, end)| (start, end, dijkstra_communication.ready_tick)),
+        dijkstra_queue: dijkstra_communication.queue.iter().copied().collect(),
+    }
+}
+
+/// Replaces the current session, whose cities and trains are `entities`, with `save`. The
+/// built edges of the graph are restored from the saved rails.
+pub fn restore_session(
+    commands: &mut Commands,
+    state: &mut State,
+    dijkstra_communication: &mut DijkstraCommunication,
+    network_planning: &NetworkPlanning,
+    entities: impl Iterator<Item = Entity>,
+    save: SaveGame,
+) -> Result<(), String> {
+    if dijkstra_communication.task.is_some() {
+        return Err("Dijkstra is busy".to_string());
+    }
+    if network_planning.task.is_some() {
+        return Err("a network is being planned".to_string());
+    }
+    if save.config.world_hash() != state.config.world_hash() {
+        return Err("the save was played on a different globe".to_string());
+    }
+
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let Ok(mut globe_points) = globe_points_lock.write() else {
+        return Err("failed to lock globe points".to_string());
+    };
+    if globe_points.points.is_empty() {
+        return Err("the globe is not ready yet".to_string());
+    }
+
+    // Remove the current session.
+    for entity in entities {
+        commands.entity(entity).despawn();
+    }
+    for (_, info) in state.rails.rails.drain() {
+        commands.entity(info.entity).despawn();
+    }
+    globe_points.clear_built();
+
+    for &(rail, counter) in &save.rails {
+        globe_points.mark_built(&[rail.from, rail.to]);
+        let (Some(from_point), Some(to_point)) = (
+            globe_points.points.get(&rail.from),
+            globe_points.points.get(&rail.to),
+        ) else {
+            continue;
+        };
+        let (mid_point, length, rotation) = rail_placement(from_point.pos, to_point.pos);
+        let entity = spawn_rail(commands, rail, mid_point, length, rotation);
+        state.rails.rails.insert(
+            rail,
+            RailInfo {
+                entity,
+                counter: counter.into(),
+            },
+        );
+    }
+    for city in save.cities {
+        if let Some(&globe_point) = globe_points.points.get(&city) {
+            spawn_city(commands, city, globe_point);
+        }
+    }
+    for train in save.trains {
+        spawn_train(commands, train);
+    }
+    drop(globe_points);
+
+    state.config = save.config;
+    state.expansion_rng = save.expansion_rng;
+    state.tick = save.tick;
+    state.create_new_city_next = save.create_new_city_next;
+    state.max_rail_usage = save.max_rail_usage.into();
+    dijkstra_communication.queue = save.dijkstra_queue.into();
+    if let Some((start, end, ready_tick)) = save.dijkstra_task {
+        search_path(
+            dijkstra_communication,
+            &state.globe_points,
+            state.config.track_limits(),
+            state.config.cost_model(),
+            start,
+            end,
+            ready_tick,
+        );
+    }
+    Ok(())
+}
+
+/// Summary of the rail network, printed at the end of a headless run.
+pub struct NetworkStats {
+    pub cities: usize,
+    pub trains: usize,
+    pub rails: usize,
+    pub rail_length: f32,
+    pub total_usage: usize,
+    pub max_usage: usize,
+    pub unused_rails: usize,
+}
+
+impl NetworkStats {
+    pub fn collect(world: &mut World) -> Self {
+        let cities = world.query_filtered::<(), With<City>>().iter(world).count();
+        let trains = world.query::<&Train>().iter(world).count();
+        let state = world.resource::<State>();
+        let globe_points = state.globe_points.read().unwrap();
+        let mut stats = NetworkStats {
+            cities,
+            trains,
+            rails: state.rails.rails.len(),
+            rail_length: 0.0,
+            total_usage: 0,
+            max_usage: 0,
+            unused_rails: 0,
+        };
+        for (rail, info) in &state.rails.rails {
+            if let (Some(from), Some(to)) = (
+                globe_points.points.get(&rail.from),
+                globe_points.points.get(&rail.to),
+            ) {
+                stats.rail_length += from.pos.distance(to.pos);
+            }
+            let usage = info.counter.load(Ordering::Relaxed);
+            stats.total_usage += usage;
+            stats.max_usage = stats.max_usage.max(usage);
+            if usage == 0 {
+                stats.unused_rails += 1;
+            }
+        }
+        stats
+    }
+}
+
+impl std::fmt::Display for NetworkStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "cities:        {}", self.cities)?;
+        writeln!(f, "trains:        {}", self.trains)?;
+        writeln!(f, "rail pieces:   {}", self.rails)?;
+        writeln!(f, "rail length:   {:.2}", self.rail_length)?;
+        writeln!(f, "unused pieces: {}", self.unused_rails)?;
+        writeln!(f, "total usage:   {}", self.total_usage)?;
+        writeln!(
+            f,
+            "mean usage:    {:.2}",
+            self.total_usage as f32 / self.rails.max(1) as f32
+        )?;
+        write!(f, "max usage:     {}", self.max_usage)
+    }
+}
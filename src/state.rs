@@ -2,23 +2,44 @@ use std::collections::HashMap;
 use std::sync::atomic::AtomicUsize;
 use std::sync::{Arc, RwLock};
 
-use crate::dijkstra::{GlobePoints, GridPoint};
+use crate::biome;
+use crate::cost_model::{CostModel, CostModelConfig, TerrainCost};
+use crate::dijkstra::{EdgeKind, GlobePoints, GridPoint, TrackLimits};
+use crate::erosion;
+use crate::globe_chunks;
+use crate::heightmap;
 use crate::perlin;
+use crate::structures;
 
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub grid_size: u32,
     pub sea_level: f32,  // sea level for the globe
-    pub snow_level: f32, // snow level above sea level
+    pub snow_level: f32, // snow level above sea level on the equator
     pub perlin_config: perlin::PerlinConfig,
+    pub mountain_config: Option<perlin::PerlinConfig>, // noise added on the higher ground
+    pub heightmap: Option<heightmap::HeightmapConfig>, // images used instead of `perlin_config`
+    pub erosion_config: erosion::ErosionConfig,
+    pub biome_config: biome::BiomeConfig,
     pub water_penalty: f32,
-    pub snow_penalty: f32,
+    pub river_threshold: f32, // fraction of the globe that has to drain through a river
+    pub bridge_penalty: f32,  // cost of an edge crossing a river
+    pub structure_config: structures::StructureConfig,
     pub min_city_distance: f32,
     pub reduction_factor: f32, // cost reduction factor for reused edges
     pub climbing_cost: f32,
+    pub max_grade: f32,            // steepest rail, height over length
+    pub min_turn_radius: f32,      // tightest curve of a rail, 0 allows any turn
+    pub network_connectivity: u32, // disjoint paths between cities of `plan_network`
+    pub route_alternatives: u32,   // routes shown when connecting two cities
     pub num_automatic_trains: i32, // number of automatic trains to spawn
+    pub tick_rate: u32,            // simulation ticks per second
+    pub cost_model_config: CostModelConfig,
+    pub lod_config: globe_chunks::LodConfig,
 }
 
 impl Default for Config {
@@ -27,28 +48,105 @@ impl Default for Config {
             grid_size: 256,
             sea_level: 4.95,
             snow_level: 0.5,
-            perlin_config: perlin::PerlinConfig {
-                seed: 485,
-                frequency: 3.0,
-                lacunarity: 1.57,
-                persistence: 0.5,
-                octaves: 6,
-                amplitude: 0.75,
-            },
+            perlin_config: perlin::PerlinConfig::default(),
+            mountain_config: None,
+            heightmap: None,
+            erosion_config: erosion::ErosionConfig::default(),
+            biome_config: biome::BiomeConfig::default(),
             water_penalty: 5.0,
-            snow_penalty: 3.0,
+            river_threshold: 0.002,
+            bridge_penalty: 0.5,
+            structure_config: structures::StructureConfig::default(),
             min_city_distance: 1.0,
             reduction_factor: 2.0, // default reduction factor
             climbing_cost: 5.0,
+            max_grade: 0.3,
+            min_turn_radius: 0.05,
+            network_connectivity: 1,
+            route_alternatives: 3,
             num_automatic_trains: 250,
+            tick_rate: 60,
+            cost_model_config: CostModelConfig::default(),
+            lod_config: globe_chunks::LodConfig::default(),
         }
     }
 }
 
+impl Config {
+    /// Simulated seconds per tick.
+    pub fn tick_seconds(&self) -> f32 {
+        1.0 / self.tick_rate as f32
+    }
+
+    pub fn track_limits(&self) -> TrackLimits {
+        TrackLimits {
+            max_grade: self.max_grade,
+            min_turn_radius: self.min_turn_radius,
+        }
+    }
+
+    /// Cost model of the path finding. It is not part of the world, so it can be changed
+    /// without rebuilding the graph.
+    pub fn cost_model(&self) -> Box<dyn CostModel> {
+        self.cost_model_config.cost_model(self.terrain_cost())
+    }
+
+    /// The `terrain` cost model with the parameters of this config, whatever the configured one.
+    pub fn terrain_cost(&self) -> TerrainCost {
+        TerrainCost {
+            reduction_factor: self.reduction_factor,
+            length_exponent: self.cost_model_config.length_exponent,
+            climbing_cost: self.climbing_cost,
+            bridge_penalty: self.bridge_penalty,
+            structures: self.structure_config,
+        }
+    }
+
+    /// Hash of the fields that determine the generated globe (terrain, mesh and graph).
+    /// Uses FNV-1a so that the value is stable across builds and can be stored on disk.
+    pub fn world_hash(&self) -> u64 {
+        // Editing the heightmap images also regenerates the globe.
+        let heightmap_files: Vec<_> = self
+            .heightmap
+            .iter()
+            .flat_map(|heightmap| heightmap.paths())
+            .map(|path| {
+                let metadata = std::fs::metadata(path).ok()?;
+                Some((metadata.len(), metadata.modified().ok()))
+            })
+            .collect();
+        let world = format!(
+            "{:?}",
+            (
+                self.grid_size,
+                self.sea_level,
+                self.snow_level,
+                (
+                    self.perlin_config,
+                    self.mountain_config,
+                    &self.heightmap,
+                    heightmap_files,
+                ),
+                self.erosion_config,
+                self.biome_config,
+                self.water_penalty,
+                self.river_threshold,
+                // The costs of tunnels and bridges are up to the cost model.
+                self.structure_config.max_span,
+                self.structure_config.clearance,
+            )
+        );
+        world.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Rail {
     pub from: GridPoint,
     pub to: GridPoint,
+    pub kind: EdgeKind, // kind of the graph edge the rail was built on
 }
 
 pub struct RailInfo {
@@ -67,7 +165,24 @@ pub struct State {
     pub globe_points: Arc<RwLock<GlobePoints>>,
     pub config: Config,
     pub rails: Rails,
-    pub rng: rand::rngs::StdRng,
+    // Only drawn from by the automatic expansion, so that player actions don't change its course.
+    // Same generator as `StdRng`, but its state can be saved.
+    pub expansion_rng: rand_chacha::ChaCha12Rng,
+    pub tick: u64, // simulation ticks run so far
     pub create_new_city_next: bool,
     pub max_rail_usage: AtomicUsize,
 }
+
+impl State {
+    pub fn new(config: Config, globe_points: GlobePoints) -> Self {
+        State {
+            expansion_rng: rand::SeedableRng::seed_from_u64(config.perlin_config.seed as u64),
+            tick: 0,
+            config,
+            globe_points: Arc::new(RwLock::new(globe_points)),
+            rails: Rails::default(),
+            create_new_city_next: true,
+            max_rail_usage: 0.into(),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::dijkstra::{EdgeKind, GridPoints, NodeIndex, STEPS};
+use crate::state::Rail;
+
+use serde::{Deserialize, Serialize};
+
+/// Tunnels and bridges, edges longer than `STEPS` that run straight through high terrain or
+/// over valleys and water instead of following the ground.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StructureConfig {
+    pub max_span: u32,    // in grid steps, at most `STEPS` disables tunnels and bridges
+    pub clearance: f32,   // how far the terrain must rise above or fall below the track
+    pub tunnel_cost: f32, // cost per unit of length
+    pub bridge_cost: f32, // cost per unit of length
+    pub pier_cost: f32,   // cost per unit of length and of mean height above the ground
+}
+
+impl Default for StructureConfig {
+    fn default() -> Self {
+        Self {
+            max_span: 24,
+            clearance: 0.05,
+            tunnel_cost: 4.0,
+            bridge_cost: 2.0,
+            pier_cost: 10.0,
+        }
+    }
+}
+
+// Tunnels and bridges run along the axes and diagonals of a face.
+const DIRECTIONS: [(i32, i32); 8] = [
+    (1, 0),
+    (-1, 0),
+    (0, 1),
+    (0, -1),
+    (1, 1),
+    (1, -1),
+    (-1, 1),
+    (-1, -1),
+];
+
+// Spans are tried every this many grid steps beyond `STEPS`.
+const SPAN_STEP: usize = 4;
+
+// Grid steps between the piers of a bridge.
+const PIER_SPACING: i32 = 4;
+
+/// Grid points of the same face that a tunnel or bridge from `from` could reach.
+pub fn span_targets(
+    points: &GridPoints,
+    from: NodeIndex,
+    config: &StructureConfig,
+) -> impl Iterator<Item = NodeIndex> {
+    let (face, i, j) = points.gridpoint(from);
+    let spans = (STEPS + 1..=config.max_span as i32).step_by(SPAN_STEP);
+    DIRECTIONS.into_iter().flat_map(move |(di, dj)| {
+        spans.clone().filter_map(move |span| {
+            let (row, col) = (i as i32 + di * span, j as i32 + dj * span);
+            if row < 0 || col < 0 {
+                return None;
+            }
+            points.index(&(face, row as u32, col as u32))
+        })
+    })
+}
+
+/// Kind and gap of a tunnel or bridge from `from` to `to`, if one can be built between them.
+///
+/// Both ends need to be on land in the same face, along an axis or a diagonal and more than
+/// `STEPS` apart. The track runs at heights interpolated between the ends. A tunnel goes where
+/// the terrain rises at least `clearance` above the track and nowhere falls below it by as much,
+/// a bridge the other way round. The gap of a bridge is the mean height of the track above the
+/// ground, which its piers make up, and 0 for a tunnel.
+pub fn structure(
+    points: &GridPoints,
+    from: NodeIndex,
+    to: NodeIndex,
+    config: &StructureConfig,
+) -> Option<(EdgeKind, f32)> {
+    let (a, b) = (points.gridpoint(from), points.gridpoint(to));
+    let (di, dj) = (b.1 as i32 - a.1 as i32, b.2 as i32 - a.2 as i32);
+    let span = di.abs().max(dj.abs());
+    if a.0 != b.0 || span <= STEPS || (di != 0 && dj != 0 && di.abs() != dj.abs()) {
+        return None;
+    }
+    let (p, q) = (points[from], points[to]);
+    if p.water || q.water {
+        return None;
+    }
+
+    let (p_height, q_height) = (p.pos.length(), q.pos.length());
+    let (mut rise, mut dip, mut gap) = (0.0f32, 0.0f32, 0.0);
+    for k in 1..span {
+        let row = (a.1 as i32 + di.signum() * k) as u32;
+        let col = (a.2 as i32 + dj.signum() * k) as u32;
+        let ground = points.get(&(a.0, row, col))?.pos.length();
+        let track = p_height + (q_height - p_height) * k as f32 / span as f32;
+        rise = rise.max(ground - track);
+        dip = dip.max(track - ground);
+        gap += (track - ground).max(0.0);
+    }
+
+    if rise > config.clearance && dip < config.clearance {
+        Some((EdgeKind::Tunnel, 0.0))
+    } else if dip > config.clearance && rise < config.clearance {
+        Some((EdgeKind::Bridge, gap / (span - 1) as f32))
+    } else {
+        None
+    }
+}
+
+/// Piers under a bridge, as the fraction of the way from `rail.from` to `rail.to` and the
+/// height of the straight deck above the ground there. Short bridges over rivers have none.
+pub fn piers(points: &GridPoints, rail: Rail) -> Vec<(f32, f32)> {
+    let (a, b) = (rail.from, rail.to);
+    let (di, dj) = (b.1 as i32 - a.1 as i32, b.2 as i32 - a.2 as i32);
+    let span = di.abs().max(dj.abs());
+    let (Some(p), Some(q)) = (points.get(&a), points.get(&b)) else {
+        return Vec::new();
+    };
+    if rail.kind != EdgeKind::Bridge || a.0 != b.0 || span <= STEPS {
+        return Vec::new();
+    }
+    (PIER_SPACING..span)
+        .step_by(PIER_SPACING as usize)
+        .filter_map(|k| {
+            let row = (a.1 as i32 + di.signum() * k) as u32;
+            let col = (a.2 as i32 + dj.signum() * k) as u32;
+            let ground = points.get(&(a.0, row, col))?.pos.length();
+            let t = k as f32 / span as f32;
+            let deck = p.pos.lerp(q.pos, t).length();
+            (deck > ground).then_some((t, deck - ground))
+        })
+        .collect()
+}
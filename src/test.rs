@@ -1,4 +1,44 @@
-use crate::dijkstra::GridPoint;
+use crate::alternatives::alternative_routes;
+use crate::biome::Biome;
+use crate::config_file::{ConfigError, config_from_query, config_from_str, parse_args};
+use crate::cost_model::{
+    ConstructionOperatingCost, CostModel, CostModelConfig, CostModelKind, DistanceCost, TerrainCost,
+};
+use crate::dijkstra::{
+    EdgeKind, GlobePoint, GlobePoints, GridPoint, GridPoints, NodeIndex, TrackLimits, astar,
+    bidirectional_astar, bidirectional_dijkstra, crosses_river, cubic, dijkstra, route,
+    shared_points,
+};
+use crate::erosion::{ErosionConfig, erode};
+use crate::geojson_file::{direction, export_network, load_network, lon_lat};
+use crate::globe_chunks::{ChunkKey, GlobeChunks, LodConfig};
+use crate::globe_file::{load_globe, save_globe};
+use crate::gltf_export::export_world;
+use crate::heightmap::{Heightmap, HeightmapConfig};
+use crate::hydrology::drain;
+use crate::layer_export::{Layer, Projection, export_layers, layer_values};
+use crate::meshes_materials::{RAIL_USAGE_LEVELS, make_globe, rail_usage_level};
+use crate::network_planner::plan_network;
+use crate::noise::{NoiseKind, NoiseSource, Terrain, noise_source};
+use crate::perlin::{Perlin, PerlinConfig};
+use crate::save_file::SaveGame;
+use crate::simulation::{
+    City, DijkstraCommunication, NetworkStats, PlayerAction, PlayerActions, Position, SimSpeed,
+    SimulationPlugin, seed_cities,
+};
+use crate::state::{Config, Rail, RailInfo, State};
+use crate::structures::{StructureConfig, piers, structure};
+use crate::train::Train;
+use bevy::math::Vec3;
+use bevy::prelude::{App, Entity, MinimalPlugins};
+use bevy::render::mesh::{Mesh, VertexAttributeValues};
+use bevy::time::TimeUpdateStrategy;
+use bevy::transform::components::Transform;
+use image::{ImageBuffer, Luma};
+use rand::{Rng, SeedableRng};
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 #[test]
 fn test_grid_point() {
@@ -11,3 +51,1567 @@ fn test_grid_point() {
     assert_ne!(point2, point3);
     assert_ne!(point1, point4);
 }
+
+fn small_globe(seed: u32) -> GlobePoints {
+    let mut config = Config {
+        grid_size: 16,
+        ..Config::default()
+    };
+    config.perlin_config.seed = seed;
+    make_globe(&config).unwrap().0
+}
+
+// The cost model of the default config.
+const TERRAIN: TerrainCost = TerrainCost {
+    reduction_factor: 2.0,
+    length_exponent: 0.9,
+    climbing_cost: 5.0,
+    bridge_penalty: 0.5,
+    structures: StructureConfig {
+        max_span: 24,
+        clearance: 0.05,
+        tunnel_cost: 4.0,
+        bridge_cost: 2.0,
+        pier_cost: 10.0,
+    },
+};
+
+fn path_cost(path: &[GridPoint], globe_points: &GlobePoints, model: &dyn CostModel) -> f32 {
+    path.windows(2)
+        .map(|w| {
+            let from = globe_points.points.index(&w[0]).unwrap();
+            let to = globe_points.points.index(&w[1]).unwrap();
+            let edge = globe_points
+                .edges(from)
+                .iter()
+                .find(|edge| edge.to == to)
+                .unwrap();
+            model.cost(&globe_points.points[from], &globe_points.points[to], edge)
+        })
+        .sum()
+}
+
+#[test]
+fn test_astar_matches_dijkstra() {
+    let globe_points = small_globe(485);
+    let pairs = [
+        ((0, 3, 4), (0, 12, 9)),
+        ((0, 8, 8), (1, 8, 8)),
+        ((2, 0, 5), (5, 16, 11)),
+        ((3, 7, 2), (4, 9, 14)),
+    ];
+    for (start, end) in pairs {
+        let expected = path_cost(
+            &dijkstra(start, end, &globe_points, &TERRAIN),
+            &globe_points,
+            &TERRAIN,
+        );
+        let path = astar(start, end, &globe_points, &TERRAIN);
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&end));
+        assert!((path_cost(&path, &globe_points, &TERRAIN) - expected).abs() < 1e-3 * expected);
+        let (path, cost) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&end));
+        assert!((path_cost(&path, &globe_points, &TERRAIN) - expected).abs() < 1e-3 * expected);
+        assert!((cost - expected).abs() < 1e-3 * expected);
+    }
+}
+
+#[test]
+fn test_astar_after_discount() {
+    let mut globe_points = small_globe(7);
+    let (start, end) = ((0, 1, 1), (3, 10, 10));
+    let path = astar(start, end, &globe_points, &TERRAIN);
+    globe_points.mark_built(&path);
+    let model = TerrainCost {
+        reduction_factor: 4.0,
+        ..TERRAIN
+    };
+    let (start, end) = ((0, 2, 1), (3, 10, 12));
+    let expected = path_cost(
+        &dijkstra(start, end, &globe_points, &model),
+        &globe_points,
+        &model,
+    );
+    let (path, _) = bidirectional_astar(start, end, &globe_points, &model);
+    assert!((path_cost(&path, &globe_points, &model) - expected).abs() < 1e-3 * expected);
+}
+
+fn random_globe(grid_size: u32, seed: u64) -> GlobePoints {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let mut globe_points = GlobePoints::new(grid_size);
+    for face in 0..6 {
+        for i in 0..=grid_size {
+            for j in 0..=grid_size {
+                let x = i as f32 / grid_size as f32 - 0.5;
+                let y = j as f32 / grid_size as f32 - 0.5;
+                let dir = match face {
+                    0 => Vec3::new(x, y, 0.5),
+                    1 => Vec3::new(-x, y, -0.5),
+                    2 => Vec3::new(0.5, y, -x),
+                    3 => Vec3::new(-0.5, y, x),
+                    4 => Vec3::new(x, 0.5, -y),
+                    _ => Vec3::new(x, -0.5, y),
+                };
+                let height: f32 = rng.random_range(-0.1..0.4);
+                globe_points.points.insert(
+                    (face, i, j),
+                    GlobePoint {
+                        pos: dir.normalize() * (5.0 + height.max(0.0)),
+                        water: height <= 0.0,
+                        penalty: if height <= 0.0 {
+                            5.0
+                        } else if height >= 0.3 {
+                            3.0
+                        } else {
+                            1.0
+                        },
+                        river: (0.0..0.02).contains(&height),
+                        ..GlobePoint::default()
+                    },
+                );
+            }
+        }
+    }
+    globe_points.build_graph(&StructureConfig::default());
+    globe_points
+}
+
+#[test]
+fn test_bidirectional_dijkstra_is_optimal() {
+    for seed in 0..4 {
+        let globe_points = random_globe(12, seed);
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed + 100);
+        for _ in 0..5 {
+            let mut random_gridpoint = || {
+                (
+                    rng.random_range(0..6),
+                    rng.random_range(0..=12),
+                    rng.random_range(0..=12),
+                )
+            };
+            let (start, end) = (random_gridpoint(), random_gridpoint());
+            let expected = path_cost(
+                &dijkstra(start, end, &globe_points, &TERRAIN),
+                &globe_points,
+                &TERRAIN,
+            );
+            let (path, cost) = bidirectional_dijkstra(start, end, &globe_points, &TERRAIN);
+            assert_eq!(path.first(), Some(&start));
+            assert_eq!(path.last(), Some(&end));
+            assert!((cost - expected).abs() <= 1e-3 * expected);
+            assert!((path_cost(&path, &globe_points, &TERRAIN) - cost).abs() <= 1e-3 * cost);
+        }
+    }
+}
+
+#[test]
+fn test_node_index_round_trip() {
+    let globe_points = GlobePoints::new(16);
+    for grid in [(0, 0, 0), (2, 16, 3), (5, 16, 16)] {
+        let idx = globe_points.points.index(&grid).unwrap();
+        assert_eq!(globe_points.points.gridpoint(idx), grid);
+    }
+    assert_eq!(globe_points.points.index(&(6, 0, 0)), None);
+    assert_eq!(globe_points.points.index(&(0, 17, 0)), None);
+}
+
+#[test]
+fn test_graph_is_symmetric_across_faces() {
+    let globe_points = random_globe(10, 3);
+    for idx in 0..globe_points.points.len() as NodeIndex {
+        let mut neighbors: Vec<NodeIndex> = globe_points.edges(idx).iter().map(|e| e.to).collect();
+        let count = neighbors.len();
+        neighbors.sort();
+        neighbors.dedup();
+        assert_eq!(neighbors.len(), count, "duplicate edges from {idx}");
+        for to in neighbors {
+            assert!(globe_points.edges(to).iter().any(|e| e.to == idx));
+        }
+    }
+    // A corner point touches three faces.
+    let corner = globe_points.points.index(&(0, 0, 0)).unwrap();
+    let faces: HashSet<u32> = globe_points
+        .edges(corner)
+        .iter()
+        .map(|e| globe_points.points.gridpoint(e.to).0)
+        .collect();
+    assert_eq!(faces.len(), 3);
+}
+
+#[test]
+fn test_globe_file_round_trip() {
+    let config = Config {
+        grid_size: 8,
+        ..Config::default()
+    };
+    let (mut globe_points, mesh) = make_globe(&config).unwrap();
+    globe_points.mark_built(&[(0, 1, 1), (0, 2, 3)]);
+    let path = std::env::temp_dir().join("terrain3d_test_globe_file_round_trip.t3d");
+    save_globe(&path, config.world_hash(), &globe_points, &mesh).unwrap();
+
+    let other_config = Config {
+        sea_level: 4.9,
+        ..config.clone()
+    };
+    assert!(
+        load_globe(&path, other_config.world_hash())
+            .unwrap()
+            .is_none()
+    );
+
+    let (loaded_points, loaded_mesh) = load_globe(&path, config.world_hash()).unwrap().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        loaded_points.min_length_per_radian,
+        globe_points.min_length_per_radian
+    );
+    assert_eq!(loaded_points.max_step_length, globe_points.max_step_length);
+    assert_eq!(loaded_points.min_penalty, globe_points.min_penalty);
+    assert!(loaded_points.has_rails);
+    for idx in 0..globe_points.points.len() as NodeIndex {
+        assert_eq!(loaded_points.points[idx].pos, globe_points.points[idx].pos);
+        assert_eq!(
+            loaded_points.points[idx].water,
+            globe_points.points[idx].water
+        );
+        let (a, b) = (&loaded_points.points[idx], &globe_points.points[idx]);
+        assert_eq!((a.river, a.lake, a.flow), (b.river, b.lake, b.flow));
+        assert_eq!(a.biome, b.biome);
+        let edges = globe_points.edges(idx);
+        let loaded_edges = loaded_points.edges(idx);
+        assert_eq!(loaded_edges.len(), edges.len());
+        for (a, b) in edges.iter().zip(loaded_edges) {
+            assert_eq!(
+                (a.to, a.built, a.kind, a.gap),
+                (b.to, b.built, b.kind, b.gap)
+            );
+        }
+    }
+    assert_eq!(
+        loaded_mesh.attribute(Mesh::ATTRIBUTE_COLOR).unwrap().len(),
+        mesh.attribute(Mesh::ATTRIBUTE_COLOR).unwrap().len()
+    );
+    assert_eq!(
+        loaded_mesh.indices().unwrap().len(),
+        mesh.indices().unwrap().len()
+    );
+
+    // A corrupt graph, grid size or mesh is an error, so that the globe is generated anew,
+    // rather than a panic.
+    save_globe(&path, config.world_hash(), &globe_points, &mesh).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    let n = globe_points.points.len();
+    let offsets_at = 32 + 24 * n + 4; // header, points, offset count
+    let edges_at = offsets_at + 4 * (n + 1) + 4;
+    let corrupt = |at: usize, value: u32| {
+        let mut bytes = bytes.clone();
+        bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        std::fs::write(&path, bytes).unwrap();
+        load_globe(&path, config.world_hash())
+    };
+    assert!(corrupt(offsets_at + 4, u32::MAX).is_err()); // decreasing offsets
+    assert!(corrupt(edges_at, n as u32).is_err()); // edge leaving the globe
+    assert!(corrupt(edges_at, 0).unwrap().is_some());
+    assert!(corrupt(16, u32::MAX).is_err()); // grid size
+    assert!(corrupt(bytes.len() - 4, u32::MAX).is_err()); // last mesh index
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_train_moves_smoothly() {
+    let rail = Rail {
+        from: (0, 1, 1),
+        to: (0, 2, 1),
+        kind: EdgeKind::Surface,
+    };
+    let mut state = State::new(Config::default(), GlobePoints::default());
+    state.rails.rails.insert(
+        rail,
+        RailInfo {
+            entity: Entity::PLACEHOLDER,
+            counter: 0.into(),
+        },
+    );
+    // Right angle turns at every rail.
+    let corners = [(0.0, 0.0), (0.5, 0.0), (0.5, 0.5), (1.0, 0.5), (1.0, 1.0)];
+    let transforms = corners
+        .map(|(x, y)| {
+            let pos = Vec3::new(x, y, 5.0).normalize() * 5.0;
+            (Transform::from_translation(pos), rail)
+        })
+        .to_vec();
+    let mut train = Train::new(transforms).unwrap();
+
+    let mut transform = Transform::default();
+    let mut positions = Vec::new();
+    let mut axes = Vec::new();
+    for _ in 0..250 {
+        train.update(&mut transform, 0.07, &state);
+        positions.push(transform.translation);
+        axes.push(transform.rotation * Vec3::Y);
+    }
+    for (i, step) in positions.windows(2).enumerate() {
+        let motion = step[1] - step[0];
+        assert!(motion.length() < 0.015);
+        // Never backwards, and facing where it goes.
+        if i > 0 {
+            assert!(motion.dot(positions[i] - positions[i - 1]) > 0.0);
+        }
+        assert!(axes[i + 1].dot(motion.normalize()).abs() > 0.99);
+    }
+}
+
+#[test]
+fn test_save_game_round_trip() {
+    let mut rng = rand_chacha::ChaCha12Rng::seed_from_u64(485);
+    let _: u32 = rng.random();
+    let rail = Rail {
+        from: (0, 1, 1),
+        to: (0, 2, 3),
+        kind: EdgeKind::Bridge,
+    };
+    let transforms = vec![
+        (Transform::from_xyz(1.0, 2.0, 3.0), rail),
+        (Transform::from_xyz(1.5, 2.0, 3.0), rail),
+    ];
+    let mut train = Train::new(transforms).unwrap();
+    train.seconds_spent_within_segment = 0.25;
+    let save = SaveGame {
+        config: Config {
+            tick_rate: 30,
+            ..Config::default()
+        },
+        cities: vec![(0, 1, 1), (0, 2, 3)],
+        rails: vec![(rail, 7)],
+        trains: vec![train],
+        expansion_rng: rng.clone(),
+        tick: 1234,
+        create_new_city_next: false,
+        max_rail_usage: 7,
+        dijkstra_task: Some(((0, 2, 3), (4, 5, 6), 1249)),
+        dijkstra_queue: vec![((1, 0, 0), (2, 3, 4))],
+    };
+    let path = std::env::temp_dir().join("terrain3d_test_save_game_round_trip.t3s");
+    save.save(&path).unwrap();
+    let mut loaded = SaveGame::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(loaded.config.world_hash(), save.config.world_hash());
+    assert_eq!(loaded.config.tick_rate, 30);
+    assert_eq!(loaded.tick, 1234);
+    assert_eq!(loaded.cities, save.cities);
+    assert_eq!(loaded.rails.len(), 1);
+    assert!(loaded.rails[0].0 == rail && loaded.rails[0].1 == 7);
+    let loaded_train = &loaded.trains[0];
+    assert_eq!(loaded_train.transforms[1].0, save.trains[0].transforms[1].0);
+    assert_eq!(loaded_train.seconds_spent_within_segment, 0.25);
+    assert_eq!(loaded_train.segment_duration, None);
+    assert!(!loaded.create_new_city_next);
+    assert_eq!(loaded.dijkstra_task, save.dijkstra_task);
+    assert_eq!(loaded.dijkstra_queue, save.dijkstra_queue);
+    assert_eq!(loaded.expansion_rng.random::<u64>(), rng.random::<u64>());
+}
+
+#[test]
+fn test_config_file_and_overrides() {
+    let text = "
+        grid_size = 128
+        sea_level = 5
+
+        [perlin_config]
+        seed = 7
+    ";
+    let overrides = [
+        ("perlin_config.octaves".to_string(), "4".to_string()),
+        ("water_penalty".to_string(), "2.5".to_string()),
+    ];
+    let config = config_from_str(text, &overrides).unwrap();
+    assert_eq!(config.grid_size, 128);
+    assert_eq!(config.sea_level, 5.0);
+    assert_eq!(config.perlin_config.seed, 7);
+    assert_eq!(config.perlin_config.octaves, 4);
+    assert_eq!(config.water_penalty, 2.5);
+    assert_eq!(
+        config.biome_config.swamp_penalty,
+        Config::default().biome_config.swamp_penalty
+    );
+
+    let args = [
+        "--grid-size",
+        "64",
+        "--perlin_config.seed=3",
+        "--config",
+        "a.toml",
+    ];
+    let (path, overrides) = parse_args(&args.map(String::from)).unwrap();
+    assert_eq!(path.as_deref(), Some("a.toml"));
+    let config = config_from_str("", &overrides).unwrap();
+    assert_eq!((config.grid_size, config.perlin_config.seed), (64, 3));
+
+    let config = config_from_query("?grid_size=32&climbing_cost=1").unwrap();
+    assert_eq!((config.grid_size, config.climbing_cost), (32, 1.0));
+}
+
+#[test]
+fn test_config_validation() {
+    assert!(Config::default().validate().is_ok());
+    let invalid = |text: &str| matches!(config_from_str(text, &[]), Err(ConfigError::Invalid(_)));
+    assert!(invalid("[perlin_config]\noctaves = 0"));
+    assert!(invalid("grid_size = 10"));
+    assert!(invalid("grid_size = 1000000"));
+    assert!(invalid("water_penalty = -1.0"));
+    assert!(invalid("reduction_factor = 0.5"));
+    assert!(invalid("[biome_config]\nswamp_penalty = 0.0"));
+    assert!(matches!(
+        config_from_str("grid_sise = 64", &[]),
+        Err(ConfigError::Parse(_))
+    ));
+    assert!(matches!(
+        parse_args(&["--grid_size".to_string()]),
+        Err(ConfigError::Usage(_))
+    ));
+}
+
+// Runs the simulation headless for `ticks` ticks, applying each action at its tick.
+fn run_simulation(ticks: u64, speed: u32, actions: Vec<(u64, PlayerAction)>) -> App {
+    let config = Config {
+        grid_size: 16,
+        num_automatic_trains: 3,
+        tick_rate: 20,
+        ..Config::default()
+    };
+    let (globe_points, _) = make_globe(&config).unwrap();
+    let mut app = App::new();
+    app.add_plugins((MinimalPlugins, SimulationPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            50,
+        )))
+        .insert_resource(State::new(config, globe_points));
+    seed_cities(app.world_mut(), 2);
+    let mut actions = actions.into_iter().peekable();
+    while app.world().resource::<State>().tick < ticks {
+        let tick = app.world().resource::<State>().tick;
+        while let Some((_, action)) = actions.next_if(|(at, _)| *at <= tick) {
+            app.world_mut()
+                .resource_mut::<PlayerActions>()
+                .0
+                .push(action);
+        }
+        // End the frame at the next action, so that it lands on its tick at any speed.
+        let until = actions.peek().map_or(ticks, |&(at, _)| at.min(ticks));
+        app.world_mut().resource_mut::<SimSpeed>().0 = speed.min((until - tick) as u32);
+        app.update();
+    }
+    app
+}
+
+#[test]
+fn test_headless_simulation() {
+    let mut app = run_simulation(1200, 1, Vec::new());
+    let stats = NetworkStats::collect(app.world_mut());
+    // The first path plus one per automatic train, and a new city every other train.
+    assert_eq!(stats.trains, 4);
+    assert_eq!(stats.cities, 4);
+    assert!(stats.rails > 0);
+    assert!(stats.total_usage > 0);
+}
+
+#[test]
+fn test_simulation_is_deterministic() {
+    let actions = || {
+        vec![
+            (10, PlayerAction::PlaceCity((1, 8, 8))),
+            (200, PlayerAction::PlaceCity((3, 4, 12))),
+            (300, PlayerAction::PlaceCity((5, 3, 3))),
+            (400, PlayerAction::Connect((1, 8, 8), (3, 4, 12))),
+            (400, PlayerAction::Connect((1, 8, 8), (5, 3, 3))),
+            // Waits for the path searches, which must not see it come and go with their speed.
+            (
+                402,
+                PlayerAction::Build(vec![(1, 8, 8), (1, 8, 9), (1, 8, 10)]),
+            ),
+            (590, PlayerAction::PlaceCity((2, 8, 8))),
+            (600, PlayerAction::PlanNetwork),
+        ]
+    };
+    let app = run_simulation(403, 16, actions());
+    let world = app.world();
+    assert!(world.resource::<DijkstraCommunication>().task.is_some());
+    assert!(matches!(
+        world.resource::<PlayerActions>().0[..],
+        [PlayerAction::Build(_)]
+    ));
+    let outcome = || {
+        let mut app = run_simulation(800, 16, actions());
+        let world = app.world_mut();
+        let cities: Vec<GridPoint> = world
+            .query_filtered::<&Position, bevy::prelude::With<City>>()
+            .iter(world)
+            .map(|position| position.gridpoint)
+            .collect();
+        let mut trains: Vec<Vec3> = world
+            .query_filtered::<&Transform, bevy::prelude::With<Train>>()
+            .iter(world)
+            .map(|transform| transform.translation)
+            .collect();
+        trains.sort_by(|a, b| a.to_array().partial_cmp(&b.to_array()).unwrap());
+        let state = world.resource::<State>();
+        let mut rails: Vec<(GridPoint, GridPoint, usize)> = state
+            .rails
+            .rails
+            .iter()
+            .map(|(rail, info)| (rail.from, rail.to, info.counter.load(Ordering::Relaxed)))
+            .collect();
+        rails.sort();
+        assert!(
+            rails
+                .iter()
+                .any(|&(from, to, _)| (from, to) == ((1, 8, 9), (1, 8, 10)))
+        );
+        // The planned network reaches every city, also the last one.
+        for city in cities {
+            assert!(
+                rails
+                    .iter()
+                    .any(|&(from, to, _)| [from, to].contains(&city))
+            );
+        }
+        (trains, rails)
+    };
+    let (trains, rails) = outcome();
+    assert!(!trains.is_empty());
+    // One train per path: the seeded connection, the 3 automatic ones and both of the player's,
+    // the second of which waited for the first rather than being dropped.
+    assert_eq!(trains.len(), 6);
+    assert!(rails.iter().any(|&(_, _, count)| count > 0));
+    assert_eq!(outcome(), (trains, rails));
+}
+
+#[test]
+fn test_rail_usage_level() {
+    assert_eq!(rail_usage_level(0, 0), 0);
+    assert_eq!(rail_usage_level(0, 10), 0);
+    assert_eq!(rail_usage_level(10, 10), RAIL_USAGE_LEVELS - 1);
+    // A stale maximum never indexes past the palette.
+    assert_eq!(rail_usage_level(12, 10), RAIL_USAGE_LEVELS - 1);
+    let levels: Vec<usize> = (0..=10).map(|count| rail_usage_level(count, 10)).collect();
+    assert!(levels.windows(2).all(|w| w[0] <= w[1]));
+}
+
+#[test]
+fn test_erosion_is_deterministic_and_seamless() {
+    let grid_size = 32;
+    let m = grid_size + 1;
+    let perlin = Perlin::new(PerlinConfig::default());
+    let mut heights = Vec::new();
+    for face in 0..6 {
+        for row in 0..m {
+            for col in 0..m {
+                let [x, y, z] = cubic((face, row, col), grid_size);
+                let dir =
+                    Vec3::new(x as f32, y as f32, z as f32) - Vec3::splat(grid_size as f32 / 2.0);
+                let dir = dir.normalize();
+                heights.push(5.0 + perlin.noise(dir.x, dir.y, dir.z));
+            }
+        }
+    }
+    let config = ErosionConfig {
+        iterations: 5000,
+        ..ErosionConfig::default()
+    };
+    let mut eroded = heights.clone();
+    erode(&mut eroded, grid_size, &config);
+    let mut again = heights.clone();
+    erode(&mut again, grid_size, &config);
+    assert_eq!(eroded, again);
+
+    let changed = heights.iter().zip(&eroded).filter(|(a, b)| a != b).count();
+    assert!(
+        changed > heights.len() / 10,
+        "only {changed} heights changed"
+    );
+    assert!(eroded.iter().all(|h| h.is_finite()));
+
+    // Grid points shared by adjacent faces keep equal heights.
+    let mut seen = std::collections::HashMap::new();
+    for face in 0..6 {
+        for row in 0..m {
+            for col in 0..m {
+                let height = eroded[((face * m + row) * m + col) as usize];
+                let first = *seen
+                    .entry(cubic((face, row, col), grid_size))
+                    .or_insert(height);
+                assert_eq!(first, height);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_drainage() {
+    let grid_size = 32;
+    let m = grid_size + 1;
+    let sea_level = 4.95;
+    let perlin = Perlin::new(PerlinConfig::default());
+    let mut heights = Vec::new();
+    for face in 0..6 {
+        for row in 0..m {
+            for col in 0..m {
+                let [x, y, z] = cubic((face, row, col), grid_size);
+                let dir = (Vec3::new(x as f32, y as f32, z as f32)
+                    - Vec3::splat(grid_size as f32 / 2.0))
+                .normalize();
+                heights.push(5.0 + perlin.noise(dir.x, dir.y, dir.z));
+            }
+        }
+    }
+    let drainage = drain(&heights, grid_size, sea_level);
+
+    let shared = shared_points(grid_size);
+    let land = (0..heights.len())
+        .filter(|&idx| shared[idx] as usize == idx && heights[idx] > sea_level)
+        .count() as f32
+        / shared
+            .iter()
+            .enumerate()
+            .filter(|&(idx, &first)| idx == first as usize)
+            .count() as f32;
+    let mut lakes = 0;
+    for idx in 0..heights.len() {
+        assert!(drainage.level[idx] >= heights[idx]);
+        assert!(drainage.flow[idx] <= land * 1.0001);
+        if heights[idx] <= sea_level {
+            assert!(!drainage.is_lake(&heights, idx));
+        } else {
+            // Rain falls on every land point.
+            assert!(drainage.flow[idx] > 0.0);
+        }
+        if drainage.is_lake(&heights, idx) {
+            lakes += 1;
+        }
+        let first = shared[idx] as usize;
+        assert_eq!(drainage.level[idx], drainage.level[first]);
+        assert_eq!(drainage.flow[idx], drainage.flow[first]);
+    }
+    assert!(lakes > 0);
+    // Some rivers collect the rain of many points.
+    assert!(
+        drainage
+            .flow
+            .iter()
+            .any(|&flow| flow > 20.0 / heights.len() as f32)
+    );
+}
+
+#[test]
+fn test_crosses_river() {
+    let mut points = GridPoints::new(8);
+    for col in 0..=8 {
+        points.insert(
+            (0, 4, col),
+            GlobePoint {
+                river: true,
+                ..GlobePoint::default()
+            },
+        );
+    }
+    let edge = |from, to| {
+        crosses_river(
+            &points,
+            points.index(&from).unwrap(),
+            points.index(&to).unwrap(),
+        )
+    };
+    assert!(edge((0, 2, 3), (0, 6, 3)));
+    assert!(edge((0, 3, 1), (0, 5, 2)));
+    // Along the river, from its bank onto it, and beside it.
+    assert!(!edge((0, 4, 1), (0, 4, 5)));
+    assert!(!edge((0, 2, 3), (0, 4, 3)));
+    assert!(!edge((0, 1, 1), (0, 3, 6)));
+}
+
+#[test]
+fn test_biomes() {
+    assert_eq!(Biome::classify(-0.1, 0.5), Biome::Ice);
+    assert_eq!(Biome::classify(0.1, 0.9), Biome::Tundra);
+    assert_eq!(Biome::classify(0.9, 0.1), Biome::Desert);
+    assert_eq!(Biome::classify(0.4, 0.1), Biome::Grassland);
+    assert_eq!(Biome::classify(0.9, 0.5), Biome::Grassland);
+    assert_eq!(Biome::classify(0.4, 0.9), Biome::Forest);
+    assert_eq!(Biome::classify(0.9, 0.9), Biome::Swamp);
+
+    let config = Config {
+        grid_size: 32,
+        ..Config::default()
+    };
+    let (globe_points, _) = make_globe(&config).unwrap();
+    let mut counts = [0; 6];
+    for idx in 0..globe_points.points.len() as NodeIndex {
+        let point = &globe_points.points[idx];
+        if !point.water {
+            counts[Biome::ALL.iter().position(|&b| b == point.biome).unwrap()] += 1;
+            assert_eq!(point.penalty, point.biome.penalty(&config.biome_config));
+        }
+    }
+    assert!(counts.iter().all(|&count| count > 0), "{counts:?}");
+}
+
+#[test]
+fn test_noise_sources() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    let dirs: Vec<Vec3> = (0..500)
+        .map(|_| {
+            Vec3::new(
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..1.0),
+            )
+            .normalize()
+        })
+        .collect();
+    let sample = |source: &dyn NoiseSource| -> Vec<f32> {
+        dirs.iter().map(|d| source.noise(d.x, d.y, d.z)).collect()
+    };
+    let config = PerlinConfig::default();
+    let perlin = sample(&Perlin::new(config));
+    assert_eq!(sample(noise_source(&config).as_ref()), perlin);
+    for kind in [
+        NoiseKind::Simplex,
+        NoiseKind::Ridged,
+        NoiseKind::Billow,
+        NoiseKind::Warped,
+    ] {
+        let config = PerlinConfig { kind, ..config };
+        let values = sample(noise_source(&config).as_ref());
+        assert_eq!(values, sample(noise_source(&config).as_ref()));
+        assert_ne!(values, perlin, "{kind:?}");
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(mean.abs() < 0.1, "{kind:?} averages {mean}");
+        assert!(values.iter().all(|v| v.abs() < 2.0 * config.amplitude));
+        let land = values.iter().filter(|&&v| v > 0.0).count();
+        assert!((100..400).contains(&land), "{kind:?} is {land}/500 land");
+    }
+
+    // Mountains only rise out of the higher ground.
+    let config = Config {
+        mountain_config: Some(PerlinConfig {
+            kind: NoiseKind::Ridged,
+            ..PerlinConfig::default()
+        }),
+        ..Config::default()
+    };
+    let terrain = sample(&Terrain::new(&config).unwrap());
+    for (height, base) in terrain.iter().zip(&perlin) {
+        if *base <= 0.0 {
+            assert_eq!(height, base);
+        }
+    }
+    assert_ne!(terrain, perlin);
+    let config = config_from_str("[mountain_config]\nkind = \"ridged\"", &[]).unwrap();
+    assert_eq!(config.mountain_config.unwrap().kind, NoiseKind::Ridged);
+    assert!(config_from_str("[perlin_config]\nkind = \"smooth\"", &[]).is_err());
+}
+
+#[test]
+fn test_perlin_gradient_table() {
+    let points = [
+        Vec3::new(0.6, 0.0, 0.8),
+        Vec3::new(-0.48, 0.6, 0.64),
+        Vec3::new(0.0, -1.0, 0.0),
+    ];
+    // Worlds generated before the gradient table.
+    let legacy = Perlin::new(PerlinConfig {
+        legacy_gradients: true,
+        ..PerlinConfig::default()
+    });
+    let expected = [0.063908726, 0.15245508, 0.06708568];
+    for (p, expected) in points.iter().zip(expected) {
+        assert_eq!(legacy.noise(p.x, p.y, p.z), expected);
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+    let points: Vec<Vec3> = (0..37)
+        .map(|_| Vec3::new(rng.random(), rng.random(), rng.random()) * 4.0 - 2.0)
+        .collect();
+    for legacy_gradients in [false, true] {
+        let perlin = Perlin::new(PerlinConfig {
+            legacy_gradients,
+            ..PerlinConfig::default()
+        });
+        let mut batch = vec![0.0; points.len()];
+        perlin.noise_batch(&points, &mut batch);
+        let single: Vec<f32> = points.iter().map(|p| perlin.noise(p.x, p.y, p.z)).collect();
+        assert_eq!(batch, single);
+    }
+    let sample = |seed| {
+        let perlin = Perlin::new(PerlinConfig {
+            seed,
+            ..PerlinConfig::default()
+        });
+        points
+            .iter()
+            .map(|p| perlin.noise(p.x, p.y, p.z))
+            .collect::<Vec<f32>>()
+    };
+    assert_eq!(sample(3), sample(3));
+    assert_ne!(sample(3), sample(4));
+    // Continuous across the lattice cells.
+    let perlin = Perlin::new(PerlinConfig::default());
+    for x in [-1.0, 0.0, 2.0] {
+        let below = perlin.single_layer(x - 1e-4, 0.3, 0.7);
+        let above = perlin.single_layer(x + 1e-4, 0.3, 0.7);
+        assert!((below - above).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn test_heightmap() {
+    let dir = std::env::temp_dir().join("terrain3d_test_heightmap");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    // Bright in the north, dark in the south.
+    let image = ImageBuffer::from_fn(64, 32, |_, y| Luma([if y < 16 { u16::MAX } else { 0 }]));
+    image.save(path("world.png")).unwrap();
+    let text = format!(
+        "grid_size = 16\n[heightmap]\nequirectangular = {:?}\nmin_height = -0.5\nmax_height = 0.5",
+        path("world.png")
+    );
+    let config = config_from_str(&text, &[]).unwrap();
+    let (globe_points, _) = make_globe(&config).unwrap();
+    let north = globe_points.points.get(&(4, 8, 8)).unwrap();
+    let south = globe_points.points.get(&(5, 8, 8)).unwrap();
+    assert!(north.pos.y > 0.9 * 5.5 && !north.water);
+    assert!(south.pos.y < 0.0 && south.water);
+
+    // Each cube map face has its own brightness.
+    let names = ["px", "nx", "py", "ny", "pz", "nz"].map(|face| path(&format!("{face}.png")));
+    for (face, name) in names.iter().enumerate() {
+        let level = (face as u32 * 50) as u8;
+        ImageBuffer::from_pixel(8, 8, Luma([level]))
+            .save(name)
+            .unwrap();
+    }
+    let heightmap = Heightmap::load(&HeightmapConfig {
+        faces: Some(names.clone()),
+        ..HeightmapConfig::default()
+    })
+    .unwrap();
+    let axes = [Vec3::X, -Vec3::X, Vec3::Y, -Vec3::Y, Vec3::Z, -Vec3::Z];
+    for (face, axis) in axes.into_iter().enumerate() {
+        let expected = (face * 50) as f32 / 255.0;
+        assert!((heightmap.sample(axis + Vec3::splat(0.1)) - expected).abs() < 1e-3);
+    }
+
+    let invalid = |text: &str| matches!(config_from_str(text, &[]), Err(ConfigError::Invalid(_)));
+    assert!(invalid("[heightmap]\nmin_height = 0.0"));
+    assert!(invalid(&format!(
+        "[heightmap]\nequirectangular = {:?}",
+        path("missing.png")
+    )));
+    // The size can still be read, but not the pixels.
+    let bytes = std::fs::read(path("world.png")).unwrap();
+    std::fs::write(path("truncated.png"), &bytes[..bytes.len() / 2]).unwrap();
+    assert!(invalid(&format!(
+        "[heightmap]\nequirectangular = {:?}",
+        path("truncated.png")
+    )));
+    // An image that went missing after validation fails the globe instead of panicking.
+    std::fs::remove_file(path("world.png")).unwrap();
+    assert!(make_globe(&config).is_err());
+}
+
+#[test]
+fn test_layer_export() {
+    let config = Config {
+        grid_size: 16,
+        ..Config::default()
+    };
+    let (globe_points, _) = make_globe(&config).unwrap();
+    let rail_usage = [
+        (
+            Rail {
+                from: (0, 3, 4),
+                to: (0, 4, 4),
+                kind: EdgeKind::Surface,
+            },
+            7,
+        ),
+        (
+            Rail {
+                from: (0, 4, 4),
+                to: (0, 4, 5),
+                kind: EdgeKind::Surface,
+            },
+            1,
+        ),
+    ];
+    let dir = std::env::temp_dir().join("terrain3d_test_layer_export");
+    let paths =
+        export_layers(&dir, &globe_points, &config, &rail_usage, Projection::Faces).unwrap();
+    assert_eq!(paths.len(), 6 * Layer::ALL.len());
+    let paths = export_layers(
+        &dir,
+        &globe_points,
+        &config,
+        &rail_usage,
+        Projection::Equirectangular { width: 64 },
+    )
+    .unwrap();
+    assert_eq!(paths.len(), Layer::ALL.len());
+
+    let open = |name: &str| image::open(dir.join(name)).unwrap().into_luma16();
+    let water = open("water_face2.png");
+    assert_eq!(water.dimensions(), (17, 17));
+    for (i, j) in [(0, 0), (5, 9), (16, 16)] {
+        let expected = globe_points.points.get(&(2, i, j)).unwrap().water;
+        assert_eq!(water.get_pixel(i, 16 - j)[0] == u16::MAX, expected);
+    }
+    // The point between both rails is the busiest.
+    let usage = open("rail_usage_face0.png");
+    assert_eq!(usage.get_pixel(4, 12)[0], u16::MAX);
+    assert_eq!(
+        usage.get_pixel(3, 12)[0],
+        (7.0 / 8.0 * u16::MAX as f32).round() as u16
+    );
+    assert_eq!(usage.get_pixel(9, 9)[0], 0);
+
+    // The equirectangular height map reads back as the same terrain.
+    let height = open("height_equirectangular.png");
+    assert_eq!(height.dimensions(), (64, 32));
+    let equirectangular = HeightmapConfig {
+        equirectangular: Some("height_equirectangular.png".to_string()),
+        ..HeightmapConfig::default()
+    };
+    let heightmap = Heightmap::new(&equirectangular, vec![height]);
+    let values = layer_values(Layer::Height, &globe_points, &config, &[]);
+    let mut errors = 0;
+    for idx in 0..globe_points.points.len() as NodeIndex {
+        let dir = globe_points.points[idx].pos.normalize();
+        let sampled = heightmap.sample(dir);
+        if (sampled - values[idx as usize]).abs() > 0.2 {
+            errors += 1;
+        }
+    }
+    assert!(
+        errors * 20 < globe_points.points.len(),
+        "{errors} points differ"
+    );
+}
+
+#[test]
+fn test_gltf_export() {
+    let mut app = run_simulation(100, 1, Vec::new());
+    let config = app.world().resource::<State>().config.clone();
+    let (_, globe) = make_globe(&config).unwrap();
+    let stats = NetworkStats::collect(app.world_mut());
+    assert!(stats.rails > 0);
+    let path = std::env::temp_dir().join("terrain3d_test_gltf_export.glb");
+    export_world(&path, app.world_mut(), &globe).unwrap();
+
+    let bytes = std::fs::read(&path).unwrap();
+    let u32_at = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    assert_eq!(&bytes[0..4], b"glTF");
+    assert_eq!(u32_at(4), 2);
+    assert_eq!(u32_at(8), bytes.len());
+    let json_length = u32_at(12);
+    assert_eq!(&bytes[16..20], b"JSON");
+    let document: serde_json::Value = serde_json::from_slice(&bytes[20..20 + json_length]).unwrap();
+    let bin = 20 + json_length;
+    assert_eq!(&bytes[bin + 4..bin + 8], b"BIN\0");
+    let buffer_length = document["buffers"][0]["byteLength"].as_u64().unwrap() as usize;
+    assert!(buffer_length <= u32_at(bin));
+
+    let nodes = document["nodes"].as_array().unwrap();
+    assert_eq!(nodes.len(), 1 + stats.cities + stats.rails);
+    assert_eq!(
+        document["scenes"][0]["nodes"].as_array().unwrap().len(),
+        nodes.len()
+    );
+    let globe_attributes = &document["meshes"][0]["primitives"][0]["attributes"];
+    let vertices = globe.count_vertices() as u64;
+    for attribute in ["POSITION", "NORMAL", "COLOR_0"] {
+        let accessor =
+            &document["accessors"][globe_attributes[attribute].as_u64().unwrap() as usize];
+        assert_eq!(accessor["count"], vertices);
+    }
+    // Every accessor fits its view, and every view the buffer.
+    for accessor in document["accessors"].as_array().unwrap() {
+        let components = match accessor["type"].as_str().unwrap() {
+            "SCALAR" => 1,
+            "VEC3" => 3,
+            "VEC4" => 4,
+            kind => panic!("unexpected accessor type {kind}"),
+        };
+        let view = &document["bufferViews"][accessor["bufferView"].as_u64().unwrap() as usize];
+        let length = view["byteLength"].as_u64().unwrap();
+        assert_eq!(length, accessor["count"].as_u64().unwrap() * components * 4);
+        assert!(view["byteOffset"].as_u64().unwrap() + length <= buffer_length as u64);
+    }
+}
+
+#[test]
+fn test_geojson_round_trip() {
+    for pos in [Vec3::new(0.6, 0.0, 0.8), Vec3::new(-0.96, 1.2, -1.28)] {
+        let [longitude, latitude] = lon_lat(pos);
+        assert!(direction(longitude, latitude).distance(pos.normalize()) < 1e-6);
+    }
+
+    let mut app = run_simulation(100, 1, Vec::new());
+    let path = std::env::temp_dir().join("terrain3d_test_geojson.geojson");
+    export_network(&path, app.world_mut()).unwrap();
+    let stats = NetworkStats::collect(app.world_mut());
+    assert!(stats.rails > 0);
+
+    let document: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    let features = document["features"].as_array().unwrap();
+    assert_eq!(features.len(), stats.cities + stats.rails);
+    let rail = features
+        .iter()
+        .find(|feature| feature["properties"]["kind"] == "rail")
+        .unwrap();
+    assert_eq!(rail["geometry"]["coordinates"].as_array().unwrap().len(), 2);
+    assert!(rail["properties"]["length"].as_f64().unwrap() > 0.0);
+    assert!(rail["properties"]["water"].is_boolean());
+
+    // Importing into a new game lays the same rails between the same cities, up to the copies of
+    // the points on the face edges.
+    let state = app.world().resource::<State>();
+    let config = state.config.clone();
+    let shared = shared_points(config.grid_size);
+    let canonical = |globe_points: &GlobePoints, rails: &mut dyn Iterator<Item = &Rail>| {
+        rails
+            .map(|rail| {
+                let [a, b] = [rail.from, rail.to]
+                    .map(|end| shared[globe_points.points.index(&end).unwrap() as usize]);
+                (a.min(b), a.max(b))
+            })
+            .collect::<HashSet<_>>()
+    };
+    let original = {
+        let globe_points = state.globe_points.read().unwrap();
+        canonical(&globe_points, &mut state.rails.rails.keys())
+    };
+    let (globe_points, _) = make_globe(&config).unwrap();
+    let network = load_network(&path, &globe_points, &TERRAIN).unwrap();
+    assert_eq!(network.cities.len(), stats.cities);
+
+    let mut replay = App::new();
+    replay
+        .add_plugins((MinimalPlugins, SimulationPlugin))
+        .insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+            50,
+        )))
+        .insert_resource(State::new(config, globe_points))
+        .insert_resource(PlayerActions(network.actions()));
+    while replay.world().resource::<State>().tick < 2 {
+        replay.update();
+    }
+    let replayed = NetworkStats::collect(replay.world_mut());
+    assert_eq!(replayed.cities, stats.cities);
+    assert_eq!(replayed.trains, 0);
+    let state = replay.world().resource::<State>();
+    let globe_points = state.globe_points.read().unwrap();
+    assert_eq!(
+        canonical(&globe_points, &mut state.rails.rails.keys()),
+        original
+    );
+}
+
+#[test]
+fn test_globe_chunks() {
+    let config = Config {
+        grid_size: 40,
+        ..Config::default()
+    };
+    let (_, mesh) = make_globe(&config).unwrap();
+    let lod = LodConfig {
+        chunk_size: 8,
+        ..LodConfig::default()
+    };
+    let chunks = GlobeChunks::new(mesh.clone(), config.grid_size, lod);
+
+    // From far away every face is a single coarse chunk.
+    let far = chunks.select(Vec3::new(0.0, 0.0, 1000.0));
+    assert_eq!(far, (0..6).map(ChunkKey::root).collect::<Vec<_>>());
+    let root = chunks.chunk_mesh(ChunkKey::root(0));
+    assert_eq!(root.count_vertices(), 9 * 9 + 4 * 9);
+
+    // Close to the surface the chunks get finer towards the camera and still cover every face.
+    let camera = Vec3::new(0.0, 0.0, config.sea_level + 0.5);
+    let near = chunks.select(camera);
+    assert!(near.len() > 6);
+    for face in 0..6 {
+        let area: f32 = near
+            .iter()
+            .filter(|key| key.face == face)
+            .map(|key| 0.25f32.powi(key.depth as i32))
+            .sum();
+        assert!((area - 1.0).abs() < 1e-6);
+    }
+    let closest = near
+        .iter()
+        .min_by(|a, b| {
+            let distance = |key: &&ChunkKey| chunks.bounds(**key).0.distance(camera);
+            distance(a).total_cmp(&distance(b))
+        })
+        .unwrap();
+    let deepest = near.iter().map(|key| key.depth).max().unwrap();
+    assert_eq!(closest.depth, deepest);
+
+    // The deepest chunks use every grid point, at the positions of the full mesh.
+    let Some(VertexAttributeValues::Float32x3(full)) = mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        panic!("the globe mesh has positions");
+    };
+    let chunk = chunks.chunk_mesh(*closest);
+    let Some(VertexAttributeValues::Float32x3(positions)) =
+        chunk.attribute(Mesh::ATTRIBUTE_POSITION)
+    else {
+        panic!("chunks have positions");
+    };
+    let cells = 1 << closest.depth;
+    let span = config.grid_size / cells + 1;
+    assert!(positions.len() >= (span * span) as usize);
+    for pos in &positions[..(span * span) as usize] {
+        assert!(full.contains(pos));
+    }
+}
+
+#[test]
+fn test_tunnels_and_bridges() {
+    let grid_size = 32;
+    let structures = StructureConfig {
+        tunnel_cost: 1.0,
+        bridge_cost: 1.0,
+        pier_cost: 0.1,
+        ..StructureConfig::default()
+    };
+    // Flat land with a wide ridge across face 0 and a deep gorge with a lake across face 1. The
+    // gorge is deeper than the sag of a straight deck over the coarse grid.
+    let mut globe_points = GlobePoints::new(grid_size);
+    for face in 0..6 {
+        for i in 0..=grid_size {
+            for j in 0..=grid_size {
+                let x = i as f32 / grid_size as f32 - 0.5;
+                let y = j as f32 / grid_size as f32 - 0.5;
+                let dir = match face {
+                    0 => Vec3::new(x, y, 0.5),
+                    1 => Vec3::new(-x, y, -0.5),
+                    2 => Vec3::new(0.5, y, -x),
+                    3 => Vec3::new(-0.5, y, x),
+                    4 => Vec3::new(x, 0.5, -y),
+                    _ => Vec3::new(x, -0.5, y),
+                };
+                let (height, water) = match (face, i) {
+                    (0, 10..=20) => (5.6, false),
+                    (1, 10..=20) => (3.6, true),
+                    _ => (5.1, false),
+                };
+                globe_points.points.insert(
+                    (face, i, j),
+                    GlobePoint {
+                        pos: dir.normalize() * height,
+                        water,
+                        penalty: if water { 5.0 } else { 1.0 },
+                        ..GlobePoint::default()
+                    },
+                );
+            }
+        }
+    }
+    globe_points.build_graph(&structures);
+    let points = &globe_points.points;
+    let kind = |from: GridPoint, to: GridPoint| {
+        let (from, to) = (points.index(&from).unwrap(), points.index(&to).unwrap());
+        structure(points, from, to, &structures).map(|(kind, _)| kind)
+    };
+    assert_eq!(kind((0, 4, 10), (0, 28, 10)), Some(EdgeKind::Tunnel));
+    assert_eq!(kind((1, 4, 10), (1, 28, 10)), Some(EdgeKind::Bridge));
+    assert_eq!(kind((0, 4, 4), (0, 28, 28)), Some(EdgeKind::Tunnel));
+    // Flat ground needs neither, and neither starts in the water.
+    assert_eq!(kind((2, 4, 10), (2, 28, 10)), None);
+    assert_eq!(kind((1, 12, 10), (1, 28, 10)), None);
+    // Only axes and diagonals longer than `STEPS`.
+    assert_eq!(kind((0, 4, 10), (0, 28, 12)), None);
+    assert_eq!(kind((0, 8, 10), (0, 12, 10)), None);
+
+    // Priced by the same config, the cheapest routes go through the ridge and over the lake.
+    let model = TerrainCost {
+        structures,
+        ..TERRAIN
+    };
+    for (face, structure_kind) in [(0, EdgeKind::Tunnel), (1, EdgeKind::Bridge)] {
+        let (path, _) = bidirectional_astar((face, 2, 16), (face, 30, 16), &globe_points, &model);
+        assert!(
+            path.windows(2)
+                .any(|w| globe_points.edge_kind(&w[0], &w[1]) == Some(structure_kind))
+        );
+    }
+
+    let bridge = Rail {
+        from: (1, 4, 10),
+        to: (1, 28, 10),
+        kind: EdgeKind::Bridge,
+    };
+    let bridge_piers = piers(points, bridge);
+    assert_eq!(bridge_piers.len(), 3); // in the lake, 4 steps apart
+    assert!(
+        bridge_piers
+            .iter()
+            .all(|&(t, height)| (0.0..1.0).contains(&t) && height > 0.0)
+    );
+    let tunnel = Rail {
+        kind: EdgeKind::Tunnel,
+        ..bridge
+    };
+    assert!(piers(points, tunnel).is_empty());
+}
+
+// A ramp across face 0 up to a plateau, rising `rise` per grid step. The other sides of the
+// plateau drop off more steeply.
+fn ramp_globe(rise: f32) -> GlobePoints {
+    let grid_size = 32;
+    let mut globe_points = GlobePoints::new(grid_size);
+    for face in 0..6 {
+        for i in 0..=grid_size {
+            for j in 0..=grid_size {
+                let x = i as f32 / grid_size as f32 - 0.5;
+                let y = j as f32 / grid_size as f32 - 0.5;
+                let dir = match face {
+                    0 => Vec3::new(x, y, 0.5),
+                    1 => Vec3::new(-x, y, -0.5),
+                    2 => Vec3::new(0.5, y, -x),
+                    3 => Vec3::new(-0.5, y, x),
+                    4 => Vec3::new(x, 0.5, -y),
+                    _ => Vec3::new(x, -0.5, y),
+                };
+                let height = match face {
+                    0 => {
+                        5.1 + rise * (i.clamp(4, 20) - 4) as f32
+                            - 0.16 * i.saturating_sub(28) as f32
+                    }
+                    _ => 5.1,
+                };
+                globe_points.points.insert(
+                    (face, i, j),
+                    GlobePoint {
+                        pos: dir.normalize() * height,
+                        penalty: 1.0,
+                        ..GlobePoint::default()
+                    },
+                );
+            }
+        }
+    }
+    globe_points.build_graph(&StructureConfig::default());
+    globe_points
+}
+
+#[test]
+fn test_track_limits() {
+    // Too steep to climb straight at a grade of 0.1.
+    let globe_points = ramp_globe(0.04);
+    let pos = |gridpoint: &GridPoint| globe_points.points.get(gridpoint).unwrap().pos;
+    let grade = |w: &[GridPoint]| {
+        let (p, q) = (pos(&w[0]), pos(&w[1]));
+        (p.length() - q.length()).abs() / p.distance(q)
+    };
+    let (start, end) = ((0, 2, 16), (0, 24, 16));
+
+    let (direct, direct_cost) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+    assert!(direct.windows(2).any(|w| grade(w) > 0.1));
+
+    // Winding up the ramp keeps to the grade, and costs more.
+    let graded = TrackLimits {
+        max_grade: 0.1,
+        min_turn_radius: 0.0,
+    };
+    let (path, cost) = route(start, end, &globe_points, &graded, &TERRAIN);
+    assert_eq!((path[0], path[path.len() - 1]), (start, end));
+    assert!(path.windows(2).all(|w| grade(w) <= 0.1 + 1e-6));
+    assert!(cost > direct_cost);
+
+    // With a turning radius as well, every corner leaves room for the curve.
+    let limits = TrackLimits {
+        min_turn_radius: 0.25,
+        ..graded
+    };
+    let (path, curved_cost) = route(start, end, &globe_points, &limits, &TERRAIN);
+    assert_eq!((path[0], path[path.len() - 1]), (start, end));
+    assert!(path.windows(2).all(|w| grade(w) <= 0.1 + 1e-6));
+    for w in path.windows(3) {
+        let (incoming, outgoing) = (pos(&w[1]) - pos(&w[0]), pos(&w[2]) - pos(&w[1]));
+        let room = incoming.length().min(outgoing.length()) / 2.0;
+        let half_angle = incoming.angle_between(outgoing) / 2.0;
+        assert!(room >= limits.min_turn_radius * half_angle.tan() - 1e-4);
+    }
+    assert!(curved_cost >= cost);
+
+    // Too steep everywhere, no path at all.
+    let flat_only = TrackLimits {
+        max_grade: 0.01,
+        min_turn_radius: 0.0,
+    };
+    assert!(
+        route(start, end, &globe_points, &flat_only, &TERRAIN)
+            .0
+            .is_empty()
+    );
+}
+
+#[test]
+fn test_default_track_limits() {
+    // Too steep to climb straight, or diagonally, at the default grade.
+    let globe_points = ramp_globe(0.25);
+    let limits = Config::default().track_limits();
+    let pos = |gridpoint: &GridPoint| globe_points.points.get(gridpoint).unwrap().pos;
+    let grade = |w: &[GridPoint]| {
+        let (p, q) = (pos(&w[0]), pos(&w[1]));
+        (p.length() - q.length()).abs() / p.distance(q)
+    };
+    let (start, end) = ((0, 2, 16), (0, 24, 16));
+    let (direct, _) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+    assert!(direct.windows(2).any(|w| grade(w) > limits.max_grade));
+
+    let (path, _) = route(start, end, &globe_points, &limits, &TERRAIN);
+    assert_eq!((path[0], path[path.len() - 1]), (start, end));
+    assert!(path.windows(2).all(|w| grade(w) <= limits.max_grade + 1e-6));
+    // Switchbacks: the path crosses the ramp one way, then back the other way.
+    let sideways: Vec<i64> = path
+        .windows(2)
+        .map(|w| (w[1].2 as i64 - w[0].2 as i64).signum())
+        .filter(|&step| step != 0)
+        .collect();
+    assert!(sideways.windows(2).any(|w| w[0] != w[1]));
+    for w in path.windows(3) {
+        let (incoming, outgoing) = (pos(&w[1]) - pos(&w[0]), pos(&w[2]) - pos(&w[1]));
+        let room = incoming.length().min(outgoing.length()) / 2.0;
+        let half_angle = incoming.angle_between(outgoing) / 2.0;
+        assert!(room >= limits.min_turn_radius * half_angle.tan() - 1e-4);
+    }
+}
+
+#[test]
+fn test_cost_models() {
+    let mut globe_points = small_globe(485);
+    let distance = DistanceCost {
+        reduction_factor: 2.0,
+    };
+    let construction = ConstructionOperatingCost {
+        config: CostModelConfig::default(),
+        structures: StructureConfig::default(),
+    };
+    let models: [&dyn CostModel; 3] = [&TERRAIN, &distance, &construction];
+    let (start, end) = ((0, 3, 4), (2, 0, 5));
+
+    // Every model keeps the heuristic admissible, before and after building.
+    let mut costs = Vec::new();
+    for built in [false, true] {
+        if built {
+            let (path, _) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+            globe_points.mark_built(&path);
+        }
+        for model in models {
+            let expected = path_cost(
+                &dijkstra(start, end, &globe_points, model),
+                &globe_points,
+                model,
+            );
+            let (path, cost) = bidirectional_astar(start, end, &globe_points, model);
+            assert!((cost - expected).abs() <= 1e-3 * expected);
+            assert!((path_cost(&path, &globe_points, model) - cost).abs() <= 1e-3 * cost);
+            costs.push(cost);
+        }
+    }
+    // Reusing the rails is cheaper under every model.
+    for (before, after) in costs[..3].iter().zip(&costs[3..]) {
+        assert!(after < before);
+    }
+    let (path, _) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+    assert!((path_cost(&path, &globe_points, &TERRAIN) - costs[3]).abs() <= 1e-3 * costs[3]);
+    globe_points.clear_built();
+    assert!(!globe_points.has_rails);
+    let (_, cost) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+    assert!((cost - costs[0]).abs() <= 1e-3 * costs[0]);
+
+    // The distance model finds the shortest path.
+    let (shortest, _) = bidirectional_astar(start, end, &globe_points, &distance);
+    let (cheapest, _) = bidirectional_astar(start, end, &globe_points, &TERRAIN);
+    assert!(
+        path_cost(&shortest, &globe_points, &distance)
+            <= path_cost(&cheapest, &globe_points, &distance) + 1e-4
+    );
+
+    // Switching models leaves the world, and so the cached graph, as it is.
+    let config = config_from_str("[cost_model_config]\nmodel = \"distance\"", &[]).unwrap();
+    assert_eq!(config.cost_model_config.model, CostModelKind::Distance);
+    assert_eq!(config.world_hash(), Config::default().world_hash());
+
+    // So does changing the parameters of the terrain model, which the searches pick up on the
+    // same graph.
+    let config = config_from_str(
+        "climbing_cost = 50.0\nbridge_penalty = 2.0\n\
+         [cost_model_config]\nlength_exponent = 1.0",
+        &[],
+    )
+    .unwrap();
+    assert_eq!(config.world_hash(), Config::default().world_hash());
+    let steep = Config {
+        climbing_cost: 50.0,
+        ..Config::default()
+    }
+    .terrain_cost();
+    let expected = path_cost(
+        &dijkstra(start, end, &globe_points, &steep),
+        &globe_points,
+        &steep,
+    );
+    let (path, cost) = bidirectional_astar(start, end, &globe_points, &steep);
+    assert!((cost - expected).abs() <= 1e-3 * expected);
+    assert!(cost > costs[0]);
+    let climb = |path: &[GridPoint]| -> f32 {
+        path.windows(2)
+            .map(|w| {
+                let (p, q) = (
+                    globe_points.points.get(&w[0]),
+                    globe_points.points.get(&w[1]),
+                );
+                (p.unwrap().pos.length() - q.unwrap().pos.length()).abs()
+            })
+            .sum()
+    };
+    assert!(climb(&path) <= climb(&cheapest) + 1e-4);
+}
+
+#[test]
+fn test_network_planner() {
+    let globe_points = small_globe(485);
+    let cities = [(0, 3, 4), (0, 12, 9), (1, 8, 8), (2, 0, 5), (4, 9, 14)];
+    let connected = |rails: &[Rail], skip: Option<usize>| {
+        let mut reached = HashSet::from([cities[0]]);
+        let mut grew = true;
+        while grew {
+            grew = false;
+            for (i, rail) in rails.iter().enumerate() {
+                if Some(i) != skip && reached.contains(&rail.from) != reached.contains(&rail.to) {
+                    reached.extend([rail.from, rail.to]);
+                    grew = true;
+                }
+            }
+        }
+        cities.iter().all(|city| reached.contains(city))
+    };
+    let cost = |rails: &[Rail]| {
+        rails
+            .iter()
+            .map(|rail| path_cost(&[rail.from, rail.to], &globe_points, &TERRAIN))
+            .sum::<f32>()
+    };
+
+    // A tree reaching every city, no more expensive than routing a chain of them one by one.
+    let tree = plan_network(&cities, &globe_points, &TERRAIN, 1);
+    assert!(connected(&tree, None));
+    let nodes: HashSet<GridPoint> = tree.iter().flat_map(|rail| [rail.from, rail.to]).collect();
+    assert_eq!(tree.len(), nodes.len() - 1);
+    assert!(tree.iter().all(|rail| rail.from < rail.to));
+    assert!(
+        tree.iter()
+            .all(|rail| globe_points.edge_kind(&rail.from, &rail.to) == Some(rail.kind))
+    );
+    let chain: f32 = cities
+        .windows(2)
+        .map(|w| bidirectional_astar(w[0], w[1], &globe_points, &TERRAIN).1)
+        .sum();
+    assert!(cost(&tree) <= chain * 1.001);
+
+    // With two disjoint paths no single rail is missed.
+    let network = plan_network(&cities, &globe_points, &TERRAIN, 2);
+    assert!(network.len() > tree.len());
+    assert!((0..network.len()).all(|i| connected(&network, Some(i))));
+    assert!(!(0..tree.len()).all(|i| connected(&tree, Some(i))));
+
+    assert!(plan_network(&cities[..1], &globe_points, &TERRAIN, 1).is_empty());
+}
+
+#[test]
+fn test_network_redundancy() {
+    // Flat ground that is cheap only at a few points 7 steps apart, so that every rail joins
+    // two of them:
+    //
+    //   U1 - U2 - U3
+    //   |    |    |
+    //   R -- A -- T
+    //   |    |
+    //   D -- B
+    //   |    |
+    //   F -- C
+    //
+    // The tree joins the cities R, T and C through A, with a side branch from A to C. D costs
+    // more, so the second path to T starts along the top and would reach the tree at A first.
+    let mut globe_points = ramp_globe(0.0);
+    for idx in 0..globe_points.points.len() as NodeIndex {
+        globe_points.points[idx].penalty = 100.0;
+    }
+    let cheap = [
+        (2, 2),
+        (2, 9),
+        (2, 16),
+        (9, 2),
+        (9, 9),
+        (9, 16),
+        (16, 9),
+        (23, 2),
+        (23, 9),
+    ];
+    for ((i, j), penalty) in cheap
+        .map(|point| (point, 1.0))
+        .into_iter()
+        .chain([((16, 2), 2.0)])
+    {
+        let idx = globe_points.points.index(&(0, i, j)).unwrap();
+        globe_points.points[idx].penalty = penalty;
+    }
+    let cities = [(0, 9, 2), (0, 9, 16), (0, 23, 9)];
+
+    let tree = plan_network(&cities, &globe_points, &TERRAIN, 1);
+    assert_eq!(tree.len(), 4);
+    // Along the top to T, from D to F to C, and no rails that end at A or B without adding a
+    // disjoint path.
+    let network = plan_network(&cities, &globe_points, &TERRAIN, 2);
+    assert_eq!(network.len(), tree.len() + 4 + 3);
+}
+
+#[test]
+fn test_alternative_routes() {
+    let mut globe_points = small_globe(485);
+    let config = Config::default();
+    let (start, end) = ((0, 3, 4), (2, 0, 5));
+    let (path, _) = route(start, end, &globe_points, &config.track_limits(), &TERRAIN);
+    let optimum = path_cost(&path, &globe_points, &TERRAIN);
+
+    // Different routes, cheapest first, each priced in full by its breakdown.
+    let routes = alternative_routes(start, end, &globe_points, &config, 3);
+    assert!(routes.len() > 1);
+    assert!((routes[0].cost - optimum).abs() <= 1e-3 * optimum);
+    for (i, alternative) in routes.iter().enumerate() {
+        assert_eq!(alternative.path.first(), Some(&start));
+        assert_eq!(alternative.path.last(), Some(&end));
+        assert!(routes[..i].iter().all(|r| r.path != alternative.path));
+        assert!(routes[..i].iter().all(|r| r.cost <= alternative.cost));
+        let cost = path_cost(&alternative.path, &globe_points, &TERRAIN);
+        assert!((alternative.cost - cost).abs() <= 1e-3 * cost);
+        assert!((alternative.breakdown.total() - cost).abs() <= 1e-3 * cost);
+        assert_eq!(alternative.breakdown.reused, 0.0);
+    }
+
+    // Once the cheapest route is built, following it again is mostly savings.
+    globe_points.mark_built(&routes[0].path);
+    let reused = alternative_routes(start, end, &globe_points, &config, 1);
+    assert_eq!(reused.len(), 1);
+    assert_eq!(reused[0].path, routes[0].path);
+    assert!((reused[0].breakdown.total() - optimum / 2.0).abs() <= 1e-3 * optimum);
+}
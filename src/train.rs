@@ -2,7 +2,7 @@ use crate::state::{Rail, State};
 use bevy::prelude::*;
 use std::sync::atomic::Ordering;
 
-#[derive(Component)]
+#[derive(Component, Clone)]
 pub struct Train {
     pub transforms: Vec<(Transform, Rail)>,
     pub idx: usize,
@@ -63,10 +63,17 @@ impl Train {
         }
     }
 
+    // The train faces along the curve rather than along the rails it cuts across, so that it
+    // turns smoothly through the corners too.
     fn bezier_2(&self, a: &Transform, b: &Transform, c: &Transform, ratio: f32) -> Transform {
         let p_ab = self.between_transforms(a, b, ratio);
         let p_bc = self.between_transforms(b, c, ratio);
-        self.between_transforms(&p_ab, &p_bc, ratio)
+        let mut transform = self.between_transforms(&p_ab, &p_bc, ratio);
+        let tangent = p_bc.translation - p_ab.translation;
+        if tangent.length_squared() > 0.0 {
+            transform.rotation = facing(tangent, transform.translation);
+        }
+        transform
     }
 
     pub fn current_transform(&mut self) -> Transform {
@@ -109,14 +116,15 @@ impl Train {
         }
     }
 
+    // Moves the train along its path. Returns the rail it entered, if any, whose usage counter
+    // has been increased.
     pub fn update(
         &mut self,
         transform: &mut Transform,
         time_passed_seconds: f32,
         state: &State,
-        commands: &mut Commands,
-        materials: &mut Assets<StandardMaterial>,
-    ) {
+    ) -> Option<Rail> {
+        let mut entered_rail = None;
         if self.segment_duration.is_none() {
             self.compute_segment_duration();
         }
@@ -126,29 +134,15 @@ impl Train {
             // Move to the next segment.
             self.idx = self.next_idx;
 
-            let rail_info = state.rails.rails.get(&self.transforms[self.idx].1).unwrap();
+            let rail = self.transforms[self.idx].1;
+            let rail_info = state.rails.rails.get(&rail).unwrap();
 
             let count = rail_info.counter.fetch_add(1, Ordering::Relaxed) + 1;
+            state.max_rail_usage.fetch_max(count, Ordering::Relaxed);
+            entered_rail = Some(rail);
 
-            let max_rail_usage =
-                count.max(state.max_rail_usage.fetch_max(count, Ordering::Relaxed));
-
-            let color = (((max_rail_usage - count) as f32 / max_rail_usage as f32) * 255.) as u8;
-
-            let material = materials.add(StandardMaterial {
-                base_color: Color::srgb_u8(255, color, color),
-                perceptual_roughness: 0.0,
-                metallic: 0.0,
-                ..default()
-            });
-
-            commands
-                .entity(rail_info.entity)
-                .insert((MeshMaterial3d(material),));
-
-            // Account for the remaining time.
-            self.seconds_spent_within_segment =
-                self.segment_duration.unwrap() - self.seconds_spent_within_segment;
+            // Carry the time past the end of the segment into the next one.
+            self.seconds_spent_within_segment -= self.segment_duration.unwrap();
 
             if self.forward {
                 if self.idx < self.transforms.len() - 1 {
@@ -176,5 +170,17 @@ impl Train {
         // Update the train's position
         transform.translation = current_transform.translation;
         transform.rotation = current_transform.rotation;
+        entered_rail
     }
 }
+
+// Rotation that points the length of a train, along y, in `direction` and its top away from the
+// centre of the globe, like the rails of `rail_placement`.
+fn facing(direction: Vec3, position: Vec3) -> Quat {
+    let forward = direction.normalize();
+    let up = forward
+        .cross(position.normalize())
+        .cross(forward)
+        .normalize();
+    Quat::from_mat3(&Mat3::from_cols(forward.cross(up), forward, up))
+}
@@ -1,11 +1,24 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
 
-use crate::dijkstra::{
-    GlobePoint, GlobePoints, GridPoint, bidirectional_dijkstra, get_closest_gridpoint,
+use crate::alternatives::{Route, RouteFinder};
+use crate::config_file::load_config;
+use crate::dijkstra::{EdgeKind, GlobePoints, GridPoint, get_closest_gridpoint};
+use crate::geojson_file::{GEOJSON_FILE, Network, export_network, read_features, snap_line};
+use crate::globe_chunks::{ChunkKey, GlobeChunks};
+use crate::globe_file::load_or_make_globe;
+use crate::gltf_export::{GLTF_FILE, export_world};
+use crate::meshes_materials::{Materials, Meshes};
+use crate::save_file::{SAVE_FILE, SaveGame};
+use crate::simulation::{
+    City, DijkstraCommunication, NetworkPlanning, PlayerAction, PlayerActions, Position, RailPiece,
+    RailUsageChanged, SIM_SPEEDS, SimSpeed, SimulationPlugin, restore_session, snapshot_session,
 };
-use crate::meshes_materials::{Materials, Meshes, make_globe};
-use crate::state::{Rail, RailInfo, State};
+use crate::state::State;
+use crate::structures::piers;
 use crate::train::{SelectedTrain, Train};
 
 use bevy::{
@@ -16,10 +29,16 @@ use bevy::{
     render::mesh::{Mesh, Mesh3d},
     window::WindowResolution,
 };
-use crossbeam_channel::{Receiver, Sender, bounded};
-use rand::{Rng, SeedableRng};
+use crossbeam_channel::{Receiver, TryRecvError, bounded};
 
 pub fn init() {
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
     App::new()
         .add_plugins((
             DefaultPlugins.set(WindowPlugin {
@@ -54,22 +73,54 @@ pub fn init() {
             Update,
             on_mouse_left_click.run_if(input_just_pressed(MouseButton::Left)),
         )
-        .add_systems(Update, create_path_if_dijkstra_ready)
-        .add_systems(Update, highlight_city)
-        .insert_resource(State {
-            config: crate::state::Config::default(),
-            globe_points: Arc::new(RwLock::new(GlobePoints::default())),
-            rails: crate::state::Rails::default(),
-            rng: rand::rngs::StdRng::seed_from_u64(
-                crate::state::Config::default().perlin_config.seed as u64,
+        .add_systems(
+            Update,
+            (
+                receive_routes,
+                next_route.run_if(input_just_pressed(KeyCode::Tab)),
+                build_route.run_if(input_just_pressed(KeyCode::Enter)),
+                draw_routes,
+            )
+                .chain(),
+        )
+        .add_plugins(SimulationPlugin)
+        .add_systems(
+            Update,
+            (
+                add_city_visuals,
+                add_rail_visuals,
+                add_train_visuals,
+                recolor_rails,
             ),
-            create_new_city_next: true,
-            max_rail_usage: 0.into(),
-        })
+        )
+        .add_systems(Update, save_game.run_if(input_just_pressed(KeyCode::F5)))
+        .add_systems(Update, load_game.run_if(input_just_pressed(KeyCode::F9)))
+        .add_systems(Update, export_gltf.run_if(input_just_pressed(KeyCode::F6)))
+        .add_systems(
+            Update,
+            export_geojson.run_if(input_just_pressed(KeyCode::F7)),
+        )
+        .add_systems(
+            Update,
+            (
+                import_geojson.run_if(input_just_pressed(KeyCode::F8)),
+                receive_geojson,
+            )
+                .chain(),
+        )
+        .add_systems(
+            Update,
+            plan_network.run_if(input_just_pressed(KeyCode::F10)),
+        )
+        .add_systems(Update, highlight_city)
+        .add_systems(Update, set_sim_speed)
+        .insert_resource(State::new(config, GlobePoints::default()))
         .insert_resource(SelectedCity::default())
+        .insert_resource(RoutePreview::default())
+        .insert_resource(GeoJsonImport::default())
         .add_systems(Update, draw_pointer)
         .add_systems(Update, try_getting_globe)
-        .add_systems(Update, move_trains)
+        .add_systems(PostUpdate, update_globe_chunks)
         .run();
 }
 
@@ -88,28 +139,41 @@ struct Globe;
 #[derive(Component)]
 struct MainCamera;
 
-#[derive(Component)]
-struct City;
-
 #[derive(Resource, Default)]
 struct SelectedCity(Option<Entity>);
 
-#[derive(Component)]
-struct Position {
-    gridpoint: GridPoint,
-    globe_point: GlobePoint,
+// Routes between the last two selected cities, drawn as ghosts until one is built or dropped.
+#[derive(Resource, Default)]
+struct RoutePreview {
+    receiver: Option<Receiver<Vec<PreviewRoute>>>, // set while the routes are searched
+    cancelled: Arc<AtomicBool>,                    // of the search in progress
+    routes: Vec<PreviewRoute>,
+    chosen: usize,
+}
+
+// Network read from `GEOJSON_FILE` on a background thread, set while it is snapped to the grid.
+#[derive(Resource, Default)]
+struct GeoJsonImport(Option<Receiver<Result<Network, String>>>);
+
+struct PreviewRoute {
+    route: Route,
+    positions: Vec<Vec3>, // of the grid points of the path
 }
 
+const ROUTE_COLORS: [Srgba; 5] = [AMBER_400, SKY_400, FUCHSIA_400, LIME_400, ROSE_400];
+
+// The globe is drawn in chunks, see `GlobeChunks`.
 #[derive(Resource)]
-struct GlobeReceiver {
-    receiver: Receiver<(GlobePoints, Mesh)>,
+struct GlobeLod {
+    chunks: GlobeChunks,
+    material: Handle<StandardMaterial>,
+    meshes: HashMap<ChunkKey, Handle<Mesh>>, // every chunk built so far
+    spawned: HashMap<ChunkKey, Entity>,
 }
 
 #[derive(Resource)]
-struct DijkstraCommunication {
-    sender: Sender<Option<Vec<GridPoint>>>,
-    receiver: Receiver<Option<Vec<GridPoint>>>,
-    task: Option<(GridPoint, GridPoint)>,
+struct GlobeReceiver {
+    receiver: Receiver<(GlobePoints, Mesh)>,
 }
 
 type CameraTransformQuery<'w, 's> =
@@ -118,10 +182,10 @@ type LightsTransformQuery<'w, 's> =
     Query<'w, 's, &'static mut Transform, (Without<MainCamera>, Without<Train>, With<PointLight>)>;
 type SelectedTrainQuery<'w, 's> =
     Query<'w, 's, (Entity, &'static Transform), (With<Train>, With<SelectedTrain>)>;
+type SessionEntitiesQuery<'w, 's> = Query<'w, 's, Entity, Or<(With<City>, With<Train>)>>;
 
 fn try_getting_globe(
     mut commands: Commands,
-    mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut state: ResMut<State>,
     globe_receiver: Res<GlobeReceiver>,
@@ -129,7 +193,6 @@ fn try_getting_globe(
     if let Ok((globe_points, globe_mesh)) = globe_receiver.receiver.try_recv() {
         println!("Received globe points and mesh.");
         state.globe_points = Arc::new(RwLock::new(globe_points));
-        let globe_mesh_handle = meshes.add(globe_mesh);
         let globe_material = materials.add(StandardMaterial {
             base_color: Color::WHITE,
             perceptual_roughness: 0.0,
@@ -137,14 +200,57 @@ fn try_getting_globe(
             ..default()
         });
 
-        println!("Spawning globe.");
-        commands.spawn((
-            Mesh3d(globe_mesh_handle),
-            MeshMaterial3d(globe_material.clone()),
-            Transform::from_xyz(0.0, 0.0, 0.0),
-            Globe,
-        ));
-        println!("Globe spawned.");
+        commands.insert_resource(GlobeLod {
+            chunks: GlobeChunks::new(globe_mesh, state.config.grid_size, state.config.lod_config),
+            material: globe_material,
+            meshes: HashMap::new(),
+            spawned: HashMap::new(),
+        });
+    }
+}
+
+// Swaps the globe chunks for ones of the right detail whenever the camera moved.
+fn update_globe_chunks(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    lod: Option<ResMut<GlobeLod>>,
+    camera: Query<&GlobalTransform, With<MainCamera>>,
+) {
+    let (Some(mut lod), Ok(camera)) = (lod, camera.single()) else {
+        return;
+    };
+    let lod = &mut *lod;
+    let selected = lod.chunks.select(camera.translation());
+    if selected.len() == lod.spawned.len()
+        && selected.iter().all(|key| lod.spawned.contains_key(key))
+    {
+        return;
+    }
+    lod.spawned.retain(|key, entity| {
+        let keep = selected.contains(key);
+        if !keep {
+            commands.entity(*entity).despawn();
+        }
+        keep
+    });
+    for key in selected {
+        if lod.spawned.contains_key(&key) {
+            continue;
+        }
+        let mesh = lod
+            .meshes
+            .entry(key)
+            .or_insert_with(|| meshes.add(lod.chunks.chunk_mesh(key)))
+            .clone();
+        let entity = commands
+            .spawn((
+                Mesh3d(mesh),
+                MeshMaterial3d(lod.material.clone()),
+                Transform::from_xyz(0.0, 0.0, 0.0),
+                Globe,
+            ))
+            .id();
+        lod.spawned.insert(key, entity);
     }
 }
 
@@ -157,19 +263,18 @@ fn startup(
     let (tx, rx) = bounded(1);
     let config_for_make_globe = state.config.clone();
     thread::spawn(move || {
-        let (globe_points, globe_mesh) = make_globe(&config_for_make_globe);
+        let (globe_points, globe_mesh) = match load_or_make_globe(&config_for_make_globe) {
+            Ok(globe) => globe,
+            Err(err) => {
+                eprintln!("{err}");
+                std::process::exit(2);
+            }
+        };
         tx.send((globe_points, globe_mesh)).unwrap();
     });
 
     commands.insert_resource(GlobeReceiver { receiver: rx });
 
-    let (dijkstra_tx, dijkstra_rx) = bounded(1);
-    commands.insert_resource(DijkstraCommunication {
-        sender: dijkstra_tx,
-        receiver: dijkstra_rx,
-        task: None,
-    });
-
     commands.insert_resource(Meshes::new(&mut meshes));
     commands.insert_resource(Materials::new(&mut materials));
     commands.spawn((
@@ -194,241 +299,280 @@ fn startup(
     ));
 }
 
-fn create_path_if_dijkstra_ready(
+fn save_game(
+    state: Res<State>,
+    dijkstra_communication: Res<DijkstraCommunication>,
+    network_planning: Res<NetworkPlanning>,
+    actions: Res<PlayerActions>,
+    cities: Query<&Position, With<City>>,
+    trains: Query<&Train>,
+) {
+    // The simulation waits for the plan, which isn't part of the save.
+    if network_planning.task.is_some() {
+        println!("Could not save game to {SAVE_FILE}: a network is being planned.");
+        return;
+    }
+    // Nor are the actions waiting for a path search.
+    if !actions.0.is_empty() {
+        println!("Could not save game to {SAVE_FILE}: actions are waiting for a path search.");
+        return;
+    }
+    let save = snapshot_session(
+        &state,
+        &dijkstra_communication,
+        cities.iter(),
+        trains.iter(),
+    );
+    match save.save(Path::new(SAVE_FILE)) {
+        Ok(()) => println!("Saved game to {SAVE_FILE}."),
+        Err(err) => println!("Could not save game to {SAVE_FILE}: {err}"),
+    }
+}
+
+fn load_game(
     mut commands: Commands,
     mut state: ResMut<State>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
     mut dijkstra_communication: ResMut<DijkstraCommunication>,
-    cities: Query<(Entity, &Position), With<City>>,
-    meshes: Res<Meshes>,
-    custom_materials: Res<Materials>,
+    network_planning: Res<NetworkPlanning>,
+    mut actions: ResMut<PlayerActions>,
+    mut selected: ResMut<SelectedCity>,
+    entities: SessionEntitiesQuery,
 ) {
-    let Some(_) = dijkstra_communication.task else {
-        return;
-    };
-    let Ok(dijkstra_result) = dijkstra_communication.receiver.try_recv() else {
-        return;
+    let save = match SaveGame::load(Path::new(SAVE_FILE)) {
+        Ok(save) => save,
+        Err(err) => {
+            println!("Could not load game from {SAVE_FILE}: {err}");
+            return;
+        }
     };
-    let Some(path) = dijkstra_result else {
-        println!("Dijkstra returned None, skipping path creation.");
+    match restore_session(
+        &mut commands,
+        &mut state,
+        &mut dijkstra_communication,
+        &network_planning,
+        entities.iter(),
+        save,
+    ) {
+        Ok(()) => {
+            selected.0 = None;
+            actions.0.clear();
+            println!("Loaded game from {SAVE_FILE}.");
+        }
+        Err(err) => println!("Could not load game from {SAVE_FILE}: {err}"),
+    }
+}
+
+fn export_gltf(world: &mut World) {
+    let Some(globe) = world
+        .get_resource::<GlobeLod>()
+        .map(|lod| lod.chunks.mesh.clone())
+    else {
+        println!("The globe is not ready yet.");
         return;
     };
+    match export_world(Path::new(GLTF_FILE), world, &globe) {
+        Ok(()) => println!("Exported the globe and the rail network to {GLTF_FILE}."),
+        Err(err) => println!("Could not export to {GLTF_FILE}: {err}"),
+    }
+}
 
-    // Clear task to signal Dijkstra is ready for another task.
-    dijkstra_communication.task = None;
-
-    let path_mesh = meshes.path.clone();
-    let train_mesh = meshes.train.clone();
-
-    let material = materials.add(StandardMaterial {
-        base_color: Color::srgb_u8(255, 255, 255),
-        perceptual_roughness: 0.0,
-        metallic: 0.0,
-        ..default()
-    });
+fn export_geojson(world: &mut World) {
+    match export_network(Path::new(GEOJSON_FILE), world) {
+        Ok(()) => println!("Exported the rail network to {GEOJSON_FILE}."),
+        Err(err) => println!("Could not export to {GEOJSON_FILE}: {err}"),
+    }
+}
 
-    let globe_points_lock = Arc::clone(&state.globe_points);
-    let Ok(mut globe_points) = globe_points_lock.write() else {
+// Adds the cities and rails of the file to the current network, once they are snapped to the
+// grid.
+fn import_geojson(state: Res<State>, mut import: ResMut<GeoJsonImport>) {
+    if import.0.is_some() {
+        println!("Still importing {GEOJSON_FILE}.");
+        return;
+    }
+    let Ok(globe_points) = state.globe_points.read() else {
         println!("Failed to lock globe points. This should never happen.");
         return;
     };
+    if globe_points.points.is_empty() {
+        println!("The globe is not ready yet.");
+        return;
+    }
+    let (sender, receiver) = bounded(1);
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let config = state.config.clone();
+    import.0 = Some(receiver);
+    thread::spawn(move || {
+        let model = config.cost_model();
+        let network = read_features(Path::new(GEOJSON_FILE)).and_then(|features| {
+            features.to_network(config.grid_size, |line| {
+                // Locked for one line at a time, so that the simulation can lay rails meanwhile.
+                let globe_points = globe_points_lock
+                    .read()
+                    .map_err(|_| "could not lock the globe points".to_string())?;
+                snap_line(line, &globe_points, model.as_ref())
+            })
+        });
+        sender.send(network).unwrap();
+    });
+}
 
-    let reduction_factor = state.config.reduction_factor;
-    // Apply cost reduction to edges in the path (only once per edge)
-    for w in path.windows(2) {
-        let (from, to) = (w[1], w[0]);
-        if let Some(edges) = globe_points.graph.get_mut(&from) {
-            for edge in edges.iter_mut() {
-                if edge.to == to && !edge.discounted {
-                    edge.cost /= reduction_factor;
-                    edge.discounted = true;
-                }
-            }
-        }
-        if let Some(edges) = globe_points.graph.get_mut(&to) {
-            for edge in edges.iter_mut() {
-                if edge.to == from && !edge.discounted {
-                    edge.cost /= reduction_factor;
-                    edge.discounted = true;
-                }
-            }
+fn receive_geojson(mut import: ResMut<GeoJsonImport>, mut actions: ResMut<PlayerActions>) {
+    let Some(receiver) = &import.0 else {
+        return;
+    };
+    let network = match receiver.try_recv() {
+        Ok(network) => network,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => Err("the import stopped".to_string()),
+    };
+    import.0 = None;
+    match network {
+        Ok(network) => {
+            println!(
+                "Importing {} cities and {} rail paths from {GEOJSON_FILE}.",
+                network.cities.len(),
+                network.paths.len()
+            );
+            actions.0.extend(network.actions());
         }
+        Err(err) => println!("Could not import {GEOJSON_FILE}: {err}"),
     }
+}
 
-    println!("Dijkstra done, path length: {}", path.len());
-
-    let mut train_transforms = Vec::new();
-
-    for line in path.windows(2) {
-        let (from, to) = (line[0], line[1]);
-        if let Some(from_point) = globe_points.points.get(&from) {
-            if let Some(to_point) = globe_points.points.get(&to) {
-                let rail = Rail {
-                    from: from.min(to),
-                    to: from.max(to),
-                };
-
-                // compute the transform of both the path segment and train
-                let direction = to_point.pos - from_point.pos;
-                let length = direction.length();
-                let mid_point = (from_point.pos + to_point.pos) / 2.0;
-
-                let dir_norm = direction.normalize();
-                let up = Vec3::cross(Vec3::cross(dir_norm, mid_point.normalize()), dir_norm);
-                let rotation =
-                    Quat::from_mat3(&Mat3::from_cols(Vec3::cross(dir_norm, up), dir_norm, up));
-
-                // If this piece of rail already exists, just change its material
-                // corresponding to the current path.
-                // We don't _really_ need this, but this demonstrates how to update
-                // existig rail piece entities.
-                if let std::collections::hash_map::Entry::Vacant(e) = state.rails.rails.entry(rail)
-                {
-                    // Otherwise, create a new entity for the rail and store it in the
-                    // Rails resource.
-                    //
-                    // We first create an empty entity in order to already have its ID
-                    // which we can key the RailInfo with.
-                    //
-                    // We'll update it with all the details the same way as we've updated
-                    // the existing rail piece above.
-                    let entity = commands.spawn_empty().id();
-                    e.insert(RailInfo {
-                        entity,
-                        counter: 0.into(),
-                        // Other details can be added here.
-                    });
-                    commands.entity(entity).insert((
-                        Mesh3d(path_mesh.clone()),
-                        MeshMaterial3d(material.clone()),
-                        Transform::from_scale(Vec3 {
-                            x: 0.06,
-                            y: length,
-                            z: 0.04,
-                        })
-                        .with_translation(mid_point)
-                        .with_rotation(rotation),
-                        PointerInteraction::default(),
-                    ));
-                }
+// Connects all cities with a planned network, on the next simulation tick.
+fn plan_network(mut actions: ResMut<PlayerActions>) {
+    actions.0.push(PlayerAction::PlanNetwork);
+}
+
+// The simulation spawns cities, rails and trains without meshes, these systems make them visible.
+fn add_city_visuals(
+    mut commands: Commands,
+    cities: Query<Entity, Added<City>>,
+    meshes: Res<Meshes>,
+    materials: Res<Materials>,
+) {
+    for entity in cities.iter() {
+        commands.entity(entity).insert((
+            Mesh3d(meshes.city.clone()),
+            MeshMaterial3d(materials.city.clone()),
+        ));
+    }
+}
 
-                train_transforms.push((
-                    Transform::from_translation(mid_point * 1.005).with_rotation(rotation),
-                    rail,
+fn add_rail_visuals(
+    mut commands: Commands,
+    state: Res<State>,
+    rails: Query<(Entity, &RailPiece, &Transform), Added<RailPiece>>,
+    meshes: Res<Meshes>,
+    materials: Res<Materials>,
+) {
+    let Ok(globe_points) = state.globe_points.read() else {
+        println!("Failed to lock globe points. This should never happen.");
+        return;
+    };
+    for (entity, rail_piece, transform) in rails.iter() {
+        let rail = rail_piece.0;
+        if rail.kind == EdgeKind::Tunnel {
+            // Only the portals show, the rest of the tunnel is inside the mountain.
+            let portal = (TUNNEL_PORTAL_LENGTH / transform.scale.y).min(0.5);
+            for end in [-1.0, 1.0] {
+                let portal = Transform::from_xyz(0.0, end * (0.5 - portal / 2.0), 0.0)
+                    .with_scale(Vec3::new(2.0, portal, 2.0));
+                commands.entity(entity).with_child((
+                    Mesh3d(meshes.path.clone()),
+                    MeshMaterial3d(materials.tunnel.clone()),
+                    portal,
                 ));
             }
+            continue;
+        }
+        let count = state
+            .rails
+            .rails
+            .get(&rail)
+            .map_or(0, |info| info.counter.load(Ordering::Relaxed));
+        // Rails restored from a save keep their usage colour.
+        let max_rail_usage = state.max_rail_usage.load(Ordering::Relaxed);
+        commands.entity(entity).insert((
+            Mesh3d(meshes.path.clone()),
+            MeshMaterial3d(materials.rail(count, max_rail_usage)),
+            PointerInteraction::default(),
+        ));
+        // Piers hang down from the deck to the ground, in the scaled frame of the rail.
+        let (Some(from), Some(to)) = (
+            globe_points.points.get(&rail.from),
+            globe_points.points.get(&rail.to),
+        ) else {
+            continue;
+        };
+        let world_to_rail = transform.compute_matrix().inverse();
+        for (t, height) in piers(&globe_points.points, rail) {
+            let top = world_to_rail.transform_point3(from.pos.lerp(to.pos, t));
+            let depth = height / transform.scale.z;
+            let pier = Transform::from_xyz(top.x, top.y, top.z - depth / 2.0)
+                .with_scale(Vec3::new(0.5, PIER_WIDTH / transform.scale.y, depth));
+            commands.entity(entity).with_child((
+                Mesh3d(meshes.path.clone()),
+                MeshMaterial3d(materials.pier.clone()),
+                pier,
+            ));
         }
     }
+}
+
+const TUNNEL_PORTAL_LENGTH: f32 = 0.06;
+const PIER_WIDTH: f32 = 0.03;
 
-    // spawn a train at the first point of the path
-    if let Some(mut train) = Train::new(train_transforms) {
-        let first_transform = train.current_transform();
-        commands.spawn((
-            train,
-            Mesh3d(train_mesh),
-            MeshMaterial3d(custom_materials.train.clone()),
-            first_transform,
+fn add_train_visuals(
+    mut commands: Commands,
+    trains: Query<Entity, Added<Train>>,
+    meshes: Res<Meshes>,
+    materials: Res<Materials>,
+) {
+    for entity in trains.iter() {
+        commands.entity(entity).insert((
+            Mesh3d(meshes.train.clone()),
+            MeshMaterial3d(materials.train.clone()),
             PointerInteraction::default(),
         ));
     }
+}
 
-    let add_another_train = state.config.num_automatic_trains > 0;
-    if add_another_train {
-        state.config.num_automatic_trains -= 1;
-        println!(
-            "Adding another train, {} left to add.",
-            state.config.num_automatic_trains
-        );
-        let grid_size = state.config.grid_size;
-
-        let prev_city_index = state.rng.random_range(0..cities.iter().len());
-        let Some(prev_city) = cities
-            .iter()
-            .nth(prev_city_index)
-            .map(|(_, pos)| pos.gridpoint)
-        else {
-            panic!("Getting previous city failed.");
+fn recolor_rails(
+    mut commands: Commands,
+    state: Res<State>,
+    mut rail_usage_changed: EventReader<RailUsageChanged>,
+    materials: Res<Materials>,
+) {
+    let max_rail_usage = state.max_rail_usage.load(Ordering::Relaxed);
+    for event in rail_usage_changed.read() {
+        let Some(rail_info) = state.rails.rails.get(&event.rail) else {
+            continue;
         };
+        let count = rail_info.counter.load(Ordering::Relaxed);
+        commands
+            .entity(rail_info.entity)
+            .insert(MeshMaterial3d(materials.rail(count, max_rail_usage)));
+    }
+}
 
-        let mut other_city;
-
-        let other_prev_city_index = loop {
-            let candidate = state.rng.random_range(0..cities.iter().len());
-            if candidate != prev_city_index {
-                break candidate;
+// 0 pauses the simulation, 1, 2 and 3 run it at the other speeds of `SIM_SPEEDS`.
+fn set_sim_speed(keys: Res<ButtonInput<KeyCode>>, mut sim_speed: ResMut<SimSpeed>) {
+    let digits = [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+    ];
+    for (key, speed) in digits.into_iter().zip(SIM_SPEEDS) {
+        if keys.just_pressed(key) && sim_speed.0 != speed {
+            sim_speed.0 = speed;
+            match speed {
+                0 => println!("Simulation paused."),
+                _ => println!("Simulation speed: {speed}x"),
             }
-        };
-
-        other_city = cities
-            .iter()
-            .nth(other_prev_city_index)
-            .map(|(_, pos)| pos.gridpoint);
-
-        if state.create_new_city_next {
-            // Find a place for a new city.
-            let new_city = loop {
-                let candidate_gridpoint = (
-                    state.rng.random_range(0..6),
-                    state.rng.random_range(0..=grid_size),
-                    state.rng.random_range(0..=grid_size),
-                );
-                let height_threshold = state.rng.random::<f32>().powf(3.0) + 0.01;
-                let Some(&globe_point) = globe_points.points.get(&candidate_gridpoint) else {
-                    continue; // Skip if no GlobePoint found for this gridpoint
-                };
-                if globe_point.water {
-                    continue; // Skip water points
-                }
-                if cities.iter().any(|(_, pos)| {
-                    (pos.globe_point.pos - globe_point.pos).length()
-                        < state.config.min_city_distance
-                }) {
-                    continue; // Skip if a city already exists at this point
-                }
-                let height_ratio =
-                    (globe_point.pos.length() - state.config.sea_level) / state.config.snow_level;
-                if height_ratio < height_threshold {
-                    // Spawn a city at this point
-                    commands.spawn((
-                        City,
-                        Position {
-                            gridpoint: candidate_gridpoint,
-                            globe_point,
-                        },
-                        Mesh3d(meshes.city.clone()),
-                        MeshMaterial3d(custom_materials.city.clone()),
-                        Transform::from_xyz(
-                            globe_point.pos[0],
-                            globe_point.pos[1],
-                            globe_point.pos[2],
-                        )
-                        .looking_at(Vec3::ZERO, Vec3::Z),
-                    ));
-                    break candidate_gridpoint; // Exit the loop after spawning a city
-                }
-            };
-            other_city = Some(new_city);
         }
-
-        state.create_new_city_next = !state.create_new_city_next;
-        let Some(target_city) = other_city else {
-            panic!("No target city found, skipping train creation.");
-        };
-
-        let globe_points_lock = Arc::clone(&state.globe_points);
-        let sender = dijkstra_communication.sender.clone();
-        dijkstra_communication.task = Some((prev_city, target_city));
-        thread::spawn({
-            move || {
-                let Ok(globe_points) = globe_points_lock.read() else {
-                    println!("Failed to lock globe points. This should never happen.");
-                    sender.send(None).unwrap();
-                    return;
-                };
-                let path = bidirectional_dijkstra(prev_city, target_city, &globe_points);
-                sender.send(Some(path)).unwrap();
-            }
-        });
     }
 }
 
@@ -520,10 +664,8 @@ fn draw_pointer(pointers: Query<&PointerInteraction>, mut gizmos: Gizmos) {
 fn on_mouse_right_click(
     pointers: Query<&PointerInteraction>,
     state: Res<State>,
-    mut commands: Commands,
+    mut actions: ResMut<PlayerActions>,
     cities: Query<(Entity, &Position), With<City>>,
-    meshes: Res<Meshes>,
-    materials: Res<Materials>,
 ) {
     for point in pointers
         .iter()
@@ -548,17 +690,7 @@ fn on_mouse_right_click(
                 continue; // Skip if a city already exists at this point
             }
 
-            commands.spawn((
-                City,
-                Position {
-                    gridpoint,
-                    globe_point,
-                },
-                Mesh3d(meshes.city.clone()),
-                MeshMaterial3d(materials.city.clone()),
-                Transform::from_xyz(globe_point.pos[0], globe_point.pos[1], globe_point.pos[2])
-                    .looking_at(Vec3::ZERO, Vec3::Z),
-            ));
+            actions.0.push(PlayerAction::PlaceCity(gridpoint));
         } else {
             println!("No GlobePoint found for gridpoint: {gridpoint:?}");
         }
@@ -569,7 +701,7 @@ fn on_mouse_right_click(
 fn on_mouse_left_click(
     pointers: Query<&PointerInteraction>,
     state: Res<State>,
-    mut dijkstra_communication: ResMut<DijkstraCommunication>,
+    mut preview: ResMut<RoutePreview>,
     mut commands: Commands,
     cities: Query<(Entity, &Position), With<City>>,
     mut selected: ResMut<SelectedCity>,
@@ -604,29 +736,14 @@ fn on_mouse_left_click(
                 }
                 Some(prev_selected) => {
                     if prev_selected != clicked_city {
-                        // Connect the cities
-                        println!("Connecting {prev_selected:?} and {clicked_city:?}");
-                        if dijkstra_communication.task.is_some() {
-                            println!("Dijkstra is busy, skipping connection.");
+                        // Offer routes between the cities
+                        println!("Searching routes between {prev_selected:?} and {clicked_city:?}");
+                        if preview.receiver.is_some() {
+                            println!("Route search is busy, skipping connection.");
                         } else {
-                            let globe_points_lock = Arc::clone(&state.globe_points);
-                            let sender = dijkstra_communication.sender.clone();
                             let start = cities.get(prev_selected).unwrap().1.gridpoint;
                             let end = cities.get(clicked_city).unwrap().1.gridpoint;
-                            dijkstra_communication.task = Some((start, end));
-                            thread::spawn({
-                                move || {
-                                    let Ok(globe_points) = globe_points_lock.read() else {
-                                        println!(
-                                            "Failed to lock globe points. This should never happen."
-                                        );
-                                        sender.send(None).unwrap();
-                                        return;
-                                    };
-                                    let path = bidirectional_dijkstra(start, end, &globe_points);
-                                    sender.send(Some(path)).unwrap();
-                                }
-                            });
+                            search_routes(&mut preview, &state, start, end);
                         }
                     }
                     // Clear selection
@@ -642,6 +759,121 @@ fn on_mouse_left_click(
     }
 }
 
+// Searches the alternative routes in the background, see `receive_routes`. The globe points are
+// locked for one search at a time, so that the simulation can lay rails in between.
+fn search_routes(preview: &mut RoutePreview, state: &State, start: GridPoint, end: GridPoint) {
+    let (sender, receiver) = bounded(1);
+    let globe_points_lock = Arc::clone(&state.globe_points);
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let k = state.config.route_alternatives as usize;
+    let mut finder = RouteFinder::new(start, end, &state.config, k);
+    preview.receiver = Some(receiver);
+    preview.cancelled = Arc::clone(&cancelled);
+    preview.routes.clear();
+    thread::spawn(move || {
+        loop {
+            if cancelled.load(Ordering::Relaxed) {
+                return;
+            }
+            let Ok(globe_points) = globe_points_lock.read() else {
+                println!("Failed to lock globe points. This should never happen.");
+                return;
+            };
+            if !finder.search(&globe_points) {
+                break;
+            }
+        }
+        let Ok(globe_points) = globe_points_lock.read() else {
+            println!("Failed to lock globe points. This should never happen.");
+            return;
+        };
+        let routes = finder
+            .routes()
+            .into_iter()
+            .map(|route| PreviewRoute {
+                positions: route
+                    .path
+                    .iter()
+                    .filter_map(|gridpoint| globe_points.points.get(gridpoint))
+                    .map(|globe_point| globe_point.pos * 1.005)
+                    .collect(),
+                route,
+            })
+            .collect();
+        // fails if the search was dropped meanwhile
+        let _ = sender.send(routes);
+    });
+}
+
+fn receive_routes(mut preview: ResMut<RoutePreview>) {
+    let Some(receiver) = &preview.receiver else {
+        return;
+    };
+    let routes = match receiver.try_recv() {
+        Ok(routes) => routes,
+        Err(TryRecvError::Empty) => return,
+        Err(TryRecvError::Disconnected) => {
+            preview.receiver = None;
+            println!("Route search failed.");
+            return;
+        }
+    };
+    preview.receiver = None;
+    if routes.is_empty() {
+        println!("No route found.");
+        return;
+    }
+    for (i, PreviewRoute { route, .. }) in routes.iter().enumerate() {
+        let b = route.breakdown;
+        println!(
+            "Route {}: cost {:.2}, {} steps. Distance {:.2}, climbing {:.2}, water {:.2}, \
+             snow {:.2}, terrain {:.2}, structures {:.2}, reused {:.2}",
+            i + 1,
+            route.cost,
+            route.path.len(),
+            b.distance,
+            b.climbing,
+            b.water,
+            b.snow,
+            b.terrain,
+            b.structures,
+            b.reused,
+        );
+    }
+    println!("Tab chooses another route, Enter builds it, Escape drops them.");
+    preview.routes = routes;
+    preview.chosen = 0;
+}
+
+fn next_route(mut preview: ResMut<RoutePreview>) {
+    if preview.routes.is_empty() {
+        return;
+    }
+    preview.chosen = (preview.chosen + 1) % preview.routes.len();
+    println!("Route {} chosen.", preview.chosen + 1);
+}
+
+// Lays the chosen route and sends a train along it, on the next simulation tick.
+fn build_route(mut preview: ResMut<RoutePreview>, mut actions: ResMut<PlayerActions>) {
+    let chosen = preview.chosen;
+    if let Some(preview_route) = preview.routes.drain(..).nth(chosen) {
+        actions
+            .0
+            .push(PlayerAction::Follow(preview_route.route.path));
+    }
+}
+
+fn draw_routes(preview: Res<RoutePreview>, mut gizmos: Gizmos) {
+    for (i, preview_route) in preview.routes.iter().enumerate() {
+        let color = ROUTE_COLORS[i % ROUTE_COLORS.len()];
+        let alpha = if i == preview.chosen { 1.0 } else { 0.35 };
+        gizmos.linestrip(
+            preview_route.positions.iter().copied(),
+            color.with_alpha(alpha),
+        );
+    }
+}
+
 fn highlight_city(
     state: Res<State>,
     pointers: Query<&PointerInteraction>,
@@ -677,26 +909,6 @@ fn highlight_city(
     }
 }
 
-fn move_trains(
-    mut commands: Commands,
-    state: Res<State>,
-    time: Res<Time>,
-    mut trains: Query<(&mut Train, &mut Transform), With<Train>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-) {
-    let time_passed_seconds = time.delta().as_secs_f32();
-
-    for (mut train, mut transform) in trains.iter_mut() {
-        train.update(
-            &mut transform,
-            time_passed_seconds,
-            &state,
-            &mut commands,
-            &mut materials,
-        );
-    }
-}
-
 fn move_camera_to_train(
     camera_transform: &mut Transform,
     train_transform: &Transform,
@@ -720,19 +932,26 @@ fn update_train_camera(
     mut camera_transform_q: Query<&mut Transform, (With<MainCamera>, Without<Train>)>,
     trains_q: Query<(&Train, &Transform, &SelectedTrain), With<Train>>,
 ) {
-    if let Ok((train, train_transform, _)) = trains_q.single() {
-        if let Ok(mut camera_transform) = camera_transform_q.single_mut() {
-            move_camera_to_train(&mut camera_transform, train_transform, train.forward);
-        }
+    if let Ok((train, train_transform, _)) = trains_q.single()
+        && let Ok(mut camera_transform) = camera_transform_q.single_mut()
+    {
+        move_camera_to_train(&mut camera_transform, train_transform, train.forward);
     }
 }
 
 fn on_escape(
     mut commands: Commands,
+    mut preview: ResMut<RoutePreview>,
     selected_train: SelectedTrainQuery,
     mut camera_transform_q: CameraTransformQuery,
     mut lights_transform_q: LightsTransformQuery,
 ) {
+    if preview.receiver.take().is_some() {
+        preview.cancelled.store(true, Ordering::Relaxed);
+        println!("Route search cancelled.");
+    }
+    preview.routes.clear();
+
     // if there is a selected train, deselect it, and reset the camera
     if let Some((train_entity, train_transform)) = selected_train.iter().next() {
         commands.entity(train_entity).remove::<SelectedTrain>();